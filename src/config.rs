@@ -1,32 +1,302 @@
 use sdl2::pixels::Color;
 
+use crate::types::{BusRoute, Control, Direction, DriverSpec, LaneUse, Layout, RoadSpec, Side, VehicleSpec};
+
 // Window configuration
 pub const WINDOW_WIDTH: i32 = 800;
 pub const WINDOW_HEIGHT: i32 = 800;
 pub const WINDOW_TITLE: &str = "Road Intersection Simulation";
 
+// Road geometry
+pub const LANE_WIDTH: i32 = 20;
+
+// Lane use on every approach, from the centre line outwards
+pub const LANE_USE: &[LaneUse] = &[LaneUse::LeftOnly, LaneUse::Through, LaneUse::ThroughRight];
+pub const LANES_PER_APPROACH: usize = LANE_USE.len();
+pub const APPROACH_WIDTH: i32 = LANE_WIDTH * LANES_PER_APPROACH as i32;
+
+// Each half of a road holds the lanes of one approach and a shoulder, and the
+// stop lines are drawn where the roads meet
+pub const INTERSECTION_HALF_WIDTH: i32 = APPROACH_WIDTH + LANE_WIDTH;
+
+// Roundabout geometry (radius of the middle of the circulating lane and of the island)
+pub const ROUNDABOUT_RADIUS: f32 = 50.0;
+pub const ROUNDABOUT_ISLAND_RADIUS: i32 = 35;
+
+// Roundabout entry (gap acceptance, in frames)
+pub const ROUNDABOUT_CRITICAL_GAP_TICKS: f32 = 60.0;
+pub const ROUNDABOUT_FOLLOW_UP_TICKS: u32 = 30;
+
+// Stop control (time standing at the line, and gap needed to cross the major road, in frames)
+pub const STOP_TICKS: u32 = 30;
+pub const TWO_WAY_STOP_CRITICAL_GAP_TICKS: f32 = 90.0;
+
+// Reservation-based intersection management (tile size and room around vehicles in pixels,
+// times in frames)
+pub const AIM_TILE_SIZE: i32 = 10;
+pub const AIM_SPACE_BUFFER: f32 = 3.0;
+pub const AIM_TIME_BUFFER: u32 = 2;
+pub const AIM_REQUEST_DISTANCE: f32 = 150.0;
+pub const AIM_SPEED_STEPS: usize = 8;
+
+// Pedestrians (arrivals per hour at each crosswalk, walking speed in pixels per frame, crosswalk
+// width in pixels, walk interval in frames; flashing DON'T WALK lasts as long as a crossing takes)
+pub const PEDESTRIANS_ENABLED: bool = true;
+pub const PEDESTRIAN_ARRIVALS_PER_HOUR: f32 = 120.0;
+pub const PEDESTRIAN_SPEED: f32 = 1.5;
+pub const CROSSWALK_WIDTH: i32 = 12;
+pub const WALK_TICKS: u32 = 60;
+
+// Cyclists (arrivals per hour on each approach, speeds in pixels per frame, sizes in pixels).
+// The bike lane is the shoulder, shared with buses jumping the queue. Bike boxes keep vehicles
+// waiting at a red light `BIKE_BOX_DEPTH` back from the line. Drivers turning right look for
+// cyclists up to `RIGHT_TURN_LOOKBACK` behind the line, and a right-turning vehicle coming within
+// `RIGHT_HOOK_DISTANCE` of a cyclist riding straight on counts as a right-hook conflict.
+pub const CYCLISTS_ENABLED: bool = true;
+pub const CYCLIST_ARRIVALS_PER_HOUR: f32 = 120.0;
+pub const CYCLIST_RIGHT_TURN_SHARE: f64 = 0.25;
+pub const CYCLIST_SPEED_MIN: f32 = 0.8;
+pub const CYCLIST_SPEED_MAX: f32 = 1.8;
+pub const CYCLIST_ACCELERATION: f32 = 0.05;
+pub const CYCLIST_LENGTH: i32 = 10;
+pub const CYCLIST_WIDTH: i32 = 4;
+pub const CYCLIST_GAP: f32 = 4.0;
+pub const BIKE_BOXES_ENABLED: bool = false;
+pub const BIKE_BOX_DEPTH: i32 = 30;
+pub const RIGHT_TURN_LOOKBACK: f32 = 20.0;
+pub const RIGHT_HOOK_DISTANCE: f32 = 8.0;
+
+// Runtime safety checks (overlap allowed between vehicle bodies before it counts, in pixels;
+// panicking on a violation only happens in builds with debug assertions; the summary lists
+// the first `SAFETY_REPORT_LIMIT` violations)
+pub const SAFETY_CHECKS_ENABLED: bool = true;
+pub const SAFETY_PANIC_ON_VIOLATION: bool = false;
+pub const SAFETY_OVERLAP_TOLERANCE: f32 = 0.5;
+pub const SAFETY_REPORT_LIMIT: usize = 10;
+
+// Surrogate safety measures (thresholds in frames, cell size in pixels, hard braking as
+// deceleration in pixels per frame per frame)
+pub const SSM_ENABLED: bool = true;
+pub const TTC_THRESHOLD_TICKS: f32 = 90.0;
+pub const PET_THRESHOLD_TICKS: u32 = 90;
+pub const PET_CELL_SIZE: i32 = 10;
+pub const HARD_BRAKING_DECELERATION: f32 = 0.5;
+
 // Vehicle properties
-pub const VEHICLE_WIDTH: i32 = 20;
-pub const VEHICLE_HEIGHT: i32 = 20;
-pub const MIN_VELOCITY: i32 = 2;
-pub const MAX_VELOCITY: i32 = 3;
-pub const SAFETY_DISTANCE: i32 = 30;
+pub const SAFETY_GAP: i32 = 10;
+pub const MAX_LATERAL_ACCELERATION: f32 = 0.1;
+
+// Car-following model used to weigh lane changes (IDM, times in frames)
+pub const IDM_TIME_HEADWAY: f32 = 20.0;
+pub const IDM_COMFORTABLE_DECELERATION: f32 = 0.2;
+
+// Lane changing (MOBIL)
+pub const LANE_CHANGE_POLITENESS: f32 = 0.3;
+pub const LANE_CHANGE_THRESHOLD: f32 = 0.02;
+pub const LANE_CHANGE_MAX_SAFE_DECELERATION: f32 = 0.3;
+pub const LANE_CHANGE_MANDATORY_BIAS: f32 = 0.2;
+pub const LANE_CHANGE_ZONE: i32 = 150;
+pub const LANE_CHANGE_SPEED: f32 = 0.5;
+
+// Vehicle classes (sizes in pixels, speeds in pixels per frame)
+pub const CAR_SPEC: VehicleSpec = VehicleSpec {
+    length: 20,
+    width: 18,
+    max_velocity: 2.0,
+    acceleration: 0.10,
+    accent: Color::RGB(220, 220, 220),
+};
+pub const TRUCK_SPEC: VehicleSpec = VehicleSpec {
+    length: 40,
+    width: 20,
+    max_velocity: 1.5,
+    acceleration: 0.04,
+    accent: Color::RGB(140, 90, 40),
+};
+pub const BUS_SPEC: VehicleSpec = VehicleSpec {
+    length: 44,
+    width: 20,
+    max_velocity: 1.6,
+    acceleration: 0.05,
+    accent: Color::RGB(255, 200, 0),
+};
+pub const MOTORCYCLE_SPEC: VehicleSpec = VehicleSpec {
+    length: 14,
+    width: 8,
+    max_velocity: 2.5,
+    acceleration: 0.15,
+    accent: Color::RGB(40, 40, 40),
+};
+pub const EMERGENCY_SPEC: VehicleSpec = VehicleSpec {
+    length: 24,
+    width: 18,
+    max_velocity: 3.0,
+    acceleration: 0.15,
+    accent: Color::RGB(0, 80, 255),
+};
+
+// Fleet mix (relative weights of spawned vehicle classes)
+pub const FLEET_MIX_CAR: u32 = 80;
+pub const FLEET_MIX_TRUCK: u32 = 8;
+pub const FLEET_MIX_BUS: u32 = 4;
+pub const FLEET_MIX_MOTORCYCLE: u32 = 8;
+
+// Driver compliance with the lights (probabilities per yellow or red a driver comes up to,
+// reaction times in frames; drivers decide within `DRIVER_DECISION_DISTANCE` pixels of the line
+// and can stop for a yellow braking at `YELLOW_STOP_DECELERATION` pixels per frame per frame)
+pub const COMPLIANCE_ENABLED: bool = true;
+pub const DRIVER_DECISION_DISTANCE: f32 = 120.0;
+pub const YELLOW_STOP_DECELERATION: f32 = 0.05;
+pub const CAUTIOUS_DRIVER_SPEC: DriverSpec = DriverSpec {
+    yellow_go_probability: 0.05,
+    red_run_probability: 0.0,
+    reaction_ticks: (30, 60),
+};
+pub const NORMAL_DRIVER_SPEC: DriverSpec = DriverSpec {
+    yellow_go_probability: 0.3,
+    red_run_probability: 0.01,
+    reaction_ticks: (20, 45),
+};
+pub const AGGRESSIVE_DRIVER_SPEC: DriverSpec = DriverSpec {
+    yellow_go_probability: 0.8,
+    red_run_probability: 0.1,
+    reaction_ticks: (10, 30),
+};
+
+// Queue discharge (standing drivers take their reaction time to move off; headways are measured
+// for the first `DISCHARGE_POSITIONS` vehicles in each queue, and the saturation headway from
+// position `SATURATION_HEADWAY_POSITION` on)
+pub const STARTUP_REACTION_ENABLED: bool = true;
+pub const DISCHARGE_POSITIONS: usize = 10;
+pub const SATURATION_HEADWAY_POSITION: usize = 5;
+
+// Driver mix (relative weights of each driver style)
+pub const DRIVER_MIX_CAUTIOUS: u32 = 25;
+pub const DRIVER_MIX_NORMAL: u32 = 60;
+pub const DRIVER_MIX_AGGRESSIVE: u32 = 15;
+
+// Origin-destination demand (vehicles per hour, rows by entry side and columns by exit leg,
+// both in `OD_SIDES` order; U-turns on the diagonal are not made)
+pub const DEMAND_ENABLED: bool = true;
+pub const OD_SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+pub const OD_MATRIX: [[f32; 4]; 4] = [
+    //  East   West   North  South
+    [   0.0, 300.0,  60.0,  90.0], // From east
+    [ 300.0,   0.0,  90.0,  60.0], // From west
+    [  90.0,  60.0,   0.0, 150.0], // From north
+    [  60.0,  90.0, 150.0,   0.0], // From south
+];
+
+// Bus schedule (buses enter on each route once per headway, in frames)
+pub const BUS_HEADWAY_TICKS: u32 = 900;
+pub const BUS_ROUTES: [BusRoute; 2] = [
+    BusRoute { side: Side::FromEast, direction: Direction::Straight },
+    BusRoute { side: Side::FromSouth, direction: Direction::Left },
+];
+
+// Transit signal priority
+pub const TSP_ENABLED: bool = true;
+pub const TSP_GREEN_EXTENSION: bool = true;
+pub const TSP_EARLY_GREEN: bool = true;
+pub const TSP_QUEUE_JUMP: bool = true;
+pub const TSP_DETECTION_DISTANCE: i32 = 200;
+pub const TSP_GREEN_EXTENSION_TICKS: u32 = 180;
+
+// Road network (a corridor of intersections, each drawn in its own window-sized area;
+// roads may only join legs that the intersections' layouts have)
+pub const NETWORK_INTERSECTIONS: usize = 3;
+pub const NETWORK_LAYOUTS: [Layout; NETWORK_INTERSECTIONS] = [
+    Layout::Cross,
+    Layout::ThreeLeg(Side::FromNorth),
+    Layout::Cross,
+];
+pub const NETWORK_CONTROLS: [Control; NETWORK_INTERSECTIONS] = [Control::Signals; NETWORK_INTERSECTIONS];
+pub const NETWORK_ROADS: &[RoadSpec] = &[
+    RoadSpec { from: 0, leg: Side::FromWest, to: 1, length: 400.0 },
+    RoadSpec { from: 1, leg: Side::FromWest, to: 2, length: 600.0 },
+];
+
+// Coordinated fixed-time control along the corridor through the network
+pub const COORDINATION_ENABLED: bool = false;
+pub const COORDINATED_SIDE: Side = Side::FromEast;
+pub const CYCLE_TICKS: u32 = 600;
+pub const COORDINATED_GREEN_TICKS: u32 = 180;
+pub const PROGRESSION_SPEED: f32 = 2.0;
+
+// Time-space diagram of the corridor, written on exit
+pub const TIME_SPACE_SAMPLE_TICKS: u32 = 10;
+pub const TIME_SPACE_SIGNALS_PATH: &str = "time_space_signals.csv";
+pub const TIME_SPACE_TRAJECTORIES_PATH: &str = "time_space_trajectories.csv";
+
+// Route choice (extra frames expected per vehicle queued ahead)
+pub const ROUTE_QUEUE_PENALTY_TICKS: f32 = 60.0;
 
 // Vehicle colors by direction
 pub const VEHICLE_COLOR_LEFT: Color = Color::RGB(255, 0, 0);     // Red
 pub const VEHICLE_COLOR_RIGHT: Color = Color::RGB(0, 255, 0);    // Green
 pub const VEHICLE_COLOR_STRAIGHT: Color = Color::RGB(0, 0, 255); // Blue
 
+// Vehicle lights
+pub const HEADLIGHT_COLOR: Color = Color::RGB(255, 255, 200);
+pub const TAIL_LIGHT_COLOR: Color = Color::RGB(180, 0, 0);
+pub const BLINKER_COLOR: Color = Color::RGB(255, 160, 0);
+pub const BLINK_PERIOD_TICKS: u32 = 30;
+
 // Road colors
 pub const ROAD_COLOR: Color = Color::RGB(50, 50, 50);
 pub const ROAD_MARKING_COLOR: Color = Color::RGB(255, 255, 255);
+pub const BIKE_LANE_COLOR: Color = Color::RGB(40, 90, 50);
+
+// Cyclist color
+pub const CYCLIST_COLOR: Color = Color::RGB(255, 220, 0);
 
 // Traffic light colors
 pub const LIGHT_GREEN: Color = Color::RGB(0, 255, 0);
 pub const LIGHT_RED: Color = Color::RGB(255, 0, 0);
+pub const LIGHT_YELLOW: Color = Color::RGB(255, 200, 0);
+
+// Signal timing (in frames)
+pub const MIN_GREEN_TICKS: u32 = 60;
+pub const MAX_GREEN_TICKS: u32 = 300;
+pub const YELLOW_TICKS: u32 = 30;
+pub const ALL_RED_TICKS: u32 = 15;
+
+// Emergency vehicle preemption
+pub const PREEMPTION_DETECTION_DISTANCE: i32 = 300;
 
 // UI colors
 pub const BACKGROUND_COLOR: Color = Color::RGB(0, 0, 0);
+pub const HUD_TEXT_COLOR: Color = Color::RGB(230, 230, 230);
+
+// Size of the HUD text, in screen pixels per font pixel
+pub const HUD_TEXT_SCALE: i32 = 2;
 
 // Frame rate
-pub const FPS: u64 = 60;
\ No newline at end of file
+pub const FPS: u64 = 60;
+
+// Replay of recorded runs
+pub const REPLAY_SEEK_TICKS: u32 = 300;
+pub const REPLAY_MAX_SPEED: f32 = 16.0;
+
+// Snapshot of the whole simulation, saved and restored from the keyboard
+pub const SNAPSHOT_PATH: &str = "snapshot.json";
+
+// Live charts of the recent queues, throughput and delay, toggled with G
+pub const CHARTS_VISIBLE: bool = false;
+pub const TREND_SAMPLE_TICKS: u32 = 30;
+pub const TREND_WINDOW_MINUTES: u32 = 5;
+pub const CHART_PANEL_COLOR: Color = Color::RGB(20, 20, 30);
+pub const CHART_AXIS_COLOR: Color = Color::RGB(90, 90, 100);
+// Queue lines for the east, west, north and south approaches
+pub const CHART_QUEUE_COLORS: [Color; 4] = [
+    Color::RGB(255, 120, 120),
+    Color::RGB(120, 200, 255),
+    Color::RGB(140, 255, 140),
+    Color::RGB(255, 220, 100),
+];
+pub const CHART_THROUGHPUT_COLOR: Color = Color::RGB(0, 255, 255);
+pub const CHART_DELAY_COLOR: Color = Color::RGB(255, 0, 255);
+
+// Headless rendering of frames to PNG files
+pub const HEADLESS_FRAMES: u32 = 600;
+pub const HEADLESS_FRAME_INTERVAL: u32 = 10;
@@ -0,0 +1,207 @@
+use crate::config::*;
+use crate::types::*;
+use super::vehicle::Vehicle;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A change of the light on an approach
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct LightChange {
+    side: Side,
+    /// State shown before the change
+    from: LightState,
+    /// State shown since the change
+    to: LightState,
+    /// Frame of the change
+    time: u32,
+}
+
+/// How drivers treat the traffic lights
+///
+/// Drivers notice a light turning yellow or red only after their reaction time,
+/// and until then drive on by the light they saw before; moving off on a green
+/// is left to the start-up of the queue. A driver within
+/// `DRIVER_DECISION_DISTANCE` of the line who sees a yellow goes on if unable to
+/// stop braking at `YELLOW_STOP_DECELERATION`, and otherwise goes on with the
+/// yellow-go probability of their style if the line is within reach before the
+/// yellow ends. A driver coming up to a red runs it with the red-run probability
+/// of their style. Drivers who go on only mind the vehicles from their own approach.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Compliance {
+    /// Whether drivers may disobey the lights and take time to react to them
+    pub enabled: bool,
+    /// Number of vehicles that entered the intersection on a yellow
+    pub yellow_entries: usize,
+    /// Number of vehicles that entered the intersection on a red
+    pub red_entries: usize,
+    /// Number of drivers who stopped for a yellow
+    pub yellow_stops: usize,
+    /// Number of drivers caught by a yellow unable to stop, and too far away to reach the line before it ended
+    pub dilemma_zone_entries: usize,
+    /// Last change of the light on each approach
+    changes: Vec<LightChange>,
+}
+
+impl Compliance {
+    /// Create the compliance model set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: COMPLIANCE_ENABLED,
+            yellow_entries: 0,
+            red_entries: 0,
+            yellow_stops: 0,
+            dilemma_zone_entries: 0,
+            changes: vec![],
+        }
+    }
+
+    /// Notes the state the light on an approach shows at the given time
+    pub fn observe(&mut self, side: Side, state: LightState, time: u32) {
+        match self.changes.iter_mut().find(|change| change.side == side) {
+            Some(change) if change.to != state => *change = LightChange { side, from: change.to, to: state, time },
+            Some(_) => {},
+            None => self.changes.push(LightChange { side, from: state, to: state, time }),
+        }
+    }
+
+    /// State of the light on the vehicle's approach as its driver sees it at the given time,
+    /// while the light shows `state`
+    pub fn perceived(&self, vehicle: &Vehicle, state: LightState, time: u32) -> LightState {
+        let change = self.changes.iter().find(|change| change.side == vehicle.side);
+        match change {
+            Some(change) if self.enabled && change.to != LightState::Green && time - change.time < vehicle.reaction_ticks => {
+                change.from
+            },
+            _ => state,
+        }
+    }
+
+    /// Frames left at the given time before the yellow on an approach turns red, or 0 if it is not yellow
+    fn remaining_yellow(&self, side: Side, time: u32) -> u32 {
+        self.changes.iter()
+            .find(|change| change.side == side && change.to == LightState::Yellow)
+            .map_or(0, |change| YELLOW_TICKS.saturating_sub(time - change.time))
+    }
+
+    /// Makes up the driver's mind at the given time about a light they see is not green, if it is time to
+    ///
+    /// Returns `None` while the driver has no decision to make.
+    pub fn decide<R: Rng>(&mut self, vehicle: &Vehicle, seen: LightState, time: u32, rng: &mut R) -> Option<LightDecision> {
+        let distance = vehicle.distance_to_stop_line();
+        let velocity = vehicle.velocity;
+        if !self.enabled || seen == LightState::Green || vehicle.light_decision.is_some() ||
+           velocity <= 0.0 || distance <= 0.0 || distance > DRIVER_DECISION_DISTANCE {
+            return None;
+        }
+
+        let spec = vehicle.driver.spec();
+        let go = match seen {
+            LightState::Yellow => {
+                let can_stop = distance >= velocity * velocity / (2.0 * YELLOW_STOP_DECELERATION);
+                let reaches_line = distance / velocity <= self.remaining_yellow(vehicle.side, time) as f32;
+                if !can_stop && !reaches_line {
+                    self.dilemma_zone_entries += 1;
+                }
+                let go = !can_stop || (reaches_line && rng.gen_bool(spec.yellow_go_probability));
+                if !go {
+                    self.yellow_stops += 1;
+                }
+                go
+            },
+            _ => rng.gen_bool(spec.red_run_probability),
+        };
+        Some(if go { LightDecision::Go } else { LightDecision::Stop })
+    }
+
+    /// Counts a vehicle entering the intersection while its light shows the given state
+    pub fn entered(&mut self, state: LightState) {
+        match state {
+            LightState::Yellow => self.yellow_entries += 1,
+            LightState::Red => self.red_entries += 1,
+            LightState::Green => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compliance() -> Compliance {
+        Compliance { enabled: true, ..Compliance::configured() }
+    }
+
+    /// Cautious driver from the east coming up to the line from the given distance at the given speed
+    fn approaching(distance: f32, velocity: f32) -> Vehicle {
+        let mut vehicle = Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng());
+        vehicle.x = (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH - vehicle.width) as f32 - distance;
+        vehicle.velocity = velocity;
+        vehicle.driver = DriverStyle::Cautious;
+        vehicle
+    }
+
+    #[test]
+    fn drivers_see_a_change_only_after_their_reaction_time() {
+        let mut compliance = compliance();
+        compliance.observe(Side::FromEast, LightState::Green, 0);
+        compliance.observe(Side::FromEast, LightState::Yellow, 100);
+        let mut vehicle = approaching(50.0, 2.0);
+        vehicle.reaction_ticks = 20;
+        assert_eq!(compliance.perceived(&vehicle, LightState::Yellow, 119), LightState::Green);
+        assert_eq!(compliance.perceived(&vehicle, LightState::Yellow, 120), LightState::Yellow);
+
+        compliance.enabled = false;
+        assert_eq!(compliance.perceived(&vehicle, LightState::Yellow, 100), LightState::Yellow);
+    }
+
+    #[test]
+    fn drivers_decide_only_near_the_line_on_a_light_that_is_not_green() {
+        let mut compliance = compliance();
+        let mut rng = rand::thread_rng();
+        assert_eq!(compliance.decide(&approaching(50.0, 2.0), LightState::Green, 0, &mut rng), None);
+        assert_eq!(compliance.decide(&approaching(DRIVER_DECISION_DISTANCE + 1.0, 2.0), LightState::Red, 0, &mut rng), None);
+        assert_eq!(compliance.decide(&approaching(50.0, 0.0), LightState::Red, 0, &mut rng), None);
+
+        let mut decided = approaching(50.0, 2.0);
+        decided.light_decision = Some(LightDecision::Go);
+        assert_eq!(compliance.decide(&decided, LightState::Red, 0, &mut rng), None);
+    }
+
+    #[test]
+    fn a_yellow_is_run_only_by_drivers_unable_to_stop() {
+        let mut compliance = compliance();
+        let mut rng = rand::thread_rng();
+        compliance.observe(Side::FromEast, LightState::Yellow, 0);
+
+        // Far enough to stop, and too far to reach the line in time
+        let decision = compliance.decide(&approaching(110.0, 1.0), LightState::Yellow, 0, &mut rng);
+        assert_eq!((decision, compliance.yellow_stops), (Some(LightDecision::Stop), 1));
+
+        // Too close to stop, but over the line before the red
+        let decision = compliance.decide(&approaching(10.0, 2.0), LightState::Yellow, 0, &mut rng);
+        assert_eq!((decision, compliance.dilemma_zone_entries), (Some(LightDecision::Go), 0));
+
+        // Unable either to stop or to reach the line before the red
+        let decision = compliance.decide(&approaching(100.0, 3.5), LightState::Yellow, YELLOW_TICKS - 1, &mut rng);
+        assert_eq!((decision, compliance.dilemma_zone_entries), (Some(LightDecision::Go), 1));
+    }
+
+    #[test]
+    fn cautious_drivers_never_run_a_red() {
+        let mut compliance = compliance();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let decision = compliance.decide(&approaching(50.0, 2.0), LightState::Red, 0, &mut rng);
+            assert_eq!(decision, Some(LightDecision::Stop));
+        }
+    }
+
+    #[test]
+    fn entries_are_counted_by_the_light_they_were_made_on() {
+        let mut compliance = compliance();
+        for state in [LightState::Green, LightState::Yellow, LightState::Red, LightState::Red] {
+            compliance.entered(state);
+        }
+        assert_eq!((compliance.yellow_entries, compliance.red_entries), (1, 2));
+    }
+}
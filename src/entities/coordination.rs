@@ -0,0 +1,234 @@
+use crate::config::*;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+
+/// Fixed-time signal plan serving the approaches one after the other
+///
+/// Every phase runs its green followed by the yellow and all-red clearance
+/// intervals. The first phase starts `offset` frames into each cycle, counted
+/// from the start of the simulation, so that plans sharing a cycle length can
+/// be coordinated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalPlan {
+    /// Approaches in the order they are served, with their green times in frames
+    pub phases: Vec<(Side, u32)>,
+    /// Start of the first green within the cycle, in frames
+    pub offset: u32,
+}
+
+impl SignalPlan {
+    /// Create the plan set up in the configuration, favouring the given approach
+    ///
+    /// The coordinated approach is served first with `COORDINATED_GREEN_TICKS`,
+    /// and the other approaches of the layout share the rest of `CYCLE_TICKS`
+    /// equally.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configured cycle is too short to give the other approaches
+    /// `MIN_GREEN_TICKS` each after the coordinated green and the clearance intervals.
+    pub fn coordinated(side: Side, offset: u32, layout: Layout) -> Self {
+        Self::with_cycle(side, offset, layout, CYCLE_TICKS, COORDINATED_GREEN_TICKS)
+            .expect("CYCLE_TICKS must fit COORDINATED_GREEN_TICKS, the clearances and MIN_GREEN_TICKS for every other approach")
+    }
+
+    /// Create a plan of exactly `cycle` frames, serving the given approach first with `coordinated_green`
+    ///
+    /// Returns `None` if the cycle leaves any other approach less than `MIN_GREEN_TICKS`.
+    pub fn with_cycle(side: Side, offset: u32, layout: Layout, cycle: u32, coordinated_green: u32) -> Option<Self> {
+        let others: Vec<Side> = [Side::FromEast, Side::FromNorth, Side::FromWest, Side::FromSouth]
+            .into_iter()
+            .filter(|&other| other != side && layout.has_leg(other))
+            .collect();
+        let other_count = others.len() as u32;
+        let clearance = (other_count + 1) * (YELLOW_TICKS + ALL_RED_TICKS);
+        let other_total = cycle.checked_sub(clearance + coordinated_green)?;
+        if other_count == 0 || other_total < other_count * MIN_GREEN_TICKS {
+            return None;
+        }
+
+        // The first of the other approaches takes any frames left over by the split
+        let mut phases = vec![(side, coordinated_green)];
+        phases.extend(others.into_iter().enumerate().map(|(index, other)| {
+            let extra = if index == 0 { other_total % other_count } else { 0 };
+            (other, other_total / other_count + extra)
+        }));
+        let plan = Self { phases, offset: offset % cycle };
+        debug_assert_eq!(plan.cycle(), cycle);
+        Some(plan)
+    }
+
+    /// Length of a full cycle through all phases, in frames
+    pub fn cycle(&self) -> u32 {
+        self.phases.iter().map(|(_, green)| green + YELLOW_TICKS + ALL_RED_TICKS).sum()
+    }
+
+    /// State of the light on the given approach at the given time
+    pub fn light_state(&self, side: Side, time: u32) -> LightState {
+        let cycle = self.cycle();
+        let mut in_cycle = (time + cycle - self.offset) % cycle;
+
+        for &(phase_side, green) in &self.phases {
+            let length = green + YELLOW_TICKS + ALL_RED_TICKS;
+            if in_cycle < length {
+                if phase_side != side || in_cycle >= green + YELLOW_TICKS {
+                    return LightState::Red;
+                }
+                return if in_cycle < green { LightState::Green } else { LightState::Yellow };
+            }
+            in_cycle -= length;
+        }
+        LightState::Red
+    }
+
+    /// Frames of green left on the given approach at the given time, or 0 if it is not green
+    pub fn green_remaining(&self, side: Side, time: u32) -> u32 {
+        let cycle = self.cycle();
+        let mut in_cycle = (time + cycle - self.offset) % cycle;
+
+        for &(phase_side, green) in &self.phases {
+            let length = green + YELLOW_TICKS + ALL_RED_TICKS;
+            if in_cycle < length {
+                return if phase_side == side && in_cycle < green { green - in_cycle } else { 0 };
+            }
+            in_cycle -= length;
+        }
+        0
+    }
+}
+
+/// A chain of intersections that vehicles on one approach drive straight through
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Corridor {
+    /// Approach travelled along at every intersection of the corridor
+    pub side: Side,
+    /// Intersections in the order they are passed, with the distance from the start
+    /// of the corridor to the edge of each one's area, in pixels
+    pub stops: Vec<(usize, f32)>,
+}
+
+impl Corridor {
+    /// Distance travelled along the corridor by a vehicle within an intersection's area
+    pub fn distance_in_area(side: Side, (center_x, center_y): (f32, f32)) -> f32 {
+        match side {
+            Side::FromEast => center_x,
+            Side::FromWest => WINDOW_WIDTH as f32 - center_x,
+            Side::FromNorth => center_y,
+            Side::FromSouth => WINDOW_HEIGHT as f32 - center_y,
+        }
+    }
+
+    /// Length of an intersection's area along the corridor
+    pub fn area_length(&self) -> f32 {
+        match self.side {
+            Side::FromEast | Side::FromWest => WINDOW_WIDTH as f32,
+            Side::FromNorth | Side::FromSouth => WINDOW_HEIGHT as f32,
+        }
+    }
+
+    /// Offsets giving a green wave at `PROGRESSION_SPEED`, one per intersection of the corridor
+    ///
+    /// Each green starts when a vehicle released at the start of the previous green
+    /// arrives, since all stop lines lie at the same place within their areas.
+    pub fn offsets(&self) -> Vec<u32> {
+        self.stops.iter()
+            .map(|&(_, distance)| (distance / PROGRESSION_SPEED).round() as u32)
+            .collect()
+    }
+}
+
+/// Signal states and vehicle positions along a corridor over time
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct TimeSpaceDiagram {
+    /// Intervals of each light state: intersection, position of its stop line, state, start and end times
+    pub signals: Vec<(usize, f32, LightState, u32, u32)>,
+    /// Sampled vehicle positions: time, position along the corridor and speed
+    pub trajectories: Vec<(u32, f32, f32)>,
+}
+
+impl TimeSpaceDiagram {
+    /// Records the state of a light at the given time, extending its current interval if unchanged
+    pub fn record_signal(&mut self, intersection: usize, position: f32, state: LightState, time: u32) {
+        let current = self.signals.iter_mut()
+            .rev()
+            .find(|(other, ..)| *other == intersection);
+        match current {
+            Some((_, _, current_state, _, end)) if *current_state == state => *end = time + 1,
+            _ => self.signals.push((intersection, position, state, time, time + 1)),
+        }
+    }
+
+    /// Records the position of a vehicle on the corridor
+    pub fn record_vehicle(&mut self, time: u32, position: f32, velocity: f32) {
+        self.trajectories.push((time, position, velocity));
+    }
+
+    /// Writes the diagram as two CSV files, one for the signals and one for the vehicles
+    pub fn export(&self, signals_path: &str, trajectories_path: &str) -> std::io::Result<()> {
+        let mut signals = String::from("intersection,position,state,start,end\n");
+        for (intersection, position, state, start, end) in &self.signals {
+            signals.push_str(&format!("{},{:.1},{:?},{},{}\n", intersection, position, state, start, end));
+        }
+        std::fs::write(signals_path, signals)?;
+
+        let mut trajectories = String::from("time,position,velocity\n");
+        for (time, position, velocity) in &self.trajectories {
+            trajectories.push_str(&format!("{},{:.1},{:.2}\n", time, position, velocity));
+        }
+        std::fs::write(trajectories_path, trajectories)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+
+    fn layouts() -> Vec<Layout> {
+        std::iter::once(Layout::Cross).chain(SIDES.map(Layout::ThreeLeg)).collect()
+    }
+
+    #[test]
+    fn coordinated_plans_run_exactly_the_configured_cycle() {
+        for layout in layouts() {
+            for side in layout.legs() {
+                let plan = SignalPlan::coordinated(side, 0, layout);
+                assert_eq!(plan.cycle(), CYCLE_TICKS, "{:?} coordinated on {:?}", layout, side);
+                assert!(plan.phases[1..].iter().all(|&(_, green)| green >= MIN_GREEN_TICKS));
+            }
+        }
+    }
+
+    #[test]
+    fn cycles_too_short_for_the_other_approaches_are_refused() {
+        let clearance = 4 * (YELLOW_TICKS + ALL_RED_TICKS);
+        assert_eq!(SignalPlan::with_cycle(Side::FromEast, 0, Layout::Cross, COORDINATED_GREEN_TICKS, COORDINATED_GREEN_TICKS), None);
+        let short = COORDINATED_GREEN_TICKS + clearance + 3 * MIN_GREEN_TICKS - 1;
+        assert_eq!(SignalPlan::with_cycle(Side::FromEast, 0, Layout::Cross, short, COORDINATED_GREEN_TICKS), None);
+        let plan = SignalPlan::with_cycle(Side::FromEast, 0, Layout::Cross, short + 1, COORDINATED_GREEN_TICKS);
+        assert_eq!(plan.map(|plan| plan.cycle()), Some(short + 1));
+    }
+
+    #[test]
+    fn coordinated_approach_turns_green_at_the_offset() {
+        let offset = 100;
+        let plan = SignalPlan::coordinated(Side::FromWest, offset, Layout::Cross);
+        assert_eq!(plan.light_state(Side::FromWest, offset), LightState::Green);
+        assert_eq!(plan.light_state(Side::FromWest, offset + COORDINATED_GREEN_TICKS - 1), LightState::Green);
+        assert_eq!(plan.light_state(Side::FromWest, offset + COORDINATED_GREEN_TICKS), LightState::Yellow);
+        assert_eq!(plan.light_state(Side::FromWest, offset + COORDINATED_GREEN_TICKS + YELLOW_TICKS), LightState::Red);
+        assert_eq!(plan.light_state(Side::FromWest, offset + CYCLE_TICKS), LightState::Green);
+    }
+
+    #[test]
+    fn one_approach_at_most_is_not_red() {
+        for layout in layouts() {
+            let plan = SignalPlan::coordinated(layout.legs()[0], 37, layout);
+            for time in 0..2 * CYCLE_TICKS {
+                let open = SIDES.iter().filter(|&&side| plan.light_state(side, time) != LightState::Red).count();
+                assert!(open <= 1, "{} approaches open at frame {} in {:?}", open, time, layout);
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::config::*;
+use crate::types::*;
+use super::pedestrian::{relative_to, PedestrianTraffic};
+use super::vehicle::Vehicle;
+use super::reservation::Footprint;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+/// Distance from the middle of the intersection to the edge of the roads
+const KERB: f32 = INTERSECTION_HALF_WIDTH as f32;
+
+/// Distance of the middle of the bike lane from the centre line of the road
+const BIKE_LANE_OFFSET: f32 = (APPROACH_WIDTH + LANE_WIDTH / 2) as f32;
+
+/// Room kept clear on either side of a cyclist
+const CLEARANCE: f32 = 2.0;
+
+/// Distance from a vehicle a cyclist waiting at the line needs the intersection clear by
+const CROSSING_CLEARANCE: f32 = 10.0;
+
+/// Radius of the path a cyclist turning right follows round the corner
+fn turn_radius() -> f32 {
+    KERB - BIKE_LANE_OFFSET
+}
+
+/// Whether a point lies on a road user at `center` facing `heading`, with `margin` to spare all round
+fn covers(center: (f32, f32), heading: f32, half_length: f32, half_breadth: f32, margin: f32, point: (f32, f32)) -> bool {
+    let (along, across) = relative_to(center, heading, point);
+    along.abs() <= half_length + margin && across <= half_breadth + margin
+}
+
+/// Whether a point lies on the vehicle, with `margin` to spare all round
+fn covers_vehicle(vehicle: &Vehicle, margin: f32, point: (f32, f32)) -> bool {
+    let (half_length, half_breadth) = (vehicle.length() as f32 / 2.0, vehicle.breadth() as f32 / 2.0);
+    covers(vehicle.center(), vehicle.heading, half_length, half_breadth, margin, point)
+}
+
+/// A person riding a bicycle along the bike lane and across the intersection
+///
+/// Cyclists either ride straight on or turn right, keeping to the bike lane on
+/// the shoulder of the road throughout.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cyclist {
+    /// Side from which the cyclist approaches the intersection
+    pub side: Side,
+    /// Straight on, or right round the corner
+    pub direction: Direction,
+    /// Distance of the centre of the cyclist along its route from the stop line, negative before it
+    pub progress: f32,
+    /// Current speed, in pixels per frame
+    pub velocity: f32,
+    /// Speed the cyclist rides at on a clear road, in pixels per frame
+    pub max_velocity: f32,
+    /// Time lost compared to riding at top speed, in frames
+    pub delay: f32,
+    /// Whether a right-turning vehicle has come too close to the cyclist
+    pub hooked: bool,
+    /// Whether the intersection manager has booked the cyclist's way across
+    pub reserved: bool,
+}
+
+impl Cyclist {
+    /// Creates a cyclist entering at the edge of the window on the given side
+    fn new(side: Side, direction: Direction, max_velocity: f32) -> Self {
+        let half_extent = match side {
+            Side::FromEast | Side::FromWest => WINDOW_WIDTH / 2,
+            Side::FromNorth | Side::FromSouth => WINDOW_HEIGHT / 2,
+        };
+        Self {
+            side,
+            direction,
+            progress: KERB - half_extent as f32 + CYCLIST_LENGTH as f32 / 2.0,
+            velocity: max_velocity,
+            max_velocity,
+            delay: 0.0,
+            hooked: false,
+            reserved: false,
+        }
+    }
+
+    /// Centre and heading of the cyclist at the given distance along its route
+    pub fn pose_at(&self, progress: f32) -> ((f32, f32), f32) {
+        let heading = self.side.heading();
+        let (forward_x, forward_y) = (heading.cos(), heading.sin());
+        let (right_x, right_y) = (-forward_y, forward_x);
+        let stop_x = (WINDOW_WIDTH / 2) as f32 - KERB * forward_x + BIKE_LANE_OFFSET * right_x;
+        let stop_y = (WINDOW_HEIGHT / 2) as f32 - KERB * forward_y + BIKE_LANE_OFFSET * right_y;
+
+        let radius = turn_radius();
+        let arc_length = radius * FRAC_PI_2;
+        if self.direction != Direction::Right || progress <= 0.0 {
+            return ((stop_x + progress * forward_x, stop_y + progress * forward_y), heading);
+        }
+
+        // Round the corner, then on along the exit road
+        let (curve_x, curve_y) = (stop_x + radius * right_x, stop_y + radius * right_y);
+        let turned = heading + progress.min(arc_length) / radius;
+        let (x, y) = (curve_x + radius * turned.sin(), curve_y - radius * turned.cos());
+        let beyond = (progress - arc_length).max(0.0);
+        ((x + beyond * turned.cos(), y + beyond * turned.sin()), turned)
+    }
+
+    /// Centre and heading of the cyclist
+    pub fn pose(&self) -> ((f32, f32), f32) {
+        self.pose_at(self.progress)
+    }
+
+    /// Distance of the front wheel along the route from the stop line
+    fn front(&self) -> f32 {
+        self.progress + CYCLIST_LENGTH as f32 / 2.0
+    }
+
+    /// Whether the front wheel has crossed the stop line
+    pub fn has_entered(&self) -> bool {
+        self.front() > 0.0
+    }
+
+    /// Whether the cyclist stands at the stop line
+    pub fn is_at_line(&self) -> bool {
+        !self.has_entered() && self.front() >= -f32::EPSILON
+    }
+
+    /// Length of the route across the intersection, from the stop line to the far edge
+    fn crossing_length(&self) -> f32 {
+        match self.direction {
+            Direction::Right => turn_radius() * FRAC_PI_2,
+            _ => 2.0 * KERB,
+        }
+    }
+
+    /// Whether any part of the cyclist is still within the intersection
+    pub fn is_crossing(&self) -> bool {
+        self.has_entered() && self.progress - (CYCLIST_LENGTH as f32 / 2.0) < self.crossing_length()
+    }
+
+    /// Whether the cyclist has ridden off the edge of the window
+    fn has_departed(&self) -> bool {
+        let ((x, y), _) = self.pose();
+        x < 0.0 || x > WINDOW_WIDTH as f32 || y < 0.0 || y > WINDOW_HEIGHT as f32
+    }
+}
+
+/// Cyclists riding in the bike lanes of every approach
+///
+/// Cyclists arrive at random with top speeds spread between `CYCLIST_SPEED_MIN`
+/// and `CYCLIST_SPEED_MAX`, stop at the line when they may not go on, and never
+/// ride into a vehicle, a pedestrian or another cyclist. Vehicles give way to
+/// cyclists in their path, and drivers about to turn right wait for cyclists
+/// riding straight on that they can see coming.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CyclistTraffic {
+    /// Whether cyclists arrive
+    pub enabled: bool,
+    /// Cyclists per hour arriving on each approach
+    pub arrivals_per_hour: f32,
+    /// Whether vehicles wait at a red light behind a bike box for cyclists to wait in
+    pub bike_boxes: bool,
+    /// Cyclists on the approaches, in the intersection and on the exit roads
+    pub cyclists: Vec<Cyclist>,
+    /// Number of cyclists who have ridden through
+    pub crossed: usize,
+    /// Sum of the delay of cyclists who have ridden through, in frames
+    pub total_delay: f32,
+    /// Number of cyclists a right-turning vehicle has come too close to
+    pub right_hooks: usize,
+    /// Frames vehicles have spent giving way to cyclists
+    pub vehicle_yield_ticks: u32,
+}
+
+impl CyclistTraffic {
+    /// Create the cyclist traffic set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: CYCLISTS_ENABLED,
+            arrivals_per_hour: CYCLIST_ARRIVALS_PER_HOUR,
+            bike_boxes: BIKE_BOXES_ENABLED,
+            cyclists: vec![],
+            crossed: 0,
+            total_delay: 0.0,
+            right_hooks: 0,
+            vehicle_yield_ticks: 0,
+        }
+    }
+
+    /// Draws this frame's arrivals on the legs of the layout, with one frame lasting 1 / `FPS` of a second
+    ///
+    /// Cyclists turn right with probability `CYCLIST_RIGHT_TURN_SHARE`, where the
+    /// layout allows both. A cyclist only sets off where there is room between the
+    /// given vehicles and the other cyclists.
+    pub fn tick<R: Rng>(&mut self, rng: &mut R, layout: Layout, vehicles: &[&Vehicle]) {
+        if !self.enabled {
+            return;
+        }
+
+        let probability = (self.arrivals_per_hour as f64 / (FPS * 3600) as f64).min(1.0);
+        for side in layout.legs() {
+            if !rng.gen_bool(probability) {
+                continue;
+            }
+
+            let direction = match (layout.allows(side, Direction::Straight), layout.allows(side, Direction::Right)) {
+                (true, true) if rng.gen_bool(CYCLIST_RIGHT_TURN_SHARE) => Direction::Right,
+                (true, _) => Direction::Straight,
+                (false, true) => Direction::Right,
+                (false, false) => continue,
+            };
+
+            // Top speeds cluster around the middle of the range
+            let spread = (rng.gen::<f32>() + rng.gen::<f32>()) / 2.0;
+            let max_velocity = CYCLIST_SPEED_MIN + (CYCLIST_SPEED_MAX - CYCLIST_SPEED_MIN) * spread;
+            let cyclist = Cyclist::new(side, direction, max_velocity);
+
+            // Wait for room at the start of the bike lane
+            let room = self.cyclists.iter()
+                .filter(|other| other.side == side)
+                .all(|other| other.progress - cyclist.progress >= CYCLIST_LENGTH as f32 + CYCLIST_GAP);
+            let margin = CYCLIST_LENGTH as f32 / 2.0 + CYCLIST_GAP;
+            let (center, _) = cyclist.pose();
+            if room && vehicles.iter().all(|vehicle| !covers_vehicle(vehicle, margin, center)) {
+                self.cyclists.push(cyclist);
+            }
+        }
+    }
+
+    /// Removes the cyclists that would use a leg there is no road on
+    pub fn close_leg(&mut self, side: Side) {
+        self.cyclists.retain(|cyclist| cyclist.side != side && cyclist.side.exit_leg(cyclist.direction) != side);
+    }
+
+    /// Whether a cyclist is on or ahead of the vehicle, in the way of its next move
+    pub fn in_path(&self, vehicle: &Vehicle) -> bool {
+        self.cyclists.iter().any(|cyclist| Self::is_in_path_of(cyclist, vehicle))
+    }
+
+    /// Whether the cyclist is on or ahead of the vehicle, in the way of its next move
+    ///
+    /// A cyclist whose centre is behind the back of the vehicle is following it, and
+    /// does not hold it up.
+    fn is_in_path_of(cyclist: &Cyclist, vehicle: &Vehicle) -> bool {
+        let half_length = vehicle.length() as f32 / 2.0;
+        let reach = half_length + vehicle.next_velocity() + SAFETY_GAP as f32;
+        let half_breadth = (vehicle.breadth() + CYCLIST_WIDTH) as f32 / 2.0 + CLEARANCE;
+        let (along, across) = relative_to(vehicle.center(), vehicle.heading, cyclist.pose().0);
+        along >= -half_length && along <= reach && across <= half_breadth
+    }
+
+    /// Whether a cyclist is within `margin` of the vehicle
+    pub fn near(&self, vehicle: &Vehicle, margin: f32) -> bool {
+        self.cyclists.iter().any(|cyclist| covers_vehicle(vehicle, margin, cyclist.pose().0))
+    }
+
+    /// Whether a driver about to turn right from the given approach sees a cyclist coming
+    /// who rides straight on across its path
+    ///
+    /// Drivers look for cyclists up to `RIGHT_TURN_LOOKBACK` behind the line, and
+    /// wait until they are a quarter of the way across, past where the turn cuts the bike lane.
+    pub fn approaching(&self, side: Side) -> bool {
+        self.cyclists.iter().any(|cyclist| {
+            cyclist.side == side && cyclist.direction == Direction::Straight &&
+            cyclist.front() >= -RIGHT_TURN_LOOKBACK && cyclist.progress < KERB / 2.0
+        })
+    }
+
+    /// Whether none of the vehicles in the intersection will come near the cyclist's way across
+    ///
+    /// Each vehicle's way out of the intersection is played out in full, so that the
+    /// cyclist does not set off across a path a vehicle has yet to take.
+    pub fn is_clear_for(cyclist: &Cyclist, vehicles: &[Vehicle]) -> bool {
+        let length = cyclist.crossing_length();
+        let steps = (length / CYCLIST_LENGTH as f32).ceil() as usize;
+        let points: Vec<(f32, f32)> = (0..=steps)
+            .map(|step| cyclist.pose_at(length * step as f32 / steps as f32).0)
+            .collect();
+
+        let green = TrafficLight::green();
+        vehicles.iter().all(|vehicle| {
+            let mut trial = vehicle.clone();
+            while !trial.has_cleared_intersection() {
+                if points.iter().any(|&point| covers_vehicle(&trial, CROSSING_CLEARANCE, point)) {
+                    return false;
+                }
+                trial.update_position(&green);
+            }
+            true
+        })
+    }
+
+    /// Footprints of the cyclist crossing from the stop line, setting off from a standstill at the given frame
+    pub fn footprints(cyclist: &Cyclist, time: u32) -> Vec<Footprint> {
+        let size = CYCLIST_LENGTH as f32 + 2.0 * CLEARANCE;
+        let mut trial = cyclist.clone();
+        trial.velocity = 0.0;
+
+        let mut footprints = vec![];
+        let mut now = time;
+        while trial.progress - (CYCLIST_LENGTH as f32 / 2.0) < trial.crossing_length() {
+            let ((x, y), _) = trial.pose();
+            footprints.push(((x - size / 2.0, y - size / 2.0, size, size), now));
+            trial.velocity = (trial.velocity + CYCLIST_ACCELERATION).min(trial.max_velocity);
+            trial.progress += trial.velocity;
+            now += 1;
+        }
+        footprints
+    }
+
+    /// Moves the cyclists on by one frame
+    ///
+    /// `may_enter` tells for each cyclist whether it may ride past the stop line.
+    /// A cyclist stops rather than ride into one of the given vehicles, unless the
+    /// vehicle is giving way to it and it has room to pass, or into a pedestrian or
+    /// another cyclist.
+    pub fn update(&mut self, may_enter: &[bool], vehicles: &[&Vehicle], pedestrians: &PedestrianTraffic) {
+        let half_length = CYCLIST_LENGTH as f32 / 2.0;
+        let half_width = CYCLIST_WIDTH as f32 / 2.0;
+
+        for (i, &may_go) in may_enter.iter().enumerate() {
+            let cyclist = &self.cyclists[i];
+            let velocity = (cyclist.velocity + CYCLIST_ACCELERATION).min(cyclist.max_velocity);
+            let (center, heading) = cyclist.pose();
+            let reach = half_length + velocity + CYCLIST_GAP;
+            let ahead = |distance: f32| cyclist.pose_at(cyclist.progress + distance).0;
+
+            // Hold at the line until the cyclist may go
+            let hold = !cyclist.has_entered() && !may_go && cyclist.front() + velocity >= 0.0;
+
+            // Of two cyclists each in the other's way, the one who came first goes on
+            let blocked_by_cyclist = self.cyclists.iter().enumerate().any(|(j, other)| {
+                if j == i {
+                    return false;
+                }
+                let in_way = |from: &Cyclist, to: &Cyclist| {
+                    let (along, across) = relative_to(from.pose().0, from.pose().1, to.pose().0);
+                    along > 0.0 && along <= reach + half_length && across <= CYCLIST_WIDTH as f32 + CLEARANCE
+                };
+                in_way(cyclist, other) && !(j > i && in_way(other, cyclist))
+            });
+
+            let blocked_by_vehicle = vehicles.iter().any(|vehicle| {
+                if covers_vehicle(vehicle, 0.0, center) {
+                    // Get clear of a vehicle that has come too close
+                    return false;
+                }
+                let margin = if Self::is_in_path_of(cyclist, vehicle) { 0.0 } else { half_width + CLEARANCE };
+                [half_length, half_length + velocity, reach]
+                    .into_iter()
+                    .any(|distance| covers_vehicle(vehicle, margin, ahead(distance)))
+            });
+
+            let blocked_by_pedestrian = cyclist.has_entered() || cyclist.front() + velocity >= 0.0;
+            let blocked_by_pedestrian = blocked_by_pedestrian &&
+                pedestrians.in_way(center, heading, half_length, half_width, reach);
+
+            let cyclist = &mut self.cyclists[i];
+            if hold {
+                cyclist.progress = -half_length;
+                cyclist.velocity = 0.0;
+            } else if blocked_by_cyclist || blocked_by_vehicle || blocked_by_pedestrian {
+                cyclist.velocity = 0.0;
+            } else {
+                cyclist.velocity = velocity;
+                cyclist.progress += velocity;
+            }
+            cyclist.delay += 1.0 - cyclist.velocity / cyclist.max_velocity;
+        }
+
+        let (departed, remaining): (Vec<Cyclist>, Vec<Cyclist>) = self.cyclists.drain(..)
+            .partition(Cyclist::has_departed);
+        self.cyclists = remaining;
+        self.crossed += departed.len();
+        self.total_delay += departed.iter().map(|cyclist| cyclist.delay).sum::<f32>();
+    }
+
+    /// Notes the cyclists riding straight on that a vehicle turning right has come too close to
+    pub fn record_right_hooks(&mut self, vehicles: &[Vehicle]) {
+        for cyclist in &mut self.cyclists {
+            if cyclist.hooked || cyclist.direction != Direction::Straight || !cyclist.is_crossing() {
+                continue;
+            }
+            let (center, _) = cyclist.pose();
+            let hooked = vehicles.iter().any(|vehicle| {
+                vehicle.side == cyclist.side && vehicle.direction == Direction::Right && vehicle.velocity > 0.0 &&
+                covers_vehicle(vehicle, RIGHT_HOOK_DISTANCE, center)
+            });
+            if hooked {
+                cyclist.hooked = true;
+                self.right_hooks += 1;
+            }
+        }
+    }
+
+    /// Average delay of cyclists who have ridden through, in frames
+    pub fn average_delay(&self) -> Option<f32> {
+        if self.crossed == 0 {
+            return None;
+        }
+        Some(self.total_delay / self.crossed as f32)
+    }
+}
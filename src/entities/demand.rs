@@ -0,0 +1,103 @@
+use crate::config::*;
+use crate::types::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Vehicles arriving at the edge of the simulation according to an origin-destination matrix
+///
+/// Every pair of entry side and exit leg produces random arrivals at its rate, and
+/// the turn each vehicle makes follows from the pair. Arrivals that find no room
+/// at the start of their approach wait to enter, in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OdDemand {
+    /// Whether vehicles are generated
+    pub enabled: bool,
+    /// Vehicles per hour from each entry side to each exit leg, both in `OD_SIDES` order
+    pub matrix: [[f32; 4]; 4],
+    /// Number of vehicles generated so far
+    pub generated: usize,
+    /// Vehicles generated but not able to enter yet, per entry side and exit leg
+    pending: [[usize; 4]; 4],
+}
+
+impl OdDemand {
+    /// Create the demand set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: DEMAND_ENABLED,
+            matrix: OD_MATRIX,
+            generated: 0,
+            pending: [[0; 4]; 4],
+        }
+    }
+
+    /// Position of a side in the rows and columns of the matrix
+    pub fn index(side: Side) -> usize {
+        OD_SIDES.iter().position(|&other| other == side).unwrap_or(0)
+    }
+
+    /// Stops generating vehicles that enter from the given side
+    pub fn close_entry(&mut self, side: Side) {
+        let row = Self::index(side);
+        self.matrix[row] = [0.0; 4];
+        self.pending[row] = [0; 4];
+    }
+
+    /// Stops generating vehicles that enter from or leave by the given side
+    pub fn close_leg(&mut self, side: Side) {
+        self.close_entry(side);
+        let column = Self::index(side);
+        for row in 0..OD_SIDES.len() {
+            self.matrix[row][column] = 0.0;
+            self.pending[row][column] = 0;
+        }
+    }
+
+    /// Draws this frame's arrivals, with one frame lasting 1 / `FPS` of a second
+    pub fn tick<R: Rng>(&mut self, rng: &mut R) {
+        if !self.enabled {
+            return;
+        }
+
+        let frames_per_hour = (FPS * 3600) as f64;
+        for (row, &entry) in OD_SIDES.iter().enumerate() {
+            for (column, &exit) in OD_SIDES.iter().enumerate() {
+                let rate = self.matrix[row][column];
+                if rate <= 0.0 || entry.direction_to(exit).is_none() {
+                    continue;
+                }
+                if rng.gen_bool((rate as f64 / frames_per_hour).min(1.0)) {
+                    self.pending[row][column] += 1;
+                    self.generated += 1;
+                }
+            }
+        }
+    }
+
+    /// Movements that have a vehicle waiting to enter, as entry side and turn
+    pub fn due(&self) -> Vec<(Side, Direction)> {
+        let mut due = vec![];
+        for (row, &entry) in OD_SIDES.iter().enumerate() {
+            for (column, &exit) in OD_SIDES.iter().enumerate() {
+                if self.pending[row][column] == 0 {
+                    continue;
+                }
+                if let Some(direction) = entry.direction_to(exit) {
+                    due.push((entry, direction));
+                }
+            }
+        }
+        due
+    }
+
+    /// Records that a vehicle making the given movement has entered
+    pub fn dispatched(&mut self, side: Side, direction: Direction) {
+        let pending = &mut self.pending[Self::index(side)][Self::index(side.exit_leg(direction))];
+        *pending = pending.saturating_sub(1);
+    }
+
+    /// Number of vehicles waiting to enter
+    pub fn backlog(&self) -> usize {
+        self.pending.iter().flatten().sum()
+    }
+}
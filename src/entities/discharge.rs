@@ -0,0 +1,171 @@
+use crate::config::*;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+
+/// Queue in one lane being discharged by a green
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct LaneDischarge {
+    side: Side,
+    lane: usize,
+    /// Vehicles standing in the lane when the green started that have yet to cross the line
+    queued: usize,
+    /// Position in the queue of the last vehicle to cross the line
+    position: usize,
+    /// Frame at which the last vehicle crossed the line, or the green started
+    last: u32,
+}
+
+/// Discharge of the queues at traffic lights, measured the way it is in the field
+///
+/// When an approach turns green, the vehicles standing in each lane are numbered
+/// from the line back. As each crosses the line, the time since the vehicle ahead
+/// crossed, or since the start of the green for the first, is its headway. The
+/// saturation headway is the mean headway from `SATURATION_HEADWAY_POSITION` on,
+/// and the start-up lost time is the time the positions before it take beyond it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueDischarge {
+    /// Whether standing drivers take their reaction time to move off once the way is clear
+    pub reaction: bool,
+    /// Sum and number of the headways measured at each queue position, from the first
+    pub headways: Vec<(f32, usize)>,
+    /// Queues being discharged
+    lanes: Vec<LaneDischarge>,
+    /// Approaches that were green in the last frame
+    green: Vec<Side>,
+}
+
+impl QueueDischarge {
+    /// Create the queue discharge set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            reaction: STARTUP_REACTION_ENABLED,
+            headways: vec![(0.0, 0); DISCHARGE_POSITIONS],
+            lanes: vec![],
+            green: vec![],
+        }
+    }
+
+    /// Notes whether the light on an approach is green, and tells whether the green has just started
+    pub fn green_started(&mut self, side: Side, green: bool) -> bool {
+        let was_green = self.green.contains(&side);
+        if green && !was_green {
+            self.green.push(side);
+        } else if !green {
+            self.green.retain(|&other| other != side);
+        }
+        green && !was_green
+    }
+
+    /// Starts numbering the vehicles standing in a lane when its green starts at the given frame
+    pub fn start(&mut self, side: Side, lane: usize, queued: usize, time: u32) {
+        self.lanes.retain(|discharge| discharge.side != side || discharge.lane != lane);
+        if queued > 0 {
+            self.lanes.push(LaneDischarge { side, lane, queued, position: 0, last: time });
+        }
+    }
+
+    /// Records the headway of a vehicle crossing the line from a lane at the given frame,
+    /// if it stood in the queue when the green started
+    pub fn entered(&mut self, side: Side, lane: usize, time: u32) {
+        let Some(discharge) = self.lanes.iter_mut().find(|discharge| discharge.side == side && discharge.lane == lane) else {
+            return;
+        };
+
+        if let Some((sum, count)) = self.headways.get_mut(discharge.position) {
+            *sum += (time - discharge.last) as f32;
+            *count += 1;
+        }
+        discharge.position += 1;
+        discharge.last = time;
+        discharge.queued -= 1;
+        if discharge.queued == 0 {
+            self.lanes.retain(|discharge| discharge.side != side || discharge.lane != lane);
+        }
+    }
+
+    /// Mean headway at each queue position, in frames
+    pub fn mean_headways(&self) -> Vec<Option<f32>> {
+        self.headways.iter()
+            .map(|&(sum, count)| (count > 0).then(|| sum / count as f32))
+            .collect()
+    }
+
+    /// Mean headway of the vehicles from `SATURATION_HEADWAY_POSITION` back, in frames
+    pub fn saturation_headway(&self) -> Option<f32> {
+        let (sum, count) = self.headways.iter()
+            .skip(SATURATION_HEADWAY_POSITION - 1)
+            .fold((0.0, 0), |(sum, count), &(other_sum, other_count)| (sum + other_sum, count + other_count));
+        (count > 0).then(|| sum / count as f32)
+    }
+
+    /// Time the vehicles ahead of `SATURATION_HEADWAY_POSITION` take beyond the saturation headway, in frames
+    pub fn start_up_lost_time(&self) -> Option<f32> {
+        let saturation = self.saturation_headway()?;
+        self.mean_headways()
+            .into_iter()
+            .take(SATURATION_HEADWAY_POSITION - 1)
+            .map(|headway| headway.map(|headway| headway - saturation))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets the given queue in the first lane from the east go at a green starting at frame 0,
+    /// with the given headways
+    fn discharge(headways: &[u32]) -> QueueDischarge {
+        let mut discharge = QueueDischarge::configured();
+        discharge.start(Side::FromEast, 0, headways.len(), 0);
+        let mut time = 0;
+        for headway in headways {
+            time += headway;
+            discharge.entered(Side::FromEast, 0, time);
+        }
+        discharge
+    }
+
+    #[test]
+    fn a_green_starts_once_until_the_light_changes_again() {
+        let mut discharge = QueueDischarge::configured();
+        assert!(!discharge.green_started(Side::FromEast, false));
+        assert!(discharge.green_started(Side::FromEast, true));
+        assert!(!discharge.green_started(Side::FromEast, true));
+        assert!(discharge.green_started(Side::FromWest, true));
+        assert!(!discharge.green_started(Side::FromEast, false));
+        assert!(discharge.green_started(Side::FromEast, true));
+    }
+
+    #[test]
+    fn headways_are_measured_for_the_vehicles_queued_at_the_start_of_the_green() {
+        let mut discharge = discharge(&[10, 5, 3]);
+        assert_eq!(discharge.mean_headways()[..4], [Some(10.0), Some(5.0), Some(3.0), None]);
+
+        // A vehicle that joined the queue after the green started is not counted
+        discharge.entered(Side::FromEast, 0, 30);
+        discharge.entered(Side::FromWest, 0, 30);
+        assert_eq!(discharge.headways.iter().map(|&(_, count)| count).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn a_new_green_renumbers_the_queue() {
+        let mut discharge = QueueDischarge::configured();
+        discharge.start(Side::FromEast, 0, 3, 0);
+        discharge.entered(Side::FromEast, 0, 10);
+        discharge.start(Side::FromEast, 0, 2, 100);
+        discharge.entered(Side::FromEast, 0, 106);
+        assert_eq!(discharge.headways[0], (16.0, 2));
+        assert_eq!(discharge.headways[1], (0.0, 0));
+    }
+
+    #[test]
+    fn start_up_lost_time_is_taken_beyond_the_saturation_headway() {
+        assert_eq!(discharge(&[10, 8]).saturation_headway(), None);
+        assert_eq!(discharge(&[10, 8]).start_up_lost_time(), None);
+
+        let discharge = discharge(&[10, 8, 6, 5, 4, 4, 4, 4]);
+        assert_eq!(discharge.saturation_headway(), Some(4.0));
+        assert_eq!(discharge.start_up_lost_time(), Some(13.0));
+    }
+}
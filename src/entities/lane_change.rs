@@ -0,0 +1,159 @@
+use crate::config::*;
+use crate::types::LaneUse;
+use super::vehicle::Vehicle;
+use serde::{Deserialize, Serialize};
+
+/// Lane changing on the approaches, following the MOBIL model
+///
+/// A vehicle changes lanes when the new lane is safe, meaning the new follower
+/// would not have to brake harder than `max_safe_deceleration`, and worth it,
+/// meaning its own gain in acceleration outweighs the losses it causes to the
+/// vehicles around it, scaled by `politeness`, by more than `threshold`.
+///
+/// Vehicles in a lane that does not allow their direction have to change lanes.
+/// Such mandatory changes get a bias towards the right lane, and within
+/// `LANE_CHANGE_ZONE` of the stop line only the safety criterion applies. Other
+/// changes are no longer made within that zone. A vehicle that still has not
+/// made it by the time it gets a green takes the movement its lane allows.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LaneChangeModel {
+    /// Weight given to the acceleration of the other vehicles
+    pub politeness: f32,
+    /// Minimum gain in acceleration that makes a lane change worth it
+    pub threshold: f32,
+    /// Hardest braking a lane change may impose on the new follower
+    pub max_safe_deceleration: f32,
+    /// Extra incentive to leave a lane the vehicle may not use
+    pub mandatory_bias: f32,
+    /// Number of lane changes made to reach a lane allowing the vehicle's direction
+    pub mandatory_changes: usize,
+    /// Number of lane changes made to get past slower vehicles
+    pub discretionary_changes: usize,
+    /// Number of vehicles that reached the stop line in a lane not allowing their direction
+    pub missed_turns: usize,
+}
+
+/// Vehicles around a vehicle in a lane
+struct Neighbours<'a> {
+    leader: Option<&'a Vehicle>,
+    follower: Option<&'a Vehicle>,
+}
+
+impl LaneChangeModel {
+    /// Create the lane changing model from the configuration
+    pub fn configured() -> Self {
+        Self {
+            politeness: LANE_CHANGE_POLITENESS,
+            threshold: LANE_CHANGE_THRESHOLD,
+            max_safe_deceleration: LANE_CHANGE_MAX_SAFE_DECELERATION,
+            mandatory_bias: LANE_CHANGE_MANDATORY_BIAS,
+            mandatory_changes: 0,
+            discretionary_changes: 0,
+            missed_turns: 0,
+        }
+    }
+    
+    /// Picks the lane a waiting vehicle should move to, if any
+    ///
+    /// `lanes` are the queues of the vehicle's approach, ordered front first, and
+    /// the vehicle is at `index` in the queue of `lane`.
+    pub fn choose_lane(&self, lanes: &[Vec<Vehicle>], lane_uses: &[LaneUse], lane: usize, index: usize) -> Option<usize> {
+        let vehicle = &lanes[lane][index];
+        if vehicle.is_changing_lane() || vehicle.routed {
+            return None;
+        }
+        
+        let allowed = |lane: usize| lane_uses[lane].allows(vehicle.direction);
+        let mandatory = !allowed(lane);
+        let in_zone = vehicle.distance_to_stop_line() <= LANE_CHANGE_ZONE as f32;
+        
+        let candidates: Vec<usize> = if mandatory {
+            // Head one lane over towards the nearest lane the vehicle may use
+            let nearest = (0..lanes.len())
+                .filter(|&other| allowed(other))
+                .min_by_key(|&other| other.abs_diff(lane))?;
+            vec![if nearest < lane { lane - 1 } else { lane + 1 }]
+        } else if in_zone {
+            return None;
+        } else {
+            [lane.checked_sub(1), Some(lane + 1)]
+                .into_iter()
+                .flatten()
+                .filter(|&other| other < lanes.len() && allowed(other))
+                .collect()
+        };
+        
+        let current = Self::neighbours(&lanes[lane], vehicle, Some(index));
+        candidates.into_iter()
+            .filter_map(|target| {
+                let next = Self::neighbours(&lanes[target], vehicle, None);
+                if !self.is_safe(vehicle, &next) {
+                    return None;
+                }
+                
+                let incentive = self.incentive(vehicle, &current, &next);
+                if mandatory && in_zone {
+                    Some((target, f32::INFINITY))
+                } else if mandatory {
+                    Some((target, incentive + self.mandatory_bias))
+                } else {
+                    Some((target, incentive))
+                }
+            })
+            .filter(|&(_, incentive)| incentive > self.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(target, _)| target)
+    }
+    
+    /// Records a lane change that has been made
+    pub fn record(&mut self, mandatory: bool) {
+        if mandatory {
+            self.mandatory_changes += 1;
+        } else {
+            self.discretionary_changes += 1;
+        }
+    }
+    
+    /// Finds the vehicles directly ahead of and behind the vehicle in a lane
+    ///
+    /// `index` is the position of the vehicle in the queue if it is already in it.
+    fn neighbours<'a>(queue: &'a [Vehicle], vehicle: &Vehicle, index: Option<usize>) -> Neighbours<'a> {
+        let position = index.unwrap_or_else(|| {
+            queue.iter()
+                .position(|other| other.route_progress() < vehicle.route_progress())
+                .unwrap_or(queue.len())
+        });
+        let after = if index.is_some() { position + 1 } else { position };
+        Neighbours {
+            leader: position.checked_sub(1).map(|ahead| &queue[ahead]),
+            follower: queue.get(after),
+        }
+    }
+    
+    /// Whether the vehicle fits in between its new neighbours without forcing hard braking
+    fn is_safe(&self, vehicle: &Vehicle, next: &Neighbours) -> bool {
+        let room_ahead = next.leader.is_none_or(|leader| vehicle.gap_behind(leader) > SAFETY_GAP as f32);
+        let room_behind = next.follower.is_none_or(|follower| {
+            follower.gap_behind(vehicle) > SAFETY_GAP as f32 && 
+            follower.desired_acceleration(Some(vehicle)) >= -self.max_safe_deceleration
+        });
+        room_ahead && room_behind
+    }
+    
+    /// Net gain in acceleration from the change, counting the vehicles around it
+    fn incentive(&self, vehicle: &Vehicle, current: &Neighbours, next: &Neighbours) -> f32 {
+        let own_gain = vehicle.desired_acceleration(next.leader) - vehicle.desired_acceleration(current.leader);
+        
+        // The old follower closes up behind the old leader
+        let old_follower_gain = current.follower.map_or(0.0, |follower| {
+            follower.desired_acceleration(current.leader) - follower.desired_acceleration(Some(vehicle))
+        });
+        
+        // The new follower ends up behind the vehicle
+        let new_follower_gain = next.follower.map_or(0.0, |follower| {
+            follower.desired_acceleration(Some(vehicle)) - follower.desired_acceleration(next.leader)
+        });
+        
+        own_gain + self.politeness * (old_follower_gain + new_follower_gain)
+    }
+}
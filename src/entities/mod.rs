@@ -1,7 +1,24 @@
 // Entity modules
 pub mod vehicle;
 pub mod traffic;
+pub mod transit;
+pub mod lane_change;
+pub mod network;
+pub mod coordination;
+pub mod demand;
+pub mod roundabout;
+pub mod stop_control;
+pub mod reservation;
+pub mod pedestrian;
+pub mod cyclist;
+pub mod safety;
+pub mod surrogate;
+pub mod compliance;
+pub mod discharge;
+pub mod recording;
+pub mod trends;
 
 // Re-export for convenience
 pub use vehicle::Vehicle;
-pub use traffic::Intersection;
\ No newline at end of file
+pub use traffic::Intersection;
+pub use network::Network;
@@ -0,0 +1,441 @@
+use crate::config::*;
+use crate::types::*;
+use super::traffic::Intersection;
+use super::vehicle::Vehicle;
+use super::coordination::{Corridor, SignalPlan, TimeSpaceDiagram};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+/// One direction of a road between two intersections of the network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    /// Intersection the link starts at
+    pub from: usize,
+    /// Leg of that intersection vehicles leave by
+    pub leg: Side,
+    /// Intersection the link leads to
+    pub to: usize,
+    /// Approach of that intersection vehicles arrive on
+    pub side: Side,
+    /// Length of the link, in pixels
+    pub length: f32,
+    /// Vehicles on the link with the time they reach its end, in order of arrival
+    pub vehicles: Vec<(u32, Vehicle)>,
+}
+
+impl Link {
+    /// Frames a vehicle is expected to take to get along the link
+    fn travel_time(&self) -> f32 {
+        self.length / CAR_SPEC.max_velocity
+    }
+}
+
+/// Measurements of trips through the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct TripStats {
+    /// Number of trips started
+    pub started: usize,
+    /// Number of trips that reached their destination
+    pub completed: usize,
+    /// Number of trips that left the network anywhere but their destination
+    pub strayed: usize,
+    /// Sum of the travel times of completed trips, in frames
+    pub total_travel_time: u32,
+}
+
+impl TripStats {
+    /// Average travel time of completed trips, in frames
+    pub fn average_travel_time(&self) -> Option<f32> {
+        if self.completed == 0 {
+            return None;
+        }
+        Some(self.total_travel_time as f32 / self.completed as f32)
+    }
+}
+
+/// Several intersections joined by links
+///
+/// Each intersection is simulated in its own window-sized area. A vehicle that
+/// drives off the edge of one area onto a link reaches the approach queue of the
+/// next intersection once it has travelled the length of the link. Legs without a
+/// link lead out of the network, and serve as origins and destinations of trips.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Network {
+    // Intersections and the links joining them
+    pub intersections: Vec<Intersection>,
+    pub links: Vec<Link>,
+    
+    // Chain of intersections along the coordinated approach, if the network has one
+    pub corridor: Option<Corridor>,
+    
+    // Metrics
+    pub simulation_time: u32,
+    pub trips: TripStats,
+    pub time_space: TimeSpaceDiagram,
+    
+    // Random draws for the trips started, saved with snapshots so a restored run carries on the same way
+    pub rng: ChaCha12Rng,
+}
+
+impl Network {
+    /// Creates the network set up in the configuration
+    pub fn configured() -> Self {
+        let mut links = vec![];
+        for road in NETWORK_ROADS {
+            // Every road carries traffic both ways
+            for (from, leg, to) in [(road.from, road.leg, road.to), (road.to, road.leg.opposite(), road.from)] {
+                links.push(Link {
+                    from,
+                    leg,
+                    to,
+                    side: leg.opposite(),
+                    length: road.length,
+                    vehicles: vec![],
+                });
+            }
+        }
+
+        // Demand only enters the network on legs without a road leading in
+        let mut intersections: Vec<Intersection> = NETWORK_LAYOUTS.into_iter()
+            .zip(NETWORK_CONTROLS)
+            .map(|(layout, control)| {
+                let mut intersection = Intersection::with_layout(layout);
+                intersection.set_control(control);
+                intersection
+            })
+            .collect();
+        for link in &links {
+            intersections[link.to].demand.close_entry(link.side);
+        }
+
+        let mut network = Self {
+            intersections,
+            links,
+            corridor: None,
+            simulation_time: 0,
+            trips: TripStats::default(),
+            time_space: TimeSpaceDiagram::default(),
+            rng: ChaCha12Rng::from_entropy(),
+        };
+        network.corridor = network.find_corridor(COORDINATED_SIDE);
+        network.set_coordinated(COORDINATION_ENABLED);
+        network
+    }
+    
+    /// Follows the links taken by vehicles going straight on the given approach
+    ///
+    /// The corridor starts at the intersection no such link leads to, and is only
+    /// worth having if it runs through more than one intersection.
+    pub fn find_corridor(&self, side: Side) -> Option<Corridor> {
+        let leg = side.exit_leg(Direction::Straight);
+        let start = (0..self.intersections.len()).find(|&index| {
+            self.link_from(index, leg).is_some() && 
+            !self.links.iter().any(|link| link.to == index && link.side == side)
+        })?;
+        
+        let area_length = match side {
+            Side::FromEast | Side::FromWest => WINDOW_WIDTH as f32,
+            Side::FromNorth | Side::FromSouth => WINDOW_HEIGHT as f32,
+        };
+        let mut stops = vec![(start, 0.0)];
+        while let Some(link) = stops.last().and_then(|&(index, _)| self.link_from(index, leg)) {
+            let link = &self.links[link];
+            if stops.iter().any(|&(index, _)| index == link.to) {
+                break;
+            }
+            let distance = stops.last().map_or(0.0, |&(_, distance)| distance) + area_length + link.length;
+            stops.push((link.to, distance));
+        }
+        
+        Some(Corridor { side, stops })
+    }
+    
+    /// Whether the intersections of the corridor run coordinated fixed-time plans
+    pub fn is_coordinated(&self) -> bool {
+        self.intersections.iter().any(|intersection| intersection.signal_plan.is_some())
+    }
+    
+    /// Gives every intersection of the corridor a fixed-time plan with a common cycle
+    /// and offsets for a green wave along it, or returns them to actuated control
+    pub fn set_coordinated(&mut self, enabled: bool) {
+        let Some(corridor) = &self.corridor else {
+            return;
+        };
+        for (&(index, _), offset) in corridor.stops.iter().zip(corridor.offsets()) {
+            let layout = self.intersections[index].layout;
+            let plan = enabled.then(|| SignalPlan::coordinated(corridor.side, offset, layout));
+            self.intersections[index].set_signal_plan(plan);
+        }
+    }
+    
+    /// Adds the current light states and vehicle positions along the corridor to the time-space diagram
+    fn record_time_space(&mut self) {
+        let Some(corridor) = &self.corridor else {
+            return;
+        };
+        let time = self.simulation_time;
+        let side = corridor.side;
+        let stop_line = corridor.area_length() / 2.0 - INTERSECTION_HALF_WIDTH as f32;
+        
+        for &(index, distance) in &corridor.stops {
+            let state = self.intersections[index].light(side).state;
+            self.time_space.record_signal(index, distance + stop_line, state, time);
+        }
+        
+        if !time.is_multiple_of(TIME_SPACE_SAMPLE_TICKS) {
+            return;
+        }
+        
+        let exit_leg = side.exit_leg(Direction::Straight);
+        for &(index, distance) in &corridor.stops {
+            let intersection = &self.intersections[index];
+            
+            // Vehicles on the approach, in the intersection and on the exit road along the corridor
+            let exit_road = match exit_leg {
+                Side::FromEast => &intersection.vehicles_passed_east,
+                Side::FromWest => &intersection.vehicles_passed_west,
+                Side::FromNorth => &intersection.vehicles_passed_north,
+                Side::FromSouth => &intersection.vehicles_passed_south,
+            };
+            let vehicles = intersection.waiting(side).iter().flatten()
+                .chain(intersection.vehicles_in_intersection.iter().filter(|vehicle| vehicle.side == side))
+                .chain(exit_road.iter());
+            for vehicle in vehicles {
+                let position = distance + Corridor::distance_in_area(side, vehicle.center());
+                self.time_space.record_vehicle(time, position, vehicle.velocity);
+            }
+            
+            // Vehicles on the link to the next intersection
+            if let Some(link) = self.link_from(index, exit_leg) {
+                let link = &self.links[link];
+                for (arrival, vehicle) in &link.vehicles {
+                    let remaining = (arrival.saturating_sub(time) as f32 * vehicle.max_velocity).min(link.length);
+                    let position = distance + corridor.area_length() + link.length - remaining;
+                    self.time_space.record_vehicle(time, position, vehicle.max_velocity);
+                }
+            }
+        }
+    }
+
+    /// Writes the complete state of the network to a JSON file
+    ///
+    /// The random generators are saved as well, so a run restored from the
+    /// snapshot draws the same arrivals and driver choices as the original.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Reads a network written by `save`, to carry on from where it was
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Performs a single update step for every intersection and link
+    pub fn update(&mut self) {
+        for intersection in &mut self.intersections {
+            intersection.update();
+        }
+
+        // Put vehicles that have left an intersection on their link, or end their trip
+        for index in 0..self.intersections.len() {
+            for vehicle in std::mem::take(&mut self.intersections[index].vehicles_departed) {
+                let exit_leg = vehicle.exit_leg();
+                match self.link_from(index, exit_leg) {
+                    Some(link) => {
+                        let link = &mut self.links[link];
+                        let arrival = self.simulation_time + (link.length / vehicle.max_velocity) as u32;
+                        link.vehicles.push((arrival, vehicle));
+                    },
+                    // Only trips leaving by their destination leg count as completed
+                    None => match vehicle.trip_start {
+                        Some(start) if vehicle.destination == Some((index, exit_leg)) => {
+                            self.trips.completed += 1;
+                            self.trips.total_travel_time += self.simulation_time - start;
+                        },
+                        Some(_) => self.trips.strayed += 1,
+                        None => {},
+                    },
+                }
+            }
+        }
+
+        // Let vehicles at the end of a link into the next intersection, in order, while there's room
+        for index in 0..self.links.len() {
+            while let Some((arrival, vehicle)) = self.links[index].vehicles.first() {
+                if *arrival > self.simulation_time {
+                    break;
+                }
+                
+                let link = &self.links[index];
+                let mut next = Vehicle::new(link.side, vehicle.class, &mut self.rng);
+                next.trip_start = vehicle.trip_start;
+                next.driver = vehicle.driver;
+                next.reaction_ticks = vehicle.reaction_ticks;
+                next.destination = vehicle.destination;
+                
+                // Choose the way on from here, in case the vehicle has strayed from its route
+                let turn = vehicle.destination
+                    .and_then(|destination| self.plan_route((link.to, link.side), destination))
+                    .map(|route| route[0]);
+                if let Some(direction) = turn {
+                    next = next.with_direction(direction);
+                }
+                
+                if !self.intersections[link.to].admit_vehicle(next) {
+                    break;
+                }
+                self.links[index].vehicles.remove(0);
+            }
+        }
+
+        self.record_time_space();
+        self.simulation_time += 1;
+    }
+
+    /// Finds the link leaving the given leg of an intersection, if there is one
+    fn link_from(&self, intersection: usize, leg: Side) -> Option<usize> {
+        self.links.iter().position(|link| link.from == intersection && link.leg == leg)
+    }
+
+    /// Legs of the intersections that lead into and out of the network
+    pub fn boundary_legs(&self) -> Vec<(usize, Side)> {
+        (0..self.intersections.len())
+            .flat_map(|index| {
+                [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+                    .into_iter()
+                    .map(move |side| (index, side))
+            })
+            .filter(|&(index, side)| self.intersections[index].layout.has_leg(side) && self.link_from(index, side).is_none())
+            .collect()
+    }
+
+    /// Starts a trip between a random origin and destination, routed along the quickest path
+    ///
+    /// Returns `false` if the network has fewer than two boundary legs to choose from.
+    pub fn spawn_trip(&mut self) -> bool {
+        let legs = self.boundary_legs();
+        if legs.len() < 2 {
+            return false;
+        }
+        let origin = legs[self.rng.gen_range(0..legs.len())];
+        let destinations: Vec<(usize, Side)> = legs.into_iter().filter(|&leg| leg != origin).collect();
+        let destination = destinations[self.rng.gen_range(0..destinations.len())];
+
+        let Some(route) = self.plan_route(origin, destination) else {
+            return false;
+        };
+
+        let (index, side) = origin;
+        let class = self.intersections[index].fleet_mix.sample(&mut self.rng);
+        let mut vehicle = Vehicle::new(side, class, &mut self.rng).with_direction(route[0]);
+        vehicle.destination = Some(destination);
+        vehicle.trip_start = Some(self.simulation_time);
+
+        let admitted = self.intersections[index].admit_vehicle(vehicle);
+        if admitted {
+            self.trips.started += 1;
+        }
+        admitted
+    }
+
+    /// Finds the turns to take from an origin leg to a destination leg
+    ///
+    /// Vehicles on a trip choose their turn again at every intersection they reach,
+    /// so they follow the quickest route as conditions change. Routes are chosen by
+    /// expected travel time, counting the time to travel each link and
+    /// `ROUTE_QUEUE_PENALTY_TICKS` for every vehicle already on the link or queued on
+    /// the approach it leads to.
+    pub fn plan_route(&self, origin: (usize, Side), destination: (usize, Side)) -> Option<Vec<Direction>> {
+        // Shortest path over the approaches of the network, which are few enough to scan
+        let approaches: Vec<(usize, Side)> = (0..self.intersections.len())
+            .flat_map(|index| {
+                [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+                    .into_iter()
+                    .map(move |side| (index, side))
+            })
+            .collect();
+        let mut cost = vec![f32::INFINITY; approaches.len()];
+        let mut previous: Vec<Option<(usize, Direction)>> = vec![None; approaches.len()];
+        let mut done = vec![false; approaches.len()];
+        let mut best: Option<(f32, usize, Direction)> = None;
+
+        let start = approaches.iter().position(|&approach| approach == origin)?;
+        cost[start] = 0.0;
+
+        while let Some(current) = (0..approaches.len())
+            .filter(|&i| !done[i] && cost[i].is_finite())
+            .min_by(|&a, &b| cost[a].total_cmp(&cost[b]))
+        {
+            done[current] = true;
+            let (index, side) = approaches[current];
+
+            for direction in self.intersections[index].layout.directions(side) {
+                let leg = side.exit_leg(direction);
+                if (index, leg) == destination {
+                    if best.is_none_or(|(best_cost, _, _)| cost[current] < best_cost) {
+                        best = Some((cost[current], current, direction));
+                    }
+                    continue;
+                }
+
+                let Some(link) = self.link_from(index, leg) else {
+                    continue;
+                };
+                let link = &self.links[link];
+                let queued = link.vehicles.len() + self.intersections[link.to].approach_count(link.side);
+                let next = approaches.iter().position(|&approach| approach == (link.to, link.side))?;
+                let next_cost = cost[current] + link.travel_time() + queued as f32 * ROUTE_QUEUE_PENALTY_TICKS;
+                if next_cost < cost[next] {
+                    cost[next] = next_cost;
+                    previous[next] = Some((current, direction));
+                }
+            }
+        }
+
+        // Walk back from the destination to the origin
+        let (_, mut current, direction) = best?;
+        let mut route = vec![direction];
+        while let Some((before, direction)) = previous[current] {
+            route.push(direction);
+            current = before;
+        }
+        route.reverse();
+        Some(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets in trips and moves the network on by the given number of frames
+    fn run(network: &mut Network, frames: usize) {
+        for _ in 0..frames {
+            network.spawn_trip();
+            network.update();
+        }
+    }
+
+    #[test]
+    fn a_restored_snapshot_carries_on_like_the_original() {
+        let mut network = Network::configured();
+        network.intersections[0].set_control(Control::Reservation);
+        run(&mut network, 300);
+        assert!(network.intersections.iter().any(|intersection| intersection.vehicles().next().is_some()));
+
+        let path = std::env::temp_dir().join(format!("road_intersection_snapshot_{}.json", std::process::id()));
+        let path = path.to_string_lossy();
+        network.save(&path).unwrap();
+        let mut restored = Network::load(&path).unwrap();
+        std::fs::remove_file(&*path).unwrap();
+        assert_eq!(restored, network);
+
+        run(&mut network, 300);
+        run(&mut restored, 300);
+        assert_eq!(restored, network);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::config::*;
+use crate::types::*;
+use super::vehicle::Vehicle;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Distance from the middle of the intersection to the kerb
+const KERB: f32 = INTERSECTION_HALF_WIDTH as f32;
+
+/// Distance past the end of the crosswalk at which pedestrians wait on the footway
+const WAITING_SPOT: f32 = 6.0;
+
+/// Radius of the space a pedestrian takes up
+pub const PEDESTRIAN_RADIUS: f32 = 2.0;
+
+/// Room a vehicle leaves on either side of its path for pedestrians about to step into it
+const YIELD_MARGIN: f32 = 4.0;
+
+/// Length of the flashing DON'T WALK interval, long enough to cross from kerb to kerb
+pub fn clearance_ticks() -> u32 {
+    (2.0 * KERB / PEDESTRIAN_SPEED).ceil() as u32
+}
+
+/// Approach whose green the crosswalk on the given leg walks with, if any
+///
+/// The crosswalk runs alongside the approaches on the neighbouring legs, so only
+/// their turning vehicles cross it. The approach whose right turns lead onto the
+/// leg is preferred.
+pub fn serving_approach(layout: Layout, leg: Side) -> Option<Side> {
+    let neighbours = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+        .into_iter()
+        .filter(|&side| side != leg && side != leg.opposite() && layout.has_leg(side));
+    let mut neighbours: Vec<Side> = neighbours.collect();
+    neighbours.sort_by_key(|&side| side.exit_leg(Direction::Right) != leg);
+    neighbours.first().copied()
+}
+
+/// Screen point on the middle line of the crosswalk on the given leg
+///
+/// Offsets are measured across the road from the middle of the intersection,
+/// positive to the right of vehicles entering from the leg.
+fn crosswalk_point(leg: Side, offset: f32) -> (f32, f32) {
+    let heading = leg.heading();
+    let (forward_x, forward_y) = (heading.cos(), heading.sin());
+    let along = -(KERB - CROSSWALK_WIDTH as f32 / 2.0);
+    (
+        (WINDOW_WIDTH / 2) as f32 + along * forward_x - offset * forward_y,
+        (WINDOW_HEIGHT / 2) as f32 + along * forward_y + offset * forward_x,
+    )
+}
+
+/// Screen rectangle of the crosswalk on the given leg, as left, top, width and height
+pub fn crosswalk_rect(leg: Side) -> (f32, f32, f32, f32) {
+    let half_width = CROSSWALK_WIDTH as f32 / 2.0;
+    let (start_x, start_y) = crosswalk_point(leg, -KERB);
+    let (end_x, end_y) = crosswalk_point(leg, KERB);
+    let (left, right) = (start_x.min(end_x) - half_width, start_x.max(end_x) + half_width);
+    let (top, bottom) = (start_y.min(end_y) - half_width, start_y.max(end_y) + half_width);
+    match leg {
+        Side::FromEast | Side::FromWest => (left, top + half_width, right - left, bottom - top - 2.0 * half_width),
+        Side::FromNorth | Side::FromSouth => (left + half_width, top, right - left - 2.0 * half_width, bottom - top),
+    }
+}
+
+/// Position of a point relative to a road user at `center` facing `heading`, as the
+/// distances ahead of its centre and to one side
+pub fn relative_to((center_x, center_y): (f32, f32), heading: f32, (x, y): (f32, f32)) -> (f32, f32) {
+    let (forward_x, forward_y) = (heading.cos(), heading.sin());
+    let (dx, dy) = (x - center_x, y - center_y);
+    (dx * forward_x + dy * forward_y, (dy * forward_x - dx * forward_y).abs())
+}
+
+/// Whether a pedestrian at the given point would be touching the vehicle
+fn touches(vehicle: &Vehicle, point: (f32, f32)) -> bool {
+    let (along, across) = relative_to(vehicle.center(), vehicle.heading, point);
+    along.abs() <= vehicle.length() as f32 / 2.0 + PEDESTRIAN_RADIUS &&
+    across <= vehicle.breadth() as f32 / 2.0 + PEDESTRIAN_RADIUS
+}
+
+/// A person walking across one leg of the intersection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pedestrian {
+    /// Leg whose crosswalk the pedestrian uses
+    pub leg: Side,
+    /// Position across the road, as an offset along the crosswalk
+    pub offset: f32,
+    /// Whether the pedestrian walks towards positive offsets
+    pub forward: bool,
+    /// Whether the pedestrian has stepped off the kerb
+    pub crossing: bool,
+    /// Frames spent waiting at the kerb or held up on the crosswalk
+    pub delay: u32,
+}
+
+impl Pedestrian {
+    /// Creates a pedestrian waiting at one end of the crosswalk on the given leg
+    fn new(leg: Side, forward: bool) -> Self {
+        let start = KERB + WAITING_SPOT;
+        Self {
+            leg,
+            offset: if forward { -start } else { start },
+            forward,
+            crossing: false,
+            delay: 0,
+        }
+    }
+
+    /// Screen position of the pedestrian
+    ///
+    /// The crosswalk runs along the edge of the intersection, so off its ends the
+    /// pedestrian steps back diagonally onto the corner of the footway, clear of
+    /// the neighbouring roads.
+    pub fn position(&self) -> (f32, f32) {
+        let (x, y) = crosswalk_point(self.leg, self.offset);
+        let back = 2.0 * (self.offset.abs() - KERB).max(0.0);
+        let heading = self.leg.heading();
+        (x - back * heading.cos(), y - back * heading.sin())
+    }
+
+    /// Whether the pedestrian is on the road rather than the footway
+    pub fn is_on_road(&self) -> bool {
+        self.offset.abs() <= KERB
+    }
+
+    /// Offset the pedestrian will be at after another step
+    fn next_offset(&self) -> f32 {
+        if self.forward { self.offset + PEDESTRIAN_SPEED } else { self.offset - PEDESTRIAN_SPEED }
+    }
+
+    /// Whether the pedestrian has reached the footway on the far side
+    fn has_crossed(&self) -> bool {
+        self.crossing && self.offset.abs() >= KERB + WAITING_SPOT && (self.offset > 0.0) == self.forward
+    }
+}
+
+/// The crosswalk on one leg, with its push button and pedestrian signal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Crosswalk {
+    /// Leg the crosswalk runs across
+    pub leg: Side,
+    /// What the pedestrian signal shows
+    pub signal: PedestrianSignal,
+    /// Whether a waiting pedestrian has pushed the button
+    pub call: bool,
+    /// Frame the next walk has been booked for with the intersection manager
+    pub booked: Option<u32>,
+    /// Frames since the signal last changed
+    timer: u32,
+    /// Length of the current walk interval
+    walk_ticks: u32,
+    /// Whether the approach the crosswalk walks with had a green last frame
+    was_green: bool,
+}
+
+impl Crosswalk {
+    /// Creates a crosswalk showing DON'T WALK
+    fn new(leg: Side) -> Self {
+        Self {
+            leg,
+            signal: PedestrianSignal::DontWalk,
+            call: false,
+            booked: None,
+            timer: 0,
+            walk_ticks: WALK_TICKS,
+            was_green: false,
+        }
+    }
+
+    /// Shows WALK for the given number of frames, then flashing DON'T WALK
+    pub fn start_walk(&mut self, walk_ticks: u32) {
+        self.signal = PedestrianSignal::Walk;
+        self.timer = 0;
+        self.walk_ticks = walk_ticks;
+        self.call = false;
+    }
+
+    /// Whether the walk, or a part of the flashing DON'T WALK longer than the
+    /// yellow and all-red intervals, is still to run
+    pub fn needs_green(&self) -> bool {
+        match self.signal {
+            PedestrianSignal::Walk => true,
+            PedestrianSignal::FlashingDontWalk => self.timer + YELLOW_TICKS + ALL_RED_TICKS < clearance_ticks(),
+            PedestrianSignal::DontWalk => false,
+        }
+    }
+
+    /// Advances the signal of a crosswalk at traffic lights by one frame
+    ///
+    /// A call is answered at the start of the green of the approach the crosswalk
+    /// walks with, if enough of the green is left for the flashing DON'T WALK to
+    /// end by the end of the all-red interval that follows. The walk is cut short
+    /// if the green ends early.
+    pub fn update_signal(&mut self, green: bool, green_left: u32) {
+        let onset = green && !self.was_green;
+        self.was_green = green;
+        self.advance(green);
+
+        if self.signal == PedestrianSignal::DontWalk && onset && self.call {
+            let change_ticks = green_left.saturating_add(YELLOW_TICKS + ALL_RED_TICKS);
+            let walk_ticks = WALK_TICKS.min(change_ticks.saturating_sub(clearance_ticks()));
+            if walk_ticks > 0 {
+                self.start_walk(walk_ticks);
+            }
+        }
+    }
+
+    /// Runs the walk and clearance intervals on, ending the walk if it may no longer go on
+    pub fn advance(&mut self, may_walk: bool) {
+        self.timer += 1;
+        match self.signal {
+            PedestrianSignal::Walk if self.timer >= self.walk_ticks || !may_walk => {
+                self.signal = PedestrianSignal::FlashingDontWalk;
+                self.timer = 0;
+            },
+            PedestrianSignal::FlashingDontWalk if self.timer >= clearance_ticks() => {
+                self.signal = PedestrianSignal::DontWalk;
+                self.timer = 0;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Pedestrians arriving at the crosswalks and walking across
+///
+/// Pedestrians arrive at random at either end of each crosswalk, push the button
+/// and step off the kerb once the signal shows WALK. On their way across they
+/// stop rather than walk into a vehicle, and vehicles give way to them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PedestrianTraffic {
+    /// Whether pedestrians arrive
+    pub enabled: bool,
+    /// Pedestrians per hour arriving at each crosswalk
+    pub arrivals_per_hour: f32,
+    /// One crosswalk per leg
+    pub crosswalks: Vec<Crosswalk>,
+    /// Pedestrians waiting or crossing
+    pub pedestrians: Vec<Pedestrian>,
+    /// Number of pedestrians who have got across
+    pub crossed: usize,
+    /// Sum of the delay of pedestrians who have got across, in frames
+    pub total_delay: u32,
+    /// Frames vehicles have spent giving way to pedestrians
+    pub vehicle_yield_ticks: u32,
+    /// Frames pedestrians on the road have spent held up by vehicles
+    pub blocked_ticks: u32,
+}
+
+impl PedestrianTraffic {
+    /// Create the pedestrian traffic set up in the configuration, with a crosswalk on every leg
+    pub fn configured() -> Self {
+        Self {
+            enabled: PEDESTRIANS_ENABLED,
+            arrivals_per_hour: PEDESTRIAN_ARRIVALS_PER_HOUR,
+            crosswalks: [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+                .into_iter()
+                .map(Crosswalk::new)
+                .collect(),
+            pedestrians: vec![],
+            crossed: 0,
+            total_delay: 0,
+            vehicle_yield_ticks: 0,
+            blocked_ticks: 0,
+        }
+    }
+
+    /// Removes the crosswalk on a leg there is no road on
+    pub fn close_leg(&mut self, side: Side) {
+        self.crosswalks.retain(|crosswalk| crosswalk.leg != side);
+        self.pedestrians.retain(|pedestrian| pedestrian.leg != side);
+    }
+
+    /// Whether a crosswalk walking with the given approach still needs its green
+    pub fn holds_green(&self, layout: Layout, side: Side) -> bool {
+        self.crosswalks.iter()
+            .any(|crosswalk| crosswalk.needs_green() && serving_approach(layout, crosswalk.leg) == Some(side))
+    }
+
+    /// Number of pedestrians waiting to cross with the given approach's green
+    pub fn calls_for(&self, layout: Layout, side: Side) -> usize {
+        self.pedestrians.iter()
+            .filter(|pedestrian| !pedestrian.crossing && serving_approach(layout, pedestrian.leg) == Some(side))
+            .count()
+    }
+
+    /// Whether anyone is waiting at the kerb of the crosswalk on the given leg
+    pub fn is_waiting(&self, leg: Side) -> bool {
+        self.pedestrians.iter().any(|pedestrian| pedestrian.leg == leg && !pedestrian.crossing)
+    }
+
+    /// Draws this frame's arrivals, with one frame lasting 1 / `FPS` of a second
+    pub fn tick<R: Rng>(&mut self, rng: &mut R) {
+        if !self.enabled {
+            return;
+        }
+
+        let probability = (self.arrivals_per_hour as f64 / (FPS * 3600) as f64).min(1.0);
+        for crosswalk in &mut self.crosswalks {
+            if rng.gen_bool(probability) {
+                // There is no need to push the button while the signal shows WALK
+                self.pedestrians.push(Pedestrian::new(crosswalk.leg, rng.gen_bool(0.5)));
+                crosswalk.call |= crosswalk.signal != PedestrianSignal::Walk;
+            }
+        }
+    }
+
+    /// Moves the pedestrians on, with waiting ones stepping off while their crosswalk shows WALK
+    ///
+    /// Pedestrians on the road stop rather than walk into any of the given vehicles.
+    pub fn update(&mut self, vehicles: &[Vehicle]) {
+        for pedestrian in &mut self.pedestrians {
+            let walk = self.crosswalks.iter()
+                .any(|crosswalk| crosswalk.leg == pedestrian.leg && crosswalk.signal == PedestrianSignal::Walk);
+            if !pedestrian.crossing && walk {
+                pedestrian.crossing = true;
+            }
+            if !pedestrian.crossing {
+                pedestrian.delay += 1;
+                continue;
+            }
+
+            // Pedestrians make their way round vehicles standing on the crosswalk, and someone
+            // a turning vehicle has swung round onto keeps walking to get clear of it
+            let next = Pedestrian { offset: pedestrian.next_offset(), ..pedestrian.clone() };
+            let blocked = next.is_on_road() && vehicles.iter().any(|vehicle| {
+                let escaping = pedestrian.is_on_road() && touches(vehicle, pedestrian.position());
+                vehicle.velocity > 0.0 && touches(vehicle, next.position()) && !escaping
+            });
+            if blocked {
+                pedestrian.delay += 1;
+                self.blocked_ticks += 1;
+            } else {
+                pedestrian.offset = next.offset;
+            }
+        }
+
+        let (crossed, remaining): (Vec<Pedestrian>, Vec<Pedestrian>) = self.pedestrians.drain(..)
+            .partition(Pedestrian::has_crossed);
+        self.pedestrians = remaining;
+        self.crossed += crossed.len();
+        self.total_delay += crossed.iter().map(|pedestrian| pedestrian.delay).sum::<u32>();
+    }
+
+    /// Whether a pedestrian on the road is in the way of the vehicle's next move
+    ///
+    /// Someone making their way round a vehicle standing on the crosswalk holds it
+    /// up until they are clear of it.
+    pub fn in_path(&self, vehicle: &Vehicle) -> bool {
+        let half_length = vehicle.length() as f32 / 2.0;
+        let reach = half_length + vehicle.next_velocity() + SAFETY_GAP as f32;
+        self.in_way(vehicle.center(), vehicle.heading, half_length, vehicle.breadth() as f32 / 2.0, reach)
+    }
+
+    /// Whether a pedestrian on the road is on or ahead of a road user of the given size,
+    /// no further ahead of its centre than `reach`
+    pub fn in_way(&self, center: (f32, f32), heading: f32, half_length: f32, half_breadth: f32, reach: f32) -> bool {
+        let half_breadth = half_breadth + PEDESTRIAN_RADIUS + YIELD_MARGIN;
+        self.pedestrians.iter()
+            .filter(|pedestrian| pedestrian.crossing && pedestrian.is_on_road())
+            .any(|pedestrian| {
+                let (along, across) = relative_to(center, heading, pedestrian.position());
+                along >= -half_length - PEDESTRIAN_RADIUS && along <= reach && across <= half_breadth
+            })
+    }
+
+    /// Average delay of pedestrians who have got across, in frames
+    pub fn average_delay(&self) -> Option<f32> {
+        if self.crossed == 0 {
+            return None;
+        }
+        Some(self.total_delay as f32 / self.crossed as f32)
+    }
+}
@@ -0,0 +1,435 @@
+use crate::config::*;
+use crate::types::*;
+use super::network::Network;
+use super::traffic::Intersection;
+use super::vehicle::Vehicle;
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Approaches in the order their lights are recorded
+const SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+
+/// One line of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line {
+    /// Layout of an intersection, at the start of the file
+    Intersection { index: usize, layout: Layout },
+    /// Start of a frame
+    Frame { time: u32 },
+    /// A vehicle let in during the frame
+    Spawn { intersection: usize, id: u32, side: Side, class: VehicleClass, direction: Direction },
+    /// Control and lights of an intersection, whenever they change
+    Signals { intersection: usize, control: Control, east: LightState, west: LightState, north: LightState, south: LightState },
+    /// State of a vehicle in the frame
+    Vehicle { intersection: usize, vehicle: VehicleState },
+}
+
+/// Writes a line of a recording
+fn write_line(writer: &mut BufWriter<File>, line: &Line) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writeln!(writer)
+}
+
+/// A vehicle as recorded in one frame, with what it takes to draw it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VehicleState {
+    pub id: u32,
+    pub side: Side,
+    pub class: VehicleClass,
+    pub direction: Direction,
+    pub lane: usize,
+    pub x: f32,
+    pub y: f32,
+    pub width: i32,
+    pub height: i32,
+    pub heading: f32,
+    pub velocity: f32,
+    pub routed: bool,
+    pub route_travelled: f32,
+    pub roundabout: bool,
+}
+
+impl VehicleState {
+    /// Takes down the state of a vehicle
+    fn of(vehicle: &Vehicle) -> Self {
+        Self {
+            id: vehicle.id,
+            side: vehicle.side,
+            class: vehicle.class,
+            direction: vehicle.direction,
+            lane: vehicle.lane,
+            x: vehicle.x,
+            y: vehicle.y,
+            width: vehicle.width,
+            height: vehicle.height,
+            heading: vehicle.heading,
+            velocity: vehicle.velocity,
+            routed: vehicle.routed,
+            route_travelled: vehicle.route_travelled,
+            roundabout: vehicle.roundabout,
+        }
+    }
+
+    /// A vehicle in this state, for drawing
+    fn vehicle(&self) -> Vehicle {
+        let mut vehicle = Vehicle::new(self.side, self.class, &mut rand::thread_rng()).with_direction(self.direction);
+        vehicle.id = self.id;
+        vehicle.lane = self.lane;
+        vehicle.x = self.x;
+        vehicle.y = self.y;
+        vehicle.width = self.width;
+        vehicle.height = self.height;
+        vehicle.heading = self.heading;
+        vehicle.velocity = self.velocity;
+        vehicle.routed = self.routed;
+        vehicle.route_travelled = self.route_travelled;
+        vehicle.roundabout = self.roundabout;
+        vehicle
+    }
+}
+
+/// State of an intersection in one frame of a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntersectionState {
+    /// How right of way is given
+    pub control: Control,
+    /// Light on each approach, in the order east, west, north, south
+    pub lights: [LightState; 4],
+    /// Numbers of the vehicles let in during the frame
+    pub spawns: Vec<u32>,
+    /// Every vehicle in the intersection's area
+    pub vehicles: Vec<VehicleState>,
+}
+
+/// One frame of a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Simulation time of the network
+    pub time: u32,
+    /// State of each intersection
+    pub intersections: Vec<IntersectionState>,
+}
+
+/// Writes a run to a JSON-lines file as it goes
+///
+/// The file starts with the layout of every intersection. Each frame then gives
+/// the simulation time, the vehicles let in, the control and lights of the
+/// intersections whose signals changed, and the state of every vehicle.
+#[derive(Debug)]
+pub struct Recorder {
+    /// File the run is written to
+    pub path: String,
+    writer: BufWriter<File>,
+    /// Highest vehicle number recorded at each intersection
+    last_ids: Vec<u32>,
+    /// Control and lights last recorded at each intersection
+    signals: Vec<Option<(Control, [LightState; 4])>>,
+}
+
+impl Recorder {
+    /// Creates the file and writes the layouts of the network's intersections
+    pub fn create(path: &str, network: &Network) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (index, intersection) in network.intersections.iter().enumerate() {
+            write_line(&mut writer, &Line::Intersection { index, layout: intersection.layout })?;
+        }
+        Ok(Self {
+            path: path.to_string(),
+            writer,
+            last_ids: vec![0; network.intersections.len()],
+            signals: vec![None; network.intersections.len()],
+        })
+    }
+
+    /// Writes the current frame of the network
+    pub fn record(&mut self, network: &Network) -> std::io::Result<()> {
+        write_line(&mut self.writer, &Line::Frame { time: network.simulation_time })?;
+        for (index, intersection) in network.intersections.iter().enumerate() {
+            let vehicles: Vec<&Vehicle> = intersection.vehicles().collect();
+
+            // Vehicles are numbered in turn, so the ones not seen before have just been let in
+            for vehicle in vehicles.iter().filter(|vehicle| vehicle.id > self.last_ids[index]) {
+                write_line(&mut self.writer, &Line::Spawn {
+                    intersection: index,
+                    id: vehicle.id,
+                    side: vehicle.side,
+                    class: vehicle.class,
+                    direction: vehicle.direction,
+                })?;
+            }
+            self.last_ids[index] = vehicles.iter().map(|vehicle| vehicle.id).fold(self.last_ids[index], u32::max);
+
+            let signals = (intersection.control, SIDES.map(|side| intersection.light(side).state));
+            if self.signals[index] != Some(signals) {
+                let (control, [east, west, north, south]) = signals;
+                write_line(&mut self.writer, &Line::Signals { intersection: index, control, east, west, north, south })?;
+                self.signals[index] = Some(signals);
+            }
+
+            for vehicle in vehicles {
+                write_line(&mut self.writer, &Line::Vehicle { intersection: index, vehicle: VehicleState::of(vehicle) })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out what is left in the buffer
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A recorded run, read back for replay
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// Layout of each intersection
+    pub layouts: Vec<Layout>,
+    /// Every frame of the run, in order
+    pub frames: Vec<Frame>,
+}
+
+impl Recording {
+    /// Reads a recording written by a `Recorder`
+    ///
+    /// A last line that cannot be read, as at the end of a run cut short, is
+    /// left out. Any other line that cannot be read is an error giving its number.
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let mut layouts = vec![];
+        let mut frames: Vec<Frame> = vec![];
+        let mut lines = BufReader::new(File::open(path)?).lines().enumerate().peekable();
+        while let Some((number, line)) = lines.next() {
+            let line = match serde_json::from_str::<Line>(&line?) {
+                Ok(line) => line,
+                Err(_) if lines.peek().is_none() => break,
+                Err(error) => {
+                    let message = format!("line {}: {}", number + 1, error);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+                },
+            };
+            let index = match line {
+                Line::Intersection { layout, .. } => {
+                    layouts.push(layout);
+                    continue;
+                },
+                Line::Frame { time } => {
+                    // Signals carry over from the frame before until they change
+                    let intersections = frames.last().map_or_else(
+                        || vec![IntersectionState {
+                            control: Control::Signals,
+                            lights: [LightState::Red; 4],
+                            spawns: vec![],
+                            vehicles: vec![],
+                        }; layouts.len()],
+                        |last| last.intersections.iter()
+                            .map(|state| IntersectionState { spawns: vec![], vehicles: vec![], ..state.clone() })
+                            .collect(),
+                    );
+                    frames.push(Frame { time, intersections });
+                    continue;
+                },
+                Line::Spawn { intersection, .. } | Line::Signals { intersection, .. } | Line::Vehicle { intersection, .. } => intersection,
+            };
+            let Some(state) = frames.last_mut().and_then(|frame| frame.intersections.get_mut(index)) else {
+                continue;
+            };
+            match line {
+                Line::Spawn { id, .. } => state.spawns.push(id),
+                Line::Vehicle { vehicle, .. } => state.vehicles.push(vehicle),
+                Line::Signals { control, east, west, north, south, .. } => {
+                    state.control = control;
+                    state.lights = [east, west, north, south];
+                },
+                Line::Intersection { .. } | Line::Frame { .. } => {},
+            }
+        }
+        Ok(Self { layouts, frames })
+    }
+}
+
+/// Playing back a recording, with pause, seek and speed control
+#[derive(Debug, Clone, PartialEq)]
+pub struct Playback {
+    pub recording: Recording,
+    /// Frame of the recording on screen
+    pub position: usize,
+    /// Whether playback is paused
+    pub paused: bool,
+    /// Recorded frames played per frame on screen
+    pub speed: f32,
+    /// Part of a frame played but not shown yet
+    progress: f32,
+    /// Each intersection, set up once and brought to the frame on screen for drawing
+    scenes: Vec<Intersection>,
+}
+
+impl Playback {
+    /// Starts playing a recording from its first frame
+    pub fn new(recording: Recording) -> Self {
+        let scenes = (0..recording.layouts.len().max(1))
+            .map(|index| {
+                let layout = recording.layouts.get(index).copied().unwrap_or(Layout::Cross);
+                let mut intersection = Intersection::with_layout(layout);
+                intersection.pedestrians.enabled = false;
+                intersection.cyclists.enabled = false;
+                intersection
+            })
+            .collect();
+        Self { recording, position: 0, paused: false, speed: 1.0, progress: 0.0, scenes }
+    }
+
+    /// Moves on by one frame on screen, unless paused or at the end
+    pub fn advance(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.progress += self.speed;
+        let frames = self.progress.floor();
+        self.progress -= frames;
+        self.seek(frames as i64);
+    }
+
+    /// Jumps the given number of recorded frames forwards, or backwards if negative
+    pub fn seek(&mut self, frames: i64) {
+        let last = self.recording.frames.len().saturating_sub(1) as i64;
+        self.position = (self.position as i64 + frames).clamp(0, last) as usize;
+    }
+
+    /// Doubles the playback speed, up to `REPLAY_MAX_SPEED`
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(REPLAY_MAX_SPEED);
+    }
+
+    /// Halves the playback speed, down to 1 / `REPLAY_MAX_SPEED`
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(1.0 / REPLAY_MAX_SPEED);
+    }
+
+    /// Share of the recording played so far
+    pub fn fraction(&self) -> f32 {
+        self.position as f32 / self.recording.frames.len().saturating_sub(1).max(1) as f32
+    }
+
+    /// The given intersection as it was in the frame on screen, with just what it takes to draw it
+    pub fn intersection(&mut self, index: usize) -> &Intersection {
+        let scene = &mut self.scenes[index];
+        let Some(frame) = self.recording.frames.get(self.position) else {
+            return scene;
+        };
+        scene.simulation_time = frame.time;
+        scene.vehicles_in_intersection.clear();
+        if let Some(state) = frame.intersections.get(index) {
+            scene.control = state.control;
+            let [east, west, north, south] = state.lights;
+            scene.east_light = TrafficLight { state: east };
+            scene.west_light = TrafficLight { state: west };
+            scene.north_light = TrafficLight { state: north };
+            scene.south_light = TrafficLight { state: south };
+            scene.vehicles_in_intersection.extend(state.vehicles.iter().map(VehicleState::vehicle));
+        }
+        scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path in the temporary directory for a test's recording
+    fn temporary(name: &str) -> String {
+        let file = format!("road_intersection_{}_{}.jsonl", name, std::process::id());
+        std::env::temp_dir().join(file).to_string_lossy().into_owned()
+    }
+
+    /// Records the given number of frames of a network with trips being let in, and returns the network
+    fn record(path: &str, frames: usize) -> Network {
+        let mut network = Network::configured();
+        let mut recorder = Recorder::create(path, &network).unwrap();
+        for _ in 0..frames {
+            network.spawn_trip();
+            network.update();
+            recorder.record(&network).unwrap();
+        }
+        recorder.finish().unwrap();
+        network
+    }
+
+    #[test]
+    fn a_recording_reads_back_as_it_was_written() {
+        let path = temporary("round_trip");
+        let network = record(&path, 120);
+        let recording = Recording::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let layouts: Vec<Layout> = network.intersections.iter().map(|intersection| intersection.layout).collect();
+        assert_eq!(recording.layouts, layouts);
+        assert_eq!(recording.frames.len(), 120);
+
+        let last = recording.frames.last().unwrap();
+        assert_eq!(last.time, network.simulation_time);
+        for (state, intersection) in last.intersections.iter().zip(&network.intersections) {
+            assert_eq!(state.control, intersection.control);
+            assert_eq!(state.lights, SIDES.map(|side| intersection.light(side).state));
+            let vehicles: Vec<VehicleState> = intersection.vehicles().map(VehicleState::of).collect();
+            assert_eq!(state.vehicles, vehicles);
+        }
+        let spawns: usize = recording.frames.iter().flat_map(|frame| &frame.intersections).map(|state| state.spawns.len()).sum();
+        assert!(spawns > 0);
+    }
+
+    #[test]
+    fn a_half_written_last_line_is_left_out() {
+        let path = temporary("cut_short");
+        record(&path, 10);
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"type\":\"vehicle\",\"intersec").unwrap();
+        let recording = Recording::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.unwrap().frames.len(), 10);
+    }
+
+    #[test]
+    fn a_line_that_cannot_be_read_is_reported_with_its_number() {
+        let path = temporary("corrupt");
+        std::fs::write(&path, "{\"type\":\"frame\",\"time\":0}\nnot a line\n{\"type\":\"frame\",\"time\":1}\n").unwrap();
+        let error = Recording::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+    }
+
+    #[test]
+    fn playback_stays_within_the_recording() {
+        let frames = (0..10)
+            .map(|time| Frame { time, intersections: vec![] })
+            .collect();
+        let mut playback = Playback::new(Recording { layouts: vec![Layout::Cross], frames });
+
+        playback.seek(-5);
+        assert_eq!(playback.position, 0);
+        playback.seek(100);
+        assert_eq!(playback.position, 9);
+        assert_eq!(playback.fraction(), 1.0);
+
+        playback.seek(-9);
+        playback.slower();
+        playback.advance();
+        assert_eq!(playback.position, 0);
+        playback.advance();
+        assert_eq!(playback.position, 1);
+
+        playback.paused = true;
+        playback.advance();
+        playback.advance();
+        assert_eq!(playback.position, 1);
+
+        for _ in 0..10 {
+            playback.faster();
+        }
+        assert_eq!(playback.speed, REPLAY_MAX_SPEED);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::config::*;
+use crate::types::*;
+use super::vehicle::Vehicle;
+use serde::{Deserialize, Serialize};
+
+/// Number of tiles along each side of the intersection
+const GRID: i32 = 2 * INTERSECTION_HALF_WIDTH / AIM_TILE_SIZE;
+
+/// Number of frames ahead tiles can be held for
+const HORIZON: u32 = 1024;
+
+/// A screen rectangle, as left, top, width and height, with the frame it is taken up at
+pub type Footprint = ((f32, f32, f32, f32), u32);
+
+/// Space-time tiles of the intersection granted to a vehicle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    /// Time at which the vehicle is to enter the intersection
+    pub arrival: u32,
+    /// Speed the vehicle keeps to on its way to the stop line
+    pub velocity: f32,
+    /// Lane the crossing was planned from
+    pub lane: usize,
+    /// Direction the crossing was planned for
+    pub direction: Direction,
+    /// Tiles the crossing covers, each with the frame it covers it at
+    tiles: Vec<(usize, u32)>,
+    /// Number the tiles are held under
+    owner: u32,
+}
+
+/// Intersection manager handing out space-time reservations, in the style of AIM
+///
+/// The intersection is divided into square tiles. A vehicle approaching the
+/// intersection asks for a reservation, and the manager plays out the crossing
+/// the vehicle would make at each of a range of approach speeds, from fastest to
+/// slowest. The first crossing that only needs tiles no other vehicle holds
+/// within `time_buffer` frames is granted. The vehicle then keeps to that speed,
+/// and if it does not enter on time it gives the reservation up and asks again.
+/// A vehicle that is no longer where its reservation has it once in the
+/// intersection, held up by a blocked exit or someone crossing its path, has
+/// the rest of its crossing booked again from where it is.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReservationManager {
+    /// Frames on either side of the planned time a tile must be free for
+    pub time_buffer: u32,
+    /// Room kept around each vehicle when working out the tiles it covers
+    pub space_buffer: f32,
+    /// Number of reservations granted
+    pub granted: usize,
+    /// Number of requests that could not be granted
+    pub rejected: usize,
+    /// Number of tiles a vehicle booking its crossing again found held by someone else
+    pub conflicts: usize,
+    /// For every tile and frame modulo `HORIZON`, one more than the frame it is held for, or 0,
+    /// and the owner holding it
+    ///
+    /// Left empty until the first tile is held, and saved as the entries in use only, as
+    /// nearly all of the grid is empty.
+    #[serde(with = "sparse")]
+    held: Vec<(u32, u32)>,
+    /// Owner the next tiles held are held under
+    next_owner: u32,
+}
+
+impl ReservationManager {
+    /// Create the manager set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            time_buffer: AIM_TIME_BUFFER,
+            space_buffer: AIM_SPACE_BUFFER,
+            granted: 0,
+            rejected: 0,
+            conflicts: 0,
+            held: vec![],
+            next_owner: 0,
+        }
+    }
+
+    /// Gives up every reservation
+    pub fn reset(&mut self) {
+        self.held = vec![];
+    }
+
+    /// Position of a tile and frame in `held`
+    fn slot(tile: usize, time: u32) -> usize {
+        tile * HORIZON as usize + (time % HORIZON) as usize
+    }
+
+    /// Owner holding the tile at the given frame, if any
+    fn holder(&self, tile: usize, time: u32) -> Option<u32> {
+        self.held.get(Self::slot(tile, time))
+            .filter(|&&(held_for, _)| held_for == time + 1)
+            .map(|&(_, owner)| owner)
+    }
+
+    /// Whether the tile is held by anyone at the given frame
+    fn is_held(&self, tile: usize, time: u32) -> bool {
+        self.holder(tile, time).is_some()
+    }
+
+    /// Number to hold a new set of tiles under
+    fn new_owner(&mut self) -> u32 {
+        self.next_owner = self.next_owner.wrapping_add(1);
+        self.next_owner
+    }
+
+    /// Holds the given tiles for their frames under the given owner
+    ///
+    /// Tiles someone else holds are left to them, and counted as conflicts.
+    fn hold(&mut self, tiles: &[(usize, u32)], owner: u32) {
+        if self.held.is_empty() {
+            self.held = vec![(0, 0); (GRID * GRID) as usize * HORIZON as usize];
+        }
+        for &(tile, time) in tiles {
+            if self.holder(tile, time).is_some_and(|holder| holder != owner) {
+                self.conflicts += 1;
+                continue;
+            }
+            self.held[Self::slot(tile, time)] = (time + 1, owner);
+        }
+    }
+
+    /// Whether the tile is free at the given frame and the frames around it
+    fn is_free(&self, tile: usize, time: u32) -> bool {
+        let start = time.saturating_sub(self.time_buffer);
+        (start..=time + self.time_buffer).all(|time| !self.is_held(tile, time))
+    }
+
+    /// Screen position of the top left corner of the tile grid
+    pub fn origin() -> (i32, i32) {
+        (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH, WINDOW_HEIGHT / 2 - INTERSECTION_HALF_WIDTH)
+    }
+
+    /// Tiles covered by a vehicle and the room kept around it
+    fn footprint(&self, vehicle: &Vehicle) -> Vec<usize> {
+        let (origin_x, origin_y) = Self::origin();
+        let tile = |position: f32, origin: i32| ((position - origin as f32) / AIM_TILE_SIZE as f32).floor() as i32;
+        let left = tile(vehicle.x - self.space_buffer, origin_x).max(0);
+        let right = tile(vehicle.x + vehicle.width as f32 + self.space_buffer, origin_x).min(GRID - 1);
+        let top = tile(vehicle.y - self.space_buffer, origin_y).max(0);
+        let bottom = tile(vehicle.y + vehicle.height as f32 + self.space_buffer, origin_y).min(GRID - 1);
+
+        let mut tiles = vec![];
+        for row in top..=bottom {
+            for column in left..=right {
+                tiles.push((row * GRID + column) as usize);
+            }
+        }
+        tiles
+    }
+
+    /// Plays out the crossing of a vehicle keeping to the given speed until it reaches the stop line
+    ///
+    /// Returns `None` if the crossing needs a tile someone else holds, or
+    /// would not be over within the horizon.
+    fn plan(&self, vehicle: &Vehicle, velocity: f32, time: u32) -> Option<Reservation> {
+        let green = TrafficLight::green();
+        let mut trial = vehicle.clone();
+        let mut reservation = Reservation {
+            arrival: time,
+            velocity,
+            lane: vehicle.lane,
+            direction: vehicle.direction,
+            tiles: vec![],
+            owner: 0,
+        };
+        trial.reservation = Some(reservation.clone());
+
+        // The vehicle drives up to the stop line, and enters the frame after crossing it
+        let mut now = time;
+        while trial.distance_to_stop_line() > 0.0 {
+            trial.update_position(&green);
+            now += 1;
+            if now - time >= HORIZON {
+                return None;
+            }
+        }
+        reservation.arrival = now;
+
+        // Past the line it no longer keeps to the approach speed, and speeds up from
+        // the speed it reached, or sets off if it stood at the line
+        reservation.tiles = self.crossing(trial, now, time, true)?;
+        Some(reservation)
+    }
+
+    /// Tiles a vehicle in the intersection covers from the given frame on, until it has crossed
+    ///
+    /// Returns `None` if `check` is set and a tile is held by anyone, or if the
+    /// crossing would not be over within the horizon counted from `start`.
+    fn crossing(&self, mut trial: Vehicle, mut now: u32, start: u32, check: bool) -> Option<Vec<(usize, u32)>> {
+        let green = TrafficLight::green();
+        let mut tiles = vec![];
+        loop {
+            for tile in self.footprint(&trial) {
+                if check && !self.is_free(tile, now) {
+                    return None;
+                }
+                tiles.push((tile, now));
+            }
+            if trial.has_cleared_intersection() {
+                return Some(tiles);
+            }
+            trial.update_position(&green);
+            now += 1;
+            if now - start >= HORIZON - self.time_buffer {
+                return None;
+            }
+        }
+    }
+
+    /// Looks for a crossing for the vehicle, trying approach speeds from its top speed down
+    ///
+    /// A vehicle standing at the stop line can only set off from a standstill.
+    pub fn request(&mut self, vehicle: &Vehicle, time: u32) -> Option<Reservation> {
+        let speeds: Vec<f32> = if vehicle.distance_to_stop_line() <= 0.0 {
+            vec![vehicle.max_velocity]
+        } else {
+            (0..AIM_SPEED_STEPS)
+                .map(|step| vehicle.max_velocity * (AIM_SPEED_STEPS - step) as f32 / AIM_SPEED_STEPS as f32)
+                .collect()
+        };
+
+        let mut reservation = speeds.into_iter().find_map(|velocity| self.plan(vehicle, velocity, time));
+        match &mut reservation {
+            Some(reservation) => {
+                reservation.owner = self.new_owner();
+                self.hold(&reservation.tiles, reservation.owner);
+                self.granted += 1;
+            },
+            None => self.rejected += 1,
+        }
+        reservation
+    }
+
+    /// Books the rest of the crossing again for a vehicle in the intersection that is off course
+    ///
+    /// Nothing changes while the vehicle covers the tiles its reservation has it
+    /// cover at the given frame. Otherwise the tiles it was granted are freed, and
+    /// the ones it covers from where it is on are held instead, whether or not
+    /// they are free, as the vehicle is in the intersection already. A crossing
+    /// that would not be over within the horizon holds just the tiles the vehicle
+    /// covers now.
+    pub fn keep_track(&mut self, vehicle: &mut Vehicle, time: u32) {
+        let Some(reservation) = &vehicle.reservation else {
+            return;
+        };
+        let footprint = self.footprint(vehicle);
+        let planned: Vec<usize> = reservation.tiles.iter()
+            .filter(|&&(_, planned_time)| planned_time == time)
+            .map(|&(tile, _)| tile)
+            .collect();
+        if planned == footprint {
+            return;
+        }
+
+        self.release(reservation);
+        let tiles = self.crossing(vehicle.clone(), time, time, false)
+            .unwrap_or_else(|| footprint.into_iter().map(|tile| (tile, time)).collect());
+        let owner = reservation.owner;
+        self.hold(&tiles, owner);
+        if let Some(reservation) = &mut vehicle.reservation {
+            reservation.tiles = tiles;
+        }
+    }
+
+    /// Frees the tiles of a reservation that will not be used
+    ///
+    /// Tiles someone else has come to hold since are left to them.
+    pub fn release(&mut self, reservation: &Reservation) {
+        for &(tile, time) in &reservation.tiles {
+            if self.holder(tile, time) == Some(reservation.owner) {
+                self.held[Self::slot(tile, time)] = (0, 0);
+            }
+        }
+    }
+
+    /// Tiles under a screen rectangle given as left, top, width and height
+    fn area_tiles((left, top, width, height): (f32, f32, f32, f32)) -> Vec<usize> {
+        let (origin_x, origin_y) = Self::origin();
+        let tile = |position: f32, origin: i32| ((position - origin as f32) / AIM_TILE_SIZE as f32).floor() as i32;
+        let (first_column, last_column) = (tile(left, origin_x).max(0), tile(left + width, origin_x).min(GRID - 1));
+        let (first_row, last_row) = (tile(top, origin_y).max(0), tile(top + height, origin_y).min(GRID - 1));
+        (first_row..=last_row)
+            .flat_map(|row| (first_column..=last_column).map(move |column| (row * GRID + column) as usize))
+            .collect()
+    }
+
+    /// Holds the tiles under a screen rectangle for a stretch of frames, as early as they are all free
+    ///
+    /// The rectangle is given as left, top, width and height. Returns the first
+    /// frame of the stretch, or `None` if it cannot start within the horizon.
+    pub fn reserve_area(&mut self, area: (f32, f32, f32, f32), ticks: u32, time: u32) -> Option<u32> {
+        let tiles = Self::area_tiles(area);
+
+        // Look for the first run of frames long enough in which all the tiles are free
+        let mut start = time;
+        for now in time..time + HORIZON - self.time_buffer {
+            if now - start >= ticks {
+                break;
+            }
+            if !tiles.iter().all(|&tile| self.is_free(tile, now)) {
+                start = now + 1;
+            }
+        }
+        if start + ticks > time + HORIZON - self.time_buffer {
+            return None;
+        }
+        let held: Vec<(usize, u32)> = tiles.iter()
+            .flat_map(|&tile| (start..start + ticks).map(move |time| (tile, time)))
+            .collect();
+        let owner = self.new_owner();
+        self.hold(&held, owner);
+        Some(start)
+    }
+
+    /// Holds the tiles under each of a series of footprints at its own frame, if they are all free
+    ///
+    /// Footprints wholly outside the intersection are left out.
+    pub fn reserve_footprints(&mut self, footprints: &[Footprint]) -> bool {
+        let (origin_x, origin_y) = Self::origin();
+        let (right, bottom) = (origin_x + 2 * INTERSECTION_HALF_WIDTH, origin_y + 2 * INTERSECTION_HALF_WIDTH);
+        let held: Vec<(usize, u32)> = footprints.iter()
+            .filter(|((left, top, width, height), _)| {
+                left + width >= origin_x as f32 && *left <= right as f32 && top + height >= origin_y as f32 && *top <= bottom as f32
+            })
+            .flat_map(|&(area, time)| Self::area_tiles(area).into_iter().map(move |tile| (tile, time)))
+            .collect();
+        if !held.iter().all(|&(tile, time)| self.is_free(tile, time)) {
+            return false;
+        }
+        let owner = self.new_owner();
+        self.hold(&held, owner);
+        true
+    }
+
+    /// Screen rectangles of the tiles held at the given frame, as left, top and size
+    pub fn held_tiles(&self, time: u32) -> Vec<(i32, i32, i32)> {
+        let (origin_x, origin_y) = Self::origin();
+        (0..(GRID * GRID) as usize)
+            .filter(|&tile| self.is_held(tile, time))
+            .map(|tile| {
+                let (row, column) = (tile as i32 / GRID, tile as i32 % GRID);
+                (origin_x + column * AIM_TILE_SIZE, origin_y + row * AIM_TILE_SIZE, AIM_TILE_SIZE)
+            })
+            .collect()
+    }
+}
+
+/// Saves a grid of held tiles as its entries in use, each with its position
+mod sparse {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(held: &[(u32, u32)], serializer: S) -> Result<S::Ok, S::Error> {
+        let entries: Vec<(usize, (u32, u32))> = held.iter()
+            .enumerate()
+            .filter(|&(_, &(held_for, _))| held_for != 0)
+            .map(|(slot, &entry)| (slot, entry))
+            .collect();
+        entries.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u32, u32)>, D::Error> {
+        let entries = Vec::<(usize, (u32, u32))>::deserialize(deserializer)?;
+        if entries.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut held = vec![(0, 0); (GRID * GRID) as usize * HORIZON as usize];
+        for (slot, value) in entries {
+            let entry = held.get_mut(slot).ok_or_else(|| serde::de::Error::custom("held tile out of the grid"))?;
+            *entry = value;
+        }
+        Ok(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::traffic::Intersection;
+
+    /// Car from the east heading straight across, still well short of the stop line
+    fn approaching() -> Vehicle {
+        Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng()).with_direction(Direction::Straight)
+    }
+
+    #[test]
+    fn no_room_is_taken_until_the_first_reservation() {
+        let mut manager = ReservationManager::configured();
+        assert!(manager.held.is_empty());
+
+        assert!(manager.request(&approaching(), 0).is_some());
+        assert!(!manager.held.is_empty());
+
+        manager.reset();
+        assert!(manager.held.is_empty());
+    }
+
+    #[test]
+    fn granted_tiles_are_not_handed_out_twice() {
+        let mut manager = ReservationManager::configured();
+        let first = manager.request(&approaching(), 0).expect("an empty intersection has room");
+        let second = manager.request(&approaching(), 0).expect("a slower crossing has room");
+        assert_ne!(first.owner, second.owner);
+        assert!(second.arrival > first.arrival);
+        for &(tile, time) in &first.tiles {
+            assert_eq!(manager.holder(tile, time), Some(first.owner));
+        }
+    }
+
+    #[test]
+    fn releasing_a_reservation_leaves_tiles_someone_else_holds() {
+        let mut manager = ReservationManager::configured();
+        let given_up = manager.request(&approaching(), 0).expect("an empty intersection has room");
+        manager.release(&given_up);
+        let taken = manager.request(&approaching(), 0).expect("the tiles were freed");
+        assert_eq!(taken.tiles, given_up.tiles);
+
+        manager.release(&given_up);
+        assert!(taken.tiles.iter().all(|&(tile, time)| manager.holder(tile, time) == Some(taken.owner)));
+    }
+
+    #[test]
+    fn a_vehicle_off_course_is_booked_again_around_other_reservations() {
+        let mut manager = ReservationManager::configured();
+        let mut vehicle = approaching();
+        vehicle.x = (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH - vehicle.width) as f32;
+        vehicle.reservation = manager.request(&vehicle, 0);
+        assert!(vehicle.reservation.is_some());
+        let other = manager.request(&approaching(), 0).expect("a later crossing has room");
+
+        // Still standing at the line as the next vehicle in the lane comes up to it
+        manager.keep_track(&mut vehicle, other.arrival);
+        let rebooked = vehicle.reservation.clone().expect("the reservation is kept");
+        assert!(rebooked.tiles.iter().any(|&(_, time)| time == other.arrival));
+        assert!(manager.conflicts > 0);
+        assert!(other.tiles.iter().all(|&(tile, time)| manager.holder(tile, time) == Some(other.owner)));
+
+        // Where it was booked to be, it is left alone
+        let conflicts = manager.conflicts;
+        manager.keep_track(&mut vehicle, other.arrival);
+        assert_eq!(vehicle.reservation, Some(rebooked));
+        assert_eq!(manager.conflicts, conflicts);
+    }
+
+    #[test]
+    fn an_unobstructed_crossing_keeps_its_reservation() {
+        let mut intersection = Intersection::new();
+        intersection.set_control(Control::Reservation);
+        intersection.bus_schedule.retain_routes(|_| false);
+        for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+            intersection.demand.close_entry(side);
+        }
+        assert!(intersection.spawn_vehicle(Side::FromEast, VehicleClass::Car));
+
+        let mut granted = None;
+        for _ in 0..1000 {
+            intersection.update();
+            if let Some(vehicle) = intersection.vehicles_in_intersection.first() {
+                let reservation = vehicle.reservation.clone().expect("the reservation is kept until across");
+                assert_eq!(granted.get_or_insert_with(|| reservation.clone()), &reservation);
+            }
+            if intersection.total_vehicles_processed > 0 {
+                break;
+            }
+        }
+        assert_eq!(intersection.total_vehicles_processed, 1);
+        assert_eq!(intersection.reservations.granted, 1);
+        assert_eq!(intersection.reservations.conflicts, 0);
+    }
+}
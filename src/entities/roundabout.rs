@@ -0,0 +1,445 @@
+use crate::config::*;
+use crate::types::*;
+use super::vehicle::Vehicle;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI, TAU};
+
+/// Angle round the centre between a leg and where its traffic joins or leaves the circulating lane
+const MERGE_ANGLE: f32 = FRAC_PI_4;
+
+/// Number of straight pieces a curve is measured with
+const CURVE_SAMPLES: usize = 16;
+
+/// Length of the circulating lane
+pub fn circumference() -> f32 {
+    ROUNDABOUT_RADIUS * TAU
+}
+
+/// Point on the circulating lane at the given angle round the centre
+fn ring_point(angle: f32) -> (f32, f32) {
+    (
+        (WINDOW_WIDTH / 2) as f32 + ROUNDABOUT_RADIUS * angle.cos(),
+        (WINDOW_HEIGHT / 2) as f32 + ROUNDABOUT_RADIUS * angle.sin(),
+    )
+}
+
+/// Heading of traffic going round at the given angle
+///
+/// Traffic keeps the island on its left, so the angle decreases as it goes round.
+fn ring_heading(angle: f32) -> f32 {
+    angle - FRAC_PI_2
+}
+
+/// Smooth curve from one point and heading to another, measured by its length
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Curve {
+    /// Points along the curve, from start to end
+    points: Vec<(f32, f32)>,
+    /// Distance along the curve to each point
+    distances: Vec<f32>,
+}
+
+impl Curve {
+    /// Cubic Hermite curve leaving the start and arriving at the end along the given headings
+    fn new(start: (f32, f32), start_heading: f32, end: (f32, f32), end_heading: f32) -> Self {
+        let chord = (end.0 - start.0).hypot(end.1 - start.1);
+        let (start_x, start_y) = (chord * start_heading.cos(), chord * start_heading.sin());
+        let (end_x, end_y) = (chord * end_heading.cos(), chord * end_heading.sin());
+
+        let points: Vec<(f32, f32)> = (0..=CURVE_SAMPLES)
+            .map(|i| {
+                let t = i as f32 / CURVE_SAMPLES as f32;
+                let (t2, t3) = (t * t, t * t * t);
+                let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+                let h10 = t3 - 2.0 * t2 + t;
+                let h01 = -2.0 * t3 + 3.0 * t2;
+                let h11 = t3 - t2;
+                (
+                    h00 * start.0 + h10 * start_x + h01 * end.0 + h11 * end_x,
+                    h00 * start.1 + h10 * start_y + h01 * end.1 + h11 * end_y,
+                )
+            })
+            .collect();
+
+        let mut distances = vec![0.0];
+        for pair in points.windows(2) {
+            let step = (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1);
+            distances.push(distances[distances.len() - 1] + step);
+        }
+        Self { points, distances }
+    }
+
+    fn length(&self) -> f32 {
+        self.distances[self.distances.len() - 1]
+    }
+
+    /// Point and heading at the given distance along the curve
+    fn pose(&self, distance: f32) -> ((f32, f32), f32) {
+        let piece = self.distances.windows(2)
+            .position(|pair| distance <= pair[1])
+            .unwrap_or(CURVE_SAMPLES - 1);
+        let (from, to) = (self.points[piece], self.points[piece + 1]);
+        let piece_length = self.distances[piece + 1] - self.distances[piece];
+        let fraction = if piece_length > 0.0 {
+            ((distance - self.distances[piece]) / piece_length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (
+            (from.0 + (to.0 - from.0) * fraction, from.1 + (to.1 - from.1) * fraction),
+            (to.1 - from.1).atan2(to.0 - from.0),
+        )
+    }
+}
+
+/// Way a vehicle takes round a roundabout, from its yield line to its exit lane
+///
+/// The vehicle curves onto the circulating lane at a point fixed for its
+/// approach, goes round, and curves off at a point fixed for its exit leg. All
+/// lanes of an approach merge into the one circulating lane.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundaboutPath {
+    entry: Curve,
+    /// Angle round the centre at which the vehicle joins the circulating lane
+    merge_angle: f32,
+    /// Angle the vehicle goes round before leaving the circulating lane
+    sweep: f32,
+    exit: Curve,
+    exit_heading: f32,
+}
+
+impl RoundaboutPath {
+    /// Path from the given lane of an approach to the given lane of the exit road
+    pub fn new(side: Side, lane: usize, direction: Direction, exit_lane: usize) -> Self {
+        let (center_x, center_y) = ((WINDOW_WIDTH / 2) as f32, (WINDOW_HEIGHT / 2) as f32);
+        let half_width = INTERSECTION_HALF_WIDTH as f32;
+
+        // Start on the yield line in the middle of the lane
+        let heading = side.heading();
+        let across = (lane as f32 + 0.5) * LANE_WIDTH as f32;
+        let start = (
+            center_x - half_width * heading.cos() - across * heading.sin(),
+            center_y - half_width * heading.sin() + across * heading.cos(),
+        );
+
+        // End where the exit lane leaves the box
+        let exit_heading = heading + match direction {
+            Direction::Left => -FRAC_PI_2,
+            Direction::Straight => 0.0,
+            Direction::Right => FRAC_PI_2,
+        };
+        let exit_across = (exit_lane as f32 + 0.5) * LANE_WIDTH as f32;
+        let end = (
+            center_x + half_width * exit_heading.cos() - exit_across * exit_heading.sin(),
+            center_y + half_width * exit_heading.sin() + exit_across * exit_heading.cos(),
+        );
+
+        // Join a little way round from the approach and leave a little before the exit
+        let merge_angle = heading + PI - MERGE_ANGLE;
+        let leave_angle = exit_heading + MERGE_ANGLE;
+        let sweep = (merge_angle - leave_angle).rem_euclid(TAU);
+        let sweep = if sweep > TAU - 0.001 { 0.0 } else { sweep };
+
+        Self {
+            entry: Curve::new(start, heading, ring_point(merge_angle), ring_heading(merge_angle)),
+            merge_angle,
+            sweep,
+            exit: Curve::new(ring_point(leave_angle), ring_heading(leave_angle), end, exit_heading),
+            exit_heading,
+        }
+    }
+
+    /// Path the given vehicle takes from its lane
+    pub fn for_vehicle(vehicle: &Vehicle) -> Self {
+        Self::new(vehicle.side, vehicle.lane, vehicle.direction, vehicle.exit_lane())
+    }
+
+    /// Distance from the yield line to the circulating lane
+    pub fn entry_length(&self) -> f32 {
+        self.entry.length()
+    }
+
+    /// Distance travelled on the circulating lane
+    fn ring_length(&self) -> f32 {
+        ROUNDABOUT_RADIUS * self.sweep
+    }
+
+    /// Total length of the path
+    pub fn length(&self) -> f32 {
+        self.entry.length() + self.ring_length() + self.exit.length()
+    }
+
+    /// Centre point and heading of a vehicle that has travelled the given distance along the path
+    pub fn pose(&self, travelled: f32) -> ((f32, f32), f32) {
+        let on_ring = travelled - self.entry.length();
+        let on_exit = on_ring - self.ring_length();
+        if on_ring < 0.0 {
+            self.entry.pose(travelled)
+        } else if on_exit < 0.0 {
+            let angle = self.merge_angle - on_ring / ROUNDABOUT_RADIUS;
+            (ring_point(angle), ring_heading(angle))
+        } else if on_exit < self.exit.length() {
+            self.exit.pose(on_exit)
+        } else {
+            (self.exit.pose(self.exit.length()).0, self.exit_heading)
+        }
+    }
+
+    /// Position along the circulating lane of a vehicle that has travelled the given distance
+    ///
+    /// Positions are measured in the direction of travel from a fixed point, so
+    /// they can be compared between vehicles. Vehicles on their way on or off
+    /// count as being where they will join or have left, plus or minus the
+    /// distance still to go or already gone.
+    pub fn ring_position(&self, travelled: f32) -> f32 {
+        let merge = ROUNDABOUT_RADIUS * (-self.merge_angle).rem_euclid(TAU);
+        (merge + travelled - self.entry.length()).rem_euclid(circumference())
+    }
+
+    /// Distance left before leaving the circulating lane, negative once off it
+    pub fn ring_remaining(&self, travelled: f32) -> f32 {
+        self.entry.length() + self.ring_length() - travelled
+    }
+}
+
+/// Whether any part of a vehicle, with the given distance left on the circulating lane, is still over it
+fn over_ring(vehicle: &Vehicle, remaining: f32) -> bool {
+    if remaining >= 0.0 {
+        return true;
+    }
+    let (x, y) = vehicle.center();
+    let from_center = (x - (WINDOW_WIDTH / 2) as f32).hypot(y - (WINDOW_HEIGHT / 2) as f32);
+    from_center - (vehicle.length() as f32) / 2.0 < ROUNDABOUT_RADIUS + LANE_WIDTH as f32 / 2.0
+}
+
+/// Gap acceptance and circulation rules of a roundabout, and its counters
+///
+/// A vehicle at the yield line enters when circulating traffic that will pass
+/// its merge point is at least `critical_gap` frames away at top speed, and at
+/// least `follow_up_headway` frames after the previous vehicle to enter from the
+/// same approach.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Roundabout {
+    /// Smallest gap in circulating traffic a driver accepts, in frames
+    pub critical_gap: f32,
+    /// Time between vehicles entering one after the other from the same approach, in frames
+    pub follow_up_headway: u32,
+    /// Number of vehicles that have entered
+    pub entries: usize,
+    /// Time of the last entry from each approach
+    last_entry: [Option<u32>; 4],
+}
+
+impl Roundabout {
+    /// Create the roundabout set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            critical_gap: ROUNDABOUT_CRITICAL_GAP_TICKS,
+            follow_up_headway: ROUNDABOUT_FOLLOW_UP_TICKS,
+            entries: 0,
+            last_entry: [None; 4],
+        }
+    }
+
+    /// Slot of an approach in `last_entry`
+    fn slot(side: Side) -> usize {
+        match side {
+            Side::FromEast => 0,
+            Side::FromWest => 1,
+            Side::FromNorth => 2,
+            Side::FromSouth => 3,
+        }
+    }
+
+    /// Whether the vehicle at the yield line can enter among the vehicles already in the roundabout
+    pub fn accepts(&self, vehicle: &Vehicle, circulating: &[Vehicle], time: u32) -> bool {
+        let follow_up = self.last_entry[Self::slot(vehicle.side)]
+            .is_none_or(|last| time - last >= self.follow_up_headway);
+        if !follow_up {
+            return false;
+        }
+
+        let path = RoundaboutPath::for_vehicle(vehicle);
+        let merge = path.ring_position(path.entry_length());
+        circulating.iter().all(|other| {
+            // Vehicles still crossing under the signals have to clear first
+            if !other.roundabout {
+                return false;
+            }
+
+            let other_path = RoundaboutPath::for_vehicle(other);
+            let remaining = other_path.ring_remaining(other.route_travelled);
+            if !over_ring(other, remaining) {
+                return true;
+            }
+            let clearance = (vehicle.length() + other.length()) as f32 / 2.0 + SAFETY_GAP as f32;
+
+            // The vehicle entered before from this approach only has to have moved off
+            if other.side == vehicle.side && other.route_travelled < other_path.entry_length() {
+                return other.route_travelled >= clearance;
+            }
+
+            let upstream = (merge - other_path.ring_position(other.route_travelled)).rem_euclid(circumference());
+            let downstream = circumference() - upstream;
+            if downstream < clearance {
+                return false;
+            }
+
+            // Traffic leaving before the merge point is no concern
+            if remaining + clearance < upstream {
+                return true;
+            }
+            (upstream - clearance) / other.max_velocity >= self.critical_gap
+        })
+    }
+
+    /// Records that a vehicle has entered from the given approach
+    pub fn entered(&mut self, side: Side, time: u32) {
+        self.last_entry[Self::slot(side)] = Some(time);
+        self.entries += 1;
+    }
+
+    /// Whether the vehicle at `index` has to wait for the one ahead of it on the circulating lane,
+    /// or on its way off it
+    pub fn blocked(vehicles: &[Vehicle], index: usize) -> bool {
+        let vehicle = &vehicles[index];
+        let path = RoundaboutPath::for_vehicle(vehicle);
+        let remaining = path.ring_remaining(vehicle.route_travelled);
+        let position = path.ring_position(vehicle.route_travelled);
+
+        vehicles.iter().enumerate().any(|(other_index, other)| {
+            if other_index == index || !other.roundabout {
+                return false;
+            }
+            let other_path = RoundaboutPath::for_vehicle(other);
+            let other_remaining = other_path.ring_remaining(other.route_travelled);
+            let clearance = (vehicle.length() + other.length()) as f32 / 2.0;
+
+            // Vehicles leaving by the same lane follow the same way off the circulating lane
+            if other_remaining < 0.0 && other.exit_leg() == vehicle.exit_leg() && other.exit_lane() == vehicle.exit_lane() {
+                let ahead = remaining - other_remaining;
+                if ahead < 0.0 || (ahead == 0.0 && other_index > index) {
+                    return false;
+                }
+                return ahead - clearance - vehicle.next_velocity() <= SAFETY_GAP as f32;
+            }
+            if remaining < 0.0 || !over_ring(other, other_remaining) {
+                return false;
+            }
+
+            // Only vehicles on the part of the circulating lane still to be driven matter,
+            // and of two side by side the one that entered first goes on
+            let ahead = (other_path.ring_position(other.route_travelled) - position).rem_euclid(circumference());
+            if ahead == 0.0 && other_index > index {
+                return false;
+            }
+            ahead <= remaining + clearance + SAFETY_GAP as f32 &&
+            ahead - clearance - vehicle.next_velocity() <= SAFETY_GAP as f32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+    const DIRECTIONS: [Direction; 3] = [Direction::Left, Direction::Straight, Direction::Right];
+
+    fn same_angle(a: f32, b: f32, tolerance: f32) -> bool {
+        let difference = (a - b).rem_euclid(TAU);
+        difference < tolerance || difference > TAU - tolerance
+    }
+
+    /// Car from the given approach that has gone the given distance into the roundabout
+    fn circulating(side: Side, direction: Direction, travelled: f32) -> Vehicle {
+        let mut vehicle = Vehicle::new(side, VehicleClass::Car, &mut rand::thread_rng()).with_direction(direction);
+        vehicle.roundabout = true;
+        vehicle.route_travelled = travelled;
+        vehicle
+    }
+
+    #[test]
+    fn paths_run_smoothly_from_the_yield_line_to_the_exit_road() {
+        for side in SIDES {
+            for direction in DIRECTIONS {
+                let path = RoundaboutPath::new(side, 0, direction, 0);
+                assert!(same_angle(path.pose(0.0).1, side.heading(), 0.2), "{:?} {:?}", side, direction);
+                assert!(same_angle(path.pose(path.length()).1, path.exit_heading, 0.2), "{:?} {:?}", side, direction);
+
+                let mut previous = path.pose(0.0).0;
+                for step in 1..=path.length().ceil() as usize {
+                    let point = path.pose(step as f32).0;
+                    let moved = (point.0 - previous.0).hypot(point.1 - previous.1);
+                    assert!(moved <= 1.5, "{:?} {:?} jumps {} at {}", side, direction, moved, step);
+                    previous = point;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn left_turns_go_furthest_round_and_right_turns_not_at_all() {
+        for side in SIDES {
+            let on_ring = |direction| {
+                let path = RoundaboutPath::new(side, 0, direction, 0);
+                path.ring_remaining(path.entry_length())
+            };
+            assert!(on_ring(Direction::Left) > on_ring(Direction::Straight));
+            assert!(on_ring(Direction::Right).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn entries_from_one_approach_keep_the_follow_up_headway() {
+        let mut roundabout = Roundabout::configured();
+        let vehicle = Vehicle::new(Side::FromSouth, VehicleClass::Car, &mut rand::thread_rng()).with_direction(Direction::Straight);
+        assert!(roundabout.accepts(&vehicle, &[], 0));
+
+        roundabout.entered(Side::FromSouth, 100);
+        assert_eq!(roundabout.entries, 1);
+        assert!(!roundabout.accepts(&vehicle, &[], 100 + ROUNDABOUT_FOLLOW_UP_TICKS - 1));
+        assert!(roundabout.accepts(&vehicle, &[], 100 + ROUNDABOUT_FOLLOW_UP_TICKS));
+
+        let other = Vehicle::new(Side::FromNorth, VehicleClass::Car, &mut rand::thread_rng()).with_direction(Direction::Straight);
+        assert!(roundabout.accepts(&other, &[], 101));
+    }
+
+    #[test]
+    fn circulating_traffic_blocks_entry_until_it_has_passed() {
+        let roundabout = Roundabout::configured();
+        let vehicle = Vehicle::new(Side::FromSouth, VehicleClass::Car, &mut rand::thread_rng()).with_direction(Direction::Straight);
+        let path = RoundaboutPath::for_vehicle(&vehicle);
+        let merge = path.ring_position(path.entry_length());
+
+        // A left turn from an approach whose way round passes the merge point
+        let side = SIDES.into_iter()
+            .find(|&side| {
+                let other = circulating(side, Direction::Left, 0.0);
+                let other_path = RoundaboutPath::for_vehicle(&other);
+                let upstream = (merge - other_path.ring_position(other_path.entry_length())).rem_euclid(circumference());
+                side != vehicle.side && upstream < other_path.ring_remaining(other_path.entry_length())
+            })
+            .expect("some left turn passes the merge point");
+        let other_path = RoundaboutPath::new(side, 0, Direction::Left, 0);
+        let at_merge = other_path.entry_length() +
+            (merge - other_path.ring_position(other_path.entry_length())).rem_euclid(circumference());
+
+        let clearance = (2 * vehicle.length()) as f32 / 2.0 + SAFETY_GAP as f32;
+        let approaching = circulating(side, Direction::Left, at_merge - clearance - 1.0);
+        assert!(!roundabout.accepts(&vehicle, &[approaching], 0));
+        let passed = circulating(side, Direction::Left, at_merge + clearance + 1.0);
+        assert!(roundabout.accepts(&vehicle, &[passed], 0));
+    }
+
+    #[test]
+    fn vehicles_on_the_circulating_lane_keep_their_distance() {
+        let entry = RoundaboutPath::new(Side::FromEast, 0, Direction::Left, 0).entry_length();
+        let leader = circulating(Side::FromEast, Direction::Left, entry + 50.0);
+        let clearance = leader.length() as f32;
+        let follower = circulating(Side::FromEast, Direction::Left, entry + 50.0 - clearance - SAFETY_GAP as f32);
+        let vehicles = [leader, follower];
+        assert!(!Roundabout::blocked(&vehicles, 0));
+        assert!(Roundabout::blocked(&vehicles, 1));
+    }
+}
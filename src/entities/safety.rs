@@ -0,0 +1,183 @@
+use crate::config::*;
+use super::vehicle::Vehicle;
+use serde::{Deserialize, Serialize};
+
+/// Safety invariant a pair of vehicles has broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViolationKind {
+    /// The bodies of the two vehicles overlap
+    Overlap,
+    /// The two vehicles are in the intersection at once on paths that cross
+    ConflictingMovements,
+}
+
+/// A pair of vehicles found breaking a safety invariant
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    /// Simulation time at which the violation started
+    pub time: u32,
+    /// Invariant that was broken
+    pub kind: ViolationKind,
+    /// Numbers of the two vehicles involved
+    pub vehicles: (u32, u32),
+    /// Centres of the two vehicles at the time
+    pub positions: ((f32, f32), (f32, f32)),
+}
+
+/// Corners of a vehicle's body, following its heading
+fn corners(vehicle: &Vehicle) -> [(f32, f32); 4] {
+    let (center_x, center_y) = vehicle.center();
+    let (half_length, half_breadth) = (vehicle.length() as f32 / 2.0, vehicle.breadth() as f32 / 2.0);
+    let (forward_x, forward_y) = (vehicle.heading.cos(), vehicle.heading.sin());
+    let (right_x, right_y) = (-forward_y, forward_x);
+    let corner = |along: f32, across: f32| {
+        (center_x + along * forward_x + across * right_x, center_y + along * forward_y + across * right_y)
+    };
+    [
+        corner(-half_length, -half_breadth),
+        corner(half_length, -half_breadth),
+        corner(half_length, half_breadth),
+        corner(-half_length, half_breadth),
+    ]
+}
+
+/// Whether the bodies of two vehicles overlap by more than `SAFETY_OVERLAP_TOLERANCE`
+///
+/// Bodies are rectangles along each vehicle's heading. They overlap unless
+/// one of the rectangles' axes separates them.
+pub fn overlap(vehicle: &Vehicle, other: &Vehicle) -> bool {
+    let ((x, y), (other_x, other_y)) = (vehicle.center(), other.center());
+    let radius = |vehicle: &Vehicle| (vehicle.length() as f32).hypot(vehicle.breadth() as f32) / 2.0;
+    let reach = radius(vehicle) + radius(other);
+    if (x - other_x).hypot(y - other_y) > reach {
+        return false;
+    }
+
+    let (own, theirs) = (corners(vehicle), corners(other));
+    let project = |points: &[(f32, f32); 4], (axis_x, axis_y): (f32, f32)| {
+        points.iter()
+            .map(|(x, y)| x * axis_x + y * axis_y)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), value| (low.min(value), high.max(value)))
+    };
+    [vehicle.heading, other.heading]
+        .into_iter()
+        .flat_map(|heading| [(heading.cos(), heading.sin()), (-heading.sin(), heading.cos())])
+        .all(|axis| {
+            let ((own_low, own_high), (their_low, their_high)) = (project(&own, axis), project(&theirs, axis));
+            own_high.min(their_high) - own_low.max(their_low) > SAFETY_OVERLAP_TOLERANCE
+        })
+}
+
+/// Runtime check that vehicles never run into each other
+///
+/// Each frame every pair of vehicles is checked for overlapping bodies, and the
+/// given pairs of vehicles in the intersection for paths that cross. A pair
+/// that goes on breaking an invariant is recorded once, when it starts. With
+/// `panic_on_violation` set, a violation panics in debug and test builds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SafetyChecker {
+    /// Whether the check runs
+    pub enabled: bool,
+    /// Whether a violation panics, in builds with debug assertions
+    pub panic_on_violation: bool,
+    /// Violations found so far, in the order they started
+    pub violations: Vec<Violation>,
+    /// Violations still going on as of the last check
+    ongoing: Vec<(ViolationKind, (u32, u32))>,
+}
+
+impl SafetyChecker {
+    /// Create the checker set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: SAFETY_CHECKS_ENABLED,
+            panic_on_violation: SAFETY_PANIC_ON_VIOLATION,
+            violations: vec![],
+            ongoing: vec![],
+        }
+    }
+
+    /// Checks the vehicles at the given time and records the violations that have just started
+    ///
+    /// `crossing` lists the pairs of vehicles in the intersection whose paths cross.
+    pub fn check(&mut self, time: u32, vehicles: &[&Vehicle], crossing: &[(&Vehicle, &Vehicle)]) {
+        if !self.enabled {
+            return;
+        }
+
+        let violation = |kind: ViolationKind, vehicle: &Vehicle, other: &Vehicle| Violation {
+            time,
+            kind,
+            vehicles: (vehicle.id.min(other.id), vehicle.id.max(other.id)),
+            positions: (vehicle.center(), other.center()),
+        };
+        let overlapping = vehicles.iter()
+            .enumerate()
+            .flat_map(|(i, vehicle)| vehicles[i + 1..].iter().map(move |other| (*vehicle, *other)))
+            .filter(|(vehicle, other)| overlap(vehicle, other))
+            .map(|(vehicle, other)| violation(ViolationKind::Overlap, vehicle, other));
+        let conflicting = crossing.iter()
+            .map(|(vehicle, other)| violation(ViolationKind::ConflictingMovements, vehicle, other));
+        let found: Vec<Violation> = overlapping.chain(conflicting).collect();
+
+        let started: Vec<Violation> = found.iter()
+            .filter(|violation| !self.ongoing.contains(&(violation.kind, violation.vehicles)))
+            .copied()
+            .collect();
+        self.ongoing = found.iter().map(|violation| (violation.kind, violation.vehicles)).collect();
+
+        if let Some(violation) = started.first() {
+            if self.panic_on_violation && cfg!(debug_assertions) {
+                panic!("Safety invariant broken: {:?}", violation);
+            }
+        }
+        self.violations.extend(started);
+    }
+
+    /// Number of violations of the given kind found so far
+    pub fn count(&self, kind: ViolationKind) -> usize {
+        self.violations.iter().filter(|violation| violation.kind == kind).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+
+    fn checker() -> SafetyChecker {
+        SafetyChecker { enabled: true, panic_on_violation: false, ..SafetyChecker::configured() }
+    }
+
+    #[test]
+    fn vehicles_overlap_only_when_their_bodies_meet() {
+        let vehicle = Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng());
+        let mut other = Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng());
+        assert!(overlap(&vehicle, &other));
+
+        other.x += (vehicle.length() - 1) as f32;
+        assert!(overlap(&vehicle, &other));
+        other.x += 1.0;
+        assert!(!overlap(&vehicle, &other));
+    }
+
+    #[test]
+    fn a_violation_is_recorded_once_while_it_goes_on() {
+        let mut safety = checker();
+        let vehicle = Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng());
+        let mut other = Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng());
+
+        safety.check(0, &[&vehicle, &other], &[]);
+        safety.check(1, &[&vehicle, &other], &[]);
+        assert_eq!(safety.count(ViolationKind::Overlap), 1);
+        assert_eq!(safety.violations[0].vehicles, (vehicle.id.min(other.id), vehicle.id.max(other.id)));
+
+        other.x += 2.0 * vehicle.length() as f32;
+        safety.check(2, &[&vehicle, &other], &[]);
+        other.x = vehicle.x;
+        safety.check(3, &[&vehicle, &other], &[(&vehicle, &other)]);
+        assert_eq!(safety.count(ViolationKind::Overlap), 2);
+        assert_eq!(safety.count(ViolationKind::ConflictingMovements), 1);
+        assert_eq!(safety.violations[1].time, 3);
+    }
+}
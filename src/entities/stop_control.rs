@@ -0,0 +1,141 @@
+use crate::config::*;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+
+/// Whether vehicles making the two movements, from different approaches, have paths that cross
+///
+/// Vehicles heading for the same leg always conflict. Otherwise a right turn
+/// keeps to its corner, opposing through movements pass side by side, and so do
+/// opposing left turns; any other pair of movements crosses.
+pub fn movements_conflict(side: Side, direction: Direction, other_side: Side, other_direction: Direction) -> bool {
+    if side.exit_leg(direction) == other_side.exit_leg(other_direction) {
+        return true;
+    }
+    if direction == Direction::Right || other_direction == Direction::Right {
+        return false;
+    }
+    if other_side == side.opposite() {
+        return direction != other_direction;
+    }
+    true
+}
+
+/// A vehicle that has come to a stop at the line of a stop-controlled approach
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Arrival {
+    side: Side,
+    lane: usize,
+    time: u32,
+}
+
+/// Right of way at an intersection controlled by stop signs
+///
+/// Vehicles on a stop-controlled approach come to a full stop at the line and
+/// wait there at least `stop_ticks`. At an all-way stop they go in the order they
+/// stopped, although vehicles whose paths do not cross one that stopped earlier
+/// need not wait for it. At a two-way stop, vehicles on the minor road also wait
+/// for a gap of `critical_gap` in major road traffic that would cross their path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopControl {
+    /// Time a vehicle has to stand at the line before going, in frames
+    pub stop_ticks: u32,
+    /// Smallest gap in major road traffic a driver on the minor road accepts, in frames
+    pub critical_gap: f32,
+    /// Number of vehicles that have entered after stopping
+    pub entries: usize,
+    /// Vehicles standing at the line, in the order they stopped
+    arrivals: Vec<Arrival>,
+}
+
+impl StopControl {
+    /// Create the stop control set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            stop_ticks: STOP_TICKS,
+            critical_gap: TWO_WAY_STOP_CRITICAL_GAP_TICKS,
+            entries: 0,
+            arrivals: vec![],
+        }
+    }
+
+    /// Forgets the vehicles standing at the line, for the order to start over
+    pub fn reset(&mut self) {
+        self.arrivals.clear();
+    }
+
+    /// Records that the first vehicle in a lane is standing at the line, if not known already
+    pub fn arrived(&mut self, side: Side, lane: usize, time: u32) {
+        if !self.arrivals.iter().any(|arrival| arrival.side == side && arrival.lane == lane) {
+            self.arrivals.push(Arrival { side, lane, time });
+        }
+    }
+
+    /// Keeps only the arrivals in lanes for which the predicate holds
+    pub fn retain<F: FnMut(Side, usize) -> bool>(&mut self, mut keep: F) {
+        self.arrivals.retain(|arrival| keep(arrival.side, arrival.lane));
+    }
+
+    /// Whether the vehicle at the line of the given lane has stood there long enough
+    pub fn has_stopped(&self, side: Side, lane: usize, time: u32) -> bool {
+        self.arrivals.iter()
+            .find(|arrival| arrival.side == side && arrival.lane == lane)
+            .is_some_and(|arrival| time - arrival.time >= self.stop_ticks)
+    }
+
+    /// Lanes whose vehicle stopped at the line before the one in the given lane
+    pub fn earlier(&self, side: Side, lane: usize) -> Vec<(Side, usize)> {
+        self.arrivals.iter()
+            .take_while(|arrival| arrival.side != side || arrival.lane != lane)
+            .map(|arrival| (arrival.side, arrival.lane))
+            .collect()
+    }
+
+    /// Records that the vehicle at the line of the given lane has entered
+    pub fn entered(&mut self, side: Side, lane: usize) {
+        self.arrivals.retain(|arrival| arrival.side != side || arrival.lane != lane);
+        self.entries += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+    const DIRECTIONS: [Direction; 3] = [Direction::Left, Direction::Straight, Direction::Right];
+
+    #[test]
+    fn conflicts_do_not_depend_on_the_order_of_the_movements() {
+        for side in SIDES {
+            for other_side in SIDES.into_iter().filter(|&other| other != side) {
+                for direction in DIRECTIONS {
+                    for other_direction in DIRECTIONS {
+                        assert_eq!(
+                            movements_conflict(side, direction, other_side, other_direction),
+                            movements_conflict(other_side, other_direction, side, direction),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn movements_heading_for_the_same_leg_conflict() {
+        assert!(movements_conflict(Side::FromEast, Direction::Left, Side::FromSouth, Direction::Straight));
+        assert!(movements_conflict(Side::FromEast, Direction::Right, Side::FromWest, Direction::Left));
+    }
+
+    #[test]
+    fn opposing_movements_of_the_same_kind_pass_side_by_side() {
+        assert!(!movements_conflict(Side::FromEast, Direction::Straight, Side::FromWest, Direction::Straight));
+        assert!(!movements_conflict(Side::FromNorth, Direction::Left, Side::FromSouth, Direction::Left));
+        assert!(movements_conflict(Side::FromNorth, Direction::Left, Side::FromSouth, Direction::Straight));
+    }
+
+    #[test]
+    fn crossing_through_movements_conflict_and_right_turns_keep_to_their_corner() {
+        assert!(movements_conflict(Side::FromEast, Direction::Straight, Side::FromNorth, Direction::Straight));
+        assert!(!movements_conflict(Side::FromEast, Direction::Right, Side::FromSouth, Direction::Straight));
+    }
+}
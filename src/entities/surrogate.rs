@@ -0,0 +1,304 @@
+use crate::config::*;
+use crate::types::*;
+use super::vehicle::Vehicle;
+use serde::{Deserialize, Serialize};
+
+/// Number of cells along each side of the intersection box, for post-encroachment times
+const GRID: i32 = 2 * INTERSECTION_HALF_WIDTH / PET_CELL_SIZE;
+
+/// Time a follower would take to run into its leader at their current speeds, in frames
+///
+/// Both vehicles are assumed to follow the same route. Returns `None` unless the
+/// follower is closing in.
+pub fn time_to_collision(leader: &Vehicle, follower: &Vehicle) -> Option<f32> {
+    let gap = (leader.route_progress() - leader.length() as f32 / 2.0) -
+              (follower.route_progress() + follower.length() as f32 / 2.0);
+    let closing_speed = follower.velocity - leader.velocity;
+    (closing_speed > 0.0).then(|| gap.max(0.0) / closing_speed)
+}
+
+/// Summary of the values a surrogate safety measure took
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    /// Number of values
+    pub count: usize,
+    /// Smallest value
+    pub min: f32,
+    /// Value 15% of the way up, the usual cut-off for serious conflicts
+    pub p15: f32,
+    /// Value half way up
+    pub median: f32,
+    /// Mean of the values
+    pub mean: f32,
+}
+
+impl Distribution {
+    /// Summarises the given values, or `None` if there are none
+    pub fn of(mut values: Vec<f32>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f32::total_cmp);
+        let percentile = |share: f32| values[(share * (values.len() - 1) as f32).round() as usize];
+        Some(Self {
+            count: values.len(),
+            min: values[0],
+            p15: percentile(0.15),
+            median: percentile(0.5),
+            mean: values.iter().sum::<f32>() / values.len() as f32,
+        })
+    }
+}
+
+/// Surrogate safety measures, used to judge a design by near misses rather than crashes
+///
+/// Time-to-collision is taken between each follower and the leader in its lane,
+/// from the approach through the intersection. A conflict lasts while it stays
+/// below `ttc_threshold`, and its smallest value is recorded when it ends.
+/// Post-encroachment time is the time between one vehicle leaving a cell of the
+/// intersection box and a vehicle from another approach reaching it, recorded
+/// once for each pair of vehicles when below `pet_threshold`. A vehicle brakes
+/// hard when it decelerates by more than `hard_braking_deceleration`: vehicles
+/// that slow down lose that much speed in one frame, and vehicles that come to a
+/// halt would need that deceleration to stop from their speed within the
+/// distance they covered in their last frame on the move. Every measure is kept
+/// by the approach of the vehicle that came second or braked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurrogateSafety {
+    /// Whether the measures are taken
+    pub enabled: bool,
+    /// Time-to-collision below which followers are in conflict with their leader, in frames
+    pub ttc_threshold: f32,
+    /// Post-encroachment time below which vehicles crossing paths are in conflict, in frames
+    pub pet_threshold: u32,
+    /// Deceleration that counts as hard braking, in pixels per frame per frame
+    pub hard_braking_deceleration: f32,
+    /// Smallest time-to-collision of each conflict that has ended, in frames
+    pub ttc_events: Vec<(Side, f32)>,
+    /// Post-encroachment time of each conflict in the intersection box, in frames
+    pub pet_events: Vec<(Side, f32)>,
+    /// Frame of each hard braking event
+    pub hard_braking_events: Vec<(Side, u32)>,
+    /// Followers in conflict with their leader, by follower and leader, with the smallest time so far
+    following: Vec<(u32, u32, Side, f32)>,
+    /// Last vehicle over each cell of the box, with its approach and the frame
+    cells: Vec<Option<(u32, Side, u32)>>,
+    /// Pairs of vehicles whose post-encroachment time has been recorded, and when
+    crossed: Vec<((u32, u32), u32)>,
+    /// Speed and centre of each vehicle in the previous frame, with the distance it covered in that frame
+    speeds: Vec<(u32, f32, (f32, f32), f32)>,
+}
+
+impl SurrogateSafety {
+    /// Create the measures set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: SSM_ENABLED,
+            ttc_threshold: TTC_THRESHOLD_TICKS,
+            pet_threshold: PET_THRESHOLD_TICKS,
+            hard_braking_deceleration: HARD_BRAKING_DECELERATION,
+            ttc_events: vec![],
+            pet_events: vec![],
+            hard_braking_events: vec![],
+            following: vec![],
+            cells: vec![None; (GRID * GRID) as usize],
+            crossed: vec![],
+            speeds: vec![],
+        }
+    }
+
+    /// Takes this frame's time-to-collision between followers and leaders
+    ///
+    /// Each entry gives the follower, the leader, the approach of the follower and
+    /// their time-to-collision. Conflicts missing from the list have ended.
+    pub fn record_following(&mut self, pairs: &[(u32, u32, Side, f32)]) {
+        if !self.enabled {
+            return;
+        }
+
+        let threshold = self.ttc_threshold;
+        let conflicts: Vec<(u32, u32, Side, f32)> = pairs.iter()
+            .filter(|(_, _, _, ttc)| *ttc < threshold)
+            .map(|&(follower, leader, side, ttc)| {
+                let earlier = self.following.iter()
+                    .find(|(other_follower, other_leader, _, _)| (*other_follower, *other_leader) == (follower, leader))
+                    .map_or(ttc, |(_, _, _, smallest)| smallest.min(ttc));
+                (follower, leader, side, earlier)
+            })
+            .collect();
+
+        let ended = self.following.iter()
+            .filter(|(follower, leader, _, _)| {
+                !conflicts.iter().any(|(other_follower, other_leader, _, _)| (other_follower, other_leader) == (follower, leader))
+            })
+            .map(|&(_, _, side, smallest)| (side, smallest));
+        self.ttc_events.extend(ended);
+        self.following = conflicts;
+    }
+
+    /// Takes this frame's post-encroachment times in the intersection box
+    pub fn record_crossings(&mut self, time: u32, vehicles: &[Vehicle]) {
+        if !self.enabled {
+            return;
+        }
+
+        let threshold = self.pet_threshold;
+        self.crossed.retain(|(_, recorded)| time - recorded < threshold);
+
+        let origin_x = WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH;
+        let origin_y = WINDOW_HEIGHT / 2 - INTERSECTION_HALF_WIDTH;
+        for vehicle in vehicles {
+            let (x, y) = vehicle.center();
+            let column = ((x - origin_x as f32) / PET_CELL_SIZE as f32).floor() as i32;
+            let row = ((y - origin_y as f32) / PET_CELL_SIZE as f32).floor() as i32;
+            if !(0..GRID).contains(&column) || !(0..GRID).contains(&row) {
+                continue;
+            }
+
+            let cell = &mut self.cells[(row * GRID + column) as usize];
+            if let Some((other, side, left)) = *cell {
+                let pair = (vehicle.id.min(other), vehicle.id.max(other));
+                let encroachment = time - left;
+                let recorded = self.crossed.iter().any(|(crossed, _)| *crossed == pair);
+                if other != vehicle.id && side != vehicle.side && encroachment < threshold && !recorded {
+                    self.pet_events.push((vehicle.side, encroachment as f32));
+                    self.crossed.push((pair, time));
+                }
+            }
+            *cell = Some((vehicle.id, vehicle.side, time));
+        }
+    }
+
+    /// Takes this frame's speeds and notes the vehicles that have braked hard since the last one
+    ///
+    /// Each entry gives a vehicle, its approach, its speed and its centre.
+    pub fn record_speeds(&mut self, time: u32, speeds: &[(u32, Side, f32, (f32, f32))]) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut current = vec![];
+        for &(id, side, velocity, (x, y)) in speeds {
+            let previous = self.speeds.iter().find(|(other, _, _, _)| *other == id);
+            let moved = previous.map_or(0.0, |&(_, _, (previous_x, previous_y), _)| (x - previous_x).hypot(y - previous_y));
+            let deceleration = previous.map_or(0.0, |&(_, previous_velocity, _, previous_moved)| {
+                if velocity > 0.0 || previous_moved <= 0.0 {
+                    previous_velocity - velocity
+                } else {
+                    previous_velocity * previous_velocity / (2.0 * previous_moved)
+                }
+            });
+            if deceleration > self.hard_braking_deceleration {
+                self.hard_braking_events.push((side, time));
+            }
+            current.push((id, velocity, (x, y), moved));
+        }
+        self.speeds = current;
+    }
+
+    /// Time-to-collision of the conflicts that have ended on the given approach
+    pub fn ttc(&self, side: Side) -> Option<Distribution> {
+        Distribution::of(self.ttc_events.iter().filter(|(other, _)| *other == side).map(|(_, ttc)| *ttc).collect())
+    }
+
+    /// Post-encroachment times of the conflicts involving vehicles from the given approach
+    pub fn pet(&self, side: Side) -> Option<Distribution> {
+        Distribution::of(self.pet_events.iter().filter(|(other, _)| *other == side).map(|(_, pet)| *pet).collect())
+    }
+
+    /// Number of hard braking events on the given approach
+    pub fn hard_braking(&self, side: Side) -> usize {
+        self.hard_braking_events.iter().filter(|(other, _)| *other == side).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::coordination::SignalPlan;
+    use crate::entities::traffic::Intersection;
+
+    #[test]
+    fn no_values_give_no_distribution() {
+        assert_eq!(Distribution::of(vec![]), None);
+    }
+
+    #[test]
+    fn distributions_are_taken_over_the_sorted_values() {
+        let values: Vec<f32> = (1..=20).rev().map(|value| value as f32).collect();
+        let distribution = Distribution::of(values).unwrap();
+        assert_eq!(distribution.count, 20);
+        assert_eq!(distribution.min, 1.0);
+        assert_eq!(distribution.p15, 4.0);
+        assert_eq!(distribution.median, 11.0);
+        assert_eq!(distribution.mean, 10.5);
+    }
+
+    #[test]
+    fn a_single_value_is_every_statistic() {
+        let distribution = Distribution::of(vec![2.5]).unwrap();
+        assert_eq!((distribution.count, distribution.min, distribution.p15, distribution.median, distribution.mean), (1, 2.5, 2.5, 2.5, 2.5));
+    }
+
+    /// Runs the intersection until the vehicles from the east all stand still, and returns how many there are
+    fn run_until_standing(intersection: &mut Intersection) -> usize {
+        for _ in 0..1000 {
+            intersection.update();
+            if intersection.vehicles_waiting_east.iter().flatten().all(|vehicle| vehicle.velocity == 0.0) {
+                break;
+            }
+        }
+        intersection.vehicles_waiting_east.iter().flatten().filter(|vehicle| vehicle.velocity == 0.0).count()
+    }
+
+    /// Lets in a car from the east, going the way its lane leads
+    fn admit_from_east(intersection: &mut Intersection) {
+        assert!(intersection.admit_vehicle(Vehicle::new(Side::FromEast, VehicleClass::Car, &mut rand::thread_rng())));
+        for (lane, queue) in intersection.vehicles_waiting_east.iter_mut().enumerate() {
+            for vehicle in queue.iter_mut() {
+                *vehicle = vehicle.clone().with_direction(LANE_USE[lane].main_direction());
+            }
+        }
+    }
+
+    #[test]
+    fn running_into_a_queue_is_hard_braking() {
+        let mut intersection = Intersection::new();
+        intersection.bus_schedule.retain_routes(|_| false);
+        for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+            intersection.demand.close_entry(side);
+        }
+
+        // The approach from the east stays red throughout, and nobody runs it
+        intersection.compliance.enabled = false;
+        intersection.signal_plan = SignalPlan::with_cycle(Side::FromNorth, 0, Layout::Cross, 10 * CYCLE_TICKS, 9 * CYCLE_TICKS);
+        assert!(intersection.signal_plan.is_some());
+
+        // A car in every lane stops at the line
+        for _ in 0..LANES_PER_APPROACH {
+            admit_from_east(&mut intersection);
+        }
+        assert_eq!(run_until_standing(&mut intersection), LANES_PER_APPROACH);
+        let at_the_line = intersection.surrogates.hard_braking(Side::FromEast);
+
+        // The next one comes up behind one of them at full speed and has to stop
+        admit_from_east(&mut intersection);
+        assert_eq!(run_until_standing(&mut intersection), LANES_PER_APPROACH + 1);
+        assert!(intersection.vehicles_waiting_east.iter().any(|queue| queue.len() == 2 && queue[1].distance_to_stop_line() > 0.0));
+        assert_eq!(intersection.surrogates.hard_braking(Side::FromEast), at_the_line + 1);
+    }
+
+    #[test]
+    fn stopping_from_a_crawl_is_not_hard_braking() {
+        let mut surrogates = SurrogateSafety { enabled: true, ..SurrogateSafety::configured() };
+        surrogates.record_speeds(0, &[(1, Side::FromEast, 0.4, (100.0, 50.0))]);
+        surrogates.record_speeds(1, &[(1, Side::FromEast, 0.4, (100.4, 50.0))]);
+        surrogates.record_speeds(2, &[(1, Side::FromEast, 0.0, (100.4, 50.0))]);
+        assert_eq!(surrogates.hard_braking(Side::FromEast), 0);
+
+        surrogates.record_speeds(3, &[(2, Side::FromWest, 2.0, (300.0, 50.0))]);
+        surrogates.record_speeds(4, &[(2, Side::FromWest, 2.0, (298.0, 50.0))]);
+        surrogates.record_speeds(5, &[(2, Side::FromWest, 0.0, (298.0, 50.0))]);
+        assert_eq!(surrogates.hard_braking_events, [(Side::FromWest, 5)]);
+    }
+}
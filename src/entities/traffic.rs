@@ -1,16 +1,73 @@
 use crate::config::*;
 use crate::types::*;
 use super::vehicle::Vehicle;
-use rand::Rng;
+use super::transit::{BusSchedule, DelayStats, TransitPriority};
+use super::lane_change::LaneChangeModel;
+use super::coordination::SignalPlan;
+use super::demand::OdDemand;
+use super::roundabout::{Roundabout, RoundaboutPath};
+use super::reservation::ReservationManager;
+use super::pedestrian::{clearance_ticks, crosswalk_rect, serving_approach, PedestrianTraffic};
+use super::cyclist::CyclistTraffic;
+use super::stop_control::{movements_conflict, StopControl};
+use super::safety::SafetyChecker;
+use super::surrogate::{time_to_collision, SurrogateSafety};
+use super::compliance::Compliance;
+use super::discharge::QueueDischarge;
+use super::trends::Trends;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+/// An active emergency vehicle preemption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Preemption {
+    /// Approach the emergency vehicle is coming from
+    pub side: Side,
+    /// Simulation time at which the preemption was triggered
+    pub started_at: u32,
+    /// Number of vehicles waiting on the other approaches when the preemption was triggered
+    pub queue_at_start: usize,
+}
+
+/// Measurements of how preemption disrupts normal operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PreemptionStats {
+    /// Number of preemptions triggered
+    pub events: usize,
+    /// Total frames spent under preemption
+    pub preempted_ticks: u32,
+    /// Number of times the queues returned to their pre-preemption length
+    pub recoveries: usize,
+    /// Sum of all recovery times, in frames
+    pub total_recovery_ticks: u32,
+    /// Duration of the most recent recovery, in frames
+    pub last_recovery_ticks: Option<u32>,
+    /// The preemption being recovered from, and when it ended
+    recovering: Option<(Preemption, u32)>,
+}
+
+impl PreemptionStats {
+    /// Average time, in frames, for the queues to recover after a preemption
+    pub fn average_recovery_ticks(&self) -> Option<f32> {
+        if self.recoveries == 0 {
+            return None;
+        }
+        Some(self.total_recovery_ticks as f32 / self.recoveries as f32)
+    }
+}
 
 /// Represents the entire intersection with roads, traffic lights, and vehicles
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Intersection {
-    // Vehicles waiting at lights
-    pub vehicles_waiting_north: Vec<Vehicle>,
-    pub vehicles_waiting_south: Vec<Vehicle>,
-    pub vehicles_waiting_east: Vec<Vehicle>,
-    pub vehicles_waiting_west: Vec<Vehicle>,
+    // Vehicles waiting at lights, one queue per lane
+    pub vehicles_waiting_north: Vec<Vec<Vehicle>>,
+    pub vehicles_waiting_south: Vec<Vec<Vehicle>>,
+    pub vehicles_waiting_east: Vec<Vec<Vehicle>>,
+    pub vehicles_waiting_west: Vec<Vec<Vehicle>>,
+    
+    // Buses bypassing the queues in the queue jump lanes
+    pub vehicles_queue_jump: Vec<Vehicle>,
     
     // Vehicles in the intersection
     pub vehicles_in_intersection: Vec<Vehicle>,
@@ -21,427 +78,1475 @@ pub struct Intersection {
     pub vehicles_passed_east: Vec<Vehicle>,
     pub vehicles_passed_west: Vec<Vehicle>,
     
+    // Vehicles that have driven out of the intersection's area
+    pub vehicles_departed: Vec<Vehicle>,
+    
     // Traffic lights
     pub north_light: TrafficLight,
     pub south_light: TrafficLight,
     pub east_light: TrafficLight,
     pub west_light: TrafficLight,
     
+    // Legs meeting at the intersection, and how right of way is given
+    pub layout: Layout,
+    pub control: Control,
+    
+    // Signal controller state
+    pub green_side: Option<Side>,
+    pub phase_timer: u32,
+    pub preemption: Option<Preemption>,
+    pub signal_plan: Option<SignalPlan>,
+    
+    // Mix of vehicle classes used when spawning
+    pub fleet_mix: FleetMix,
+    
+    // How drivers treat the lights, and how the queues move off on a green
+    pub compliance: Compliance,
+    pub discharge: QueueDischarge,
+    
+    // Vehicles arriving according to the origin-destination matrix
+    pub demand: OdDemand,
+    
+    // Bus schedule and transit signal priority
+    pub bus_schedule: BusSchedule,
+    pub transit_priority: TransitPriority,
+    
+    // Lane changing on the approaches
+    pub lane_change: LaneChangeModel,
+    
+    // Entry and circulation when the intersection is a roundabout
+    pub roundabout: Roundabout,
+    // Right of way when the intersection is controlled by stop signs
+    pub stop_control: StopControl,
+    // Intersection manager handing out crossings under reservation control
+    pub reservations: ReservationManager,
+    
+    // Pedestrians using the crosswalks on each leg
+    pub pedestrians: PedestrianTraffic,
+    // Cyclists riding in the bike lanes on the shoulders
+    pub cyclists: CyclistTraffic,
+    
+    // Check that vehicles never run into each other, and the number given to the next vehicle let in
+    pub safety: SafetyChecker,
+    pub next_vehicle_id: u32,
+    
+    // Near misses measured by time-to-collision, post-encroachment time and hard braking
+    pub surrogates: SurrogateSafety,
+    
+    // Recent queue lengths, throughput and delay, for the charts on screen
+    pub trends: Trends,
+    
+    // Random draws for arrivals, drivers and their choices, saved with snapshots so a restored run carries on the same way
+    pub rng: ChaCha12Rng,
+    
     // Metrics
     pub total_vehicles_processed: usize,
     pub simulation_time: u32,
+    pub preemption_stats: PreemptionStats,
+    pub bus_delay: DelayStats,
+    pub general_delay: DelayStats,
 }
 
 impl Intersection {
     /// Creates a new intersection with all lights red and no vehicles
     pub fn new() -> Self {
         Self {
-            vehicles_waiting_north: vec![],
-            vehicles_waiting_south: vec![],
-            vehicles_waiting_east: vec![],
-            vehicles_waiting_west: vec![],
+            vehicles_waiting_north: vec![vec![]; LANES_PER_APPROACH],
+            vehicles_waiting_south: vec![vec![]; LANES_PER_APPROACH],
+            vehicles_waiting_east: vec![vec![]; LANES_PER_APPROACH],
+            vehicles_waiting_west: vec![vec![]; LANES_PER_APPROACH],
+            vehicles_queue_jump: vec![],
             vehicles_in_intersection: vec![],
             vehicles_passed_north: vec![],
             vehicles_passed_south: vec![],
             vehicles_passed_east: vec![],
             vehicles_passed_west: vec![],
+            vehicles_departed: vec![],
             north_light: TrafficLight::red(),
             south_light: TrafficLight::red(),
             east_light: TrafficLight::red(),
             west_light: TrafficLight::red(),
+            layout: Layout::Cross,
+            control: Control::Signals,
+            green_side: None,
+            phase_timer: 0,
+            preemption: None,
+            signal_plan: None,
+            fleet_mix: FleetMix::configured(),
+            compliance: Compliance::configured(),
+            discharge: QueueDischarge::configured(),
+            demand: OdDemand::configured(),
+            bus_schedule: BusSchedule::configured(),
+            transit_priority: TransitPriority::configured(),
+            lane_change: LaneChangeModel::configured(),
+            roundabout: Roundabout::configured(),
+            stop_control: StopControl::configured(),
+            reservations: ReservationManager::configured(),
+            pedestrians: PedestrianTraffic::configured(),
+            cyclists: CyclistTraffic::configured(),
+            safety: SafetyChecker::configured(),
+            next_vehicle_id: 1,
+            surrogates: SurrogateSafety::configured(),
+            trends: Trends::configured(),
+            rng: ChaCha12Rng::from_entropy(),
             total_vehicles_processed: 0,
             simulation_time: 0,
+            preemption_stats: PreemptionStats::default(),
+            bus_delay: DelayStats::default(),
+            general_delay: DelayStats::default(),
+        }
+    }
+    
+    /// Creates a new intersection with the given legs
+    ///
+    /// Demand, bus lines, crosswalks and cyclists that would use a missing leg are left out.
+    pub fn with_layout(layout: Layout) -> Self {
+        let mut intersection = Self::new();
+        intersection.layout = layout;
+        if let Layout::ThreeLeg(missing) = layout {
+            intersection.demand.close_leg(missing);
+            intersection.pedestrians.close_leg(missing);
+            intersection.cyclists.close_leg(missing);
         }
+        intersection.bus_schedule.retain_routes(|route| layout.allows(route.side, route.direction));
+        intersection
+    }
+    
+    /// Movements each lane of the given approach may be used for
+    pub fn lane_uses(&self, side: Side) -> Vec<LaneUse> {
+        (0..LANES_PER_APPROACH).map(|lane| self.layout.lane_use(side, lane)).collect()
     }
     
     /// Performs a single update step for the entire simulation
     pub fn update(&mut self) {
-        // Update traffic light states
-        self.update_traffic_lights();
+        // Let in the buses that are due according to the schedule
+        self.dispatch_scheduled_buses();
+        
+        // Let in the vehicles arriving from the origin-destination matrix
+        self.dispatch_demand();
+        
+        // Update traffic light states, following the fixed-time plan if there is one;
+        // under other controls the lights are off, and vehicles give way by other rules
+        if self.control == Control::Signals {
+            if let Some(plan) = &self.signal_plan {
+                let time = self.simulation_time;
+                let states = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+                    .map(|side| (side, plan.light_state(side, time)));
+                for (side, state) in states {
+                    self.light_mut(side).state = state;
+                }
+            } else {
+                // Detect emergency vehicles and track preemption metrics
+                self.update_preemption();
+                
+                self.update_traffic_lights();
+            }
+            
+            // Drivers notice the changes after their reaction time, and the queues
+            // standing at a light that turns green are numbered for their discharge
+            let time = self.simulation_time;
+            for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+                let state = self.light(side).state;
+                self.compliance.observe(side, state, time);
+                if self.discharge.green_started(side, state == LightState::Green) {
+                    for lane in 0..LANES_PER_APPROACH {
+                        let queued = self.waiting(side)[lane].iter().take_while(|vehicle| vehicle.velocity == 0.0).count();
+                        self.discharge.start(side, lane, queued, time);
+                    }
+                }
+            }
+        }
+        
+        // Let pedestrians arrive and update the pedestrian signals
+        self.update_crosswalks();
         
         // Process vehicles in the intersection
         self.process_intersection_vehicles();
         
+        // Walk the pedestrians on across, keeping out of the way of vehicles
+        self.pedestrians.update(&self.vehicles_in_intersection);
+        
+        // Let cyclists arrive and ride on, keeping out of the way of vehicles and pedestrians
+        self.update_cyclists();
+        
         // Process vehicles that have passed through
         self.process_passed_vehicles();
         
         // Process vehicles waiting at lights
         self.process_waiting_vehicles();
         
+        // Measure time lost by vehicles that have not cleared the intersection yet
+        self.accumulate_delay();
+        
+        // Look for vehicles that have run into each other or onto crossing paths
+        self.check_safety();
+        
+        // Measure the near misses between vehicles
+        self.update_surrogates();
+        
+        // Sample the measures plotted on screen
+        self.update_trends();
+        
         // Increment simulation time
         self.simulation_time += 1;
     }
     
-    /// Updates the traffic light states based on traffic conditions
-    fn update_traffic_lights(&mut self) {
-        // Reset all lights to red first
-        let all_green = self.east_light.state == LightState::Green || 
-                        self.west_light.state == LightState::Green || 
-                        self.north_light.state == LightState::Green || 
-                        self.south_light.state == LightState::Green;
-                        
-        if all_green {
-            // If any light is green, turn all lights red (reset for next cycle)
-            self.east_light.state = LightState::Red;
-            self.west_light.state = LightState::Red;
-            self.north_light.state = LightState::Red;
-            self.south_light.state = LightState::Red;
-        } else {
-            // Set a light to green based on queue length
-            let east_count = self.vehicles_waiting_east.len();
-            let west_count = self.vehicles_waiting_west.len();
-            let north_count = self.vehicles_waiting_north.len();
-            let south_count = self.vehicles_waiting_south.len();
-            
-            // Find the direction with the most waiting vehicles
-            if east_count >= west_count && east_count >= north_count && east_count >= south_count && east_count > 0 {
-                self.east_light.state = LightState::Green;
-            } else if west_count >= east_count && west_count >= north_count && west_count >= south_count && west_count > 0 {
-                self.west_light.state = LightState::Green;
-            } else if north_count >= east_count && north_count >= west_count && north_count >= south_count && north_count > 0 {
-                self.north_light.state = LightState::Green;
-            } else if south_count > 0 {
-                self.south_light.state = LightState::Green;
+    /// Returns the traffic light controlling the given approach
+    pub fn light(&self, side: Side) -> &TrafficLight {
+        match side {
+            Side::FromNorth => &self.north_light,
+            Side::FromSouth => &self.south_light,
+            Side::FromEast => &self.east_light,
+            Side::FromWest => &self.west_light,
+        }
+    }
+    
+    /// Switches between a fixed-time plan and actuated control
+    ///
+    /// Actuated control picks up from an all-red interval.
+    pub fn set_signal_plan(&mut self, plan: Option<SignalPlan>) {
+        self.signal_plan = plan;
+        self.reset_signals();
+    }
+    
+    /// Switches between traffic lights, a roundabout, stop signs and reservations
+    ///
+    /// Vehicles already in the intersection finish their way across before any
+    /// vehicle enters under the new control.
+    pub fn set_control(&mut self, control: Control) {
+        self.control = control;
+        self.preemption = None;
+        self.reset_signals();
+        self.stop_control.reset();
+        
+        // Every reservation is given up, including those of vehicles already in the intersection
+        self.reservations.reset();
+        for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+            self.waiting_mut(side).iter_mut().flatten().for_each(|vehicle| vehicle.reservation = None);
+        }
+        self.vehicles_queue_jump.iter_mut().for_each(|bus| bus.reservation = None);
+        self.vehicles_in_intersection.iter_mut().for_each(|vehicle| vehicle.reservation = None);
+    }
+    
+    /// What vehicles approaching the line from the given side go by
+    ///
+    /// Without signals, vehicles on the major road of a two-way stop drive on
+    /// and all others stop at the line.
+    fn approach_light(&self, side: Side) -> TrafficLight {
+        match self.control {
+            Control::Signals => self.light(side).clone(),
+            Control::TwoWayStop(_) if !self.control.stops(side) => TrafficLight::green(),
+            _ => TrafficLight::red(),
+        }
+    }
+    
+    /// What a vehicle approaching the line goes by
+    ///
+    /// Under reservation control, a vehicle holding a reservation drives on. At
+    /// traffic lights, drivers go by the light as they see it, and those who have
+    /// decided to go on through a yellow or red drive on.
+    fn approach_light_for(&self, vehicle: &Vehicle) -> TrafficLight {
+        match self.control {
+            Control::Reservation if vehicle.reservation.is_some() => TrafficLight::green(),
+            Control::Signals if vehicle.light_decision == Some(LightDecision::Go) => TrafficLight::green(),
+            Control::Signals => TrafficLight { state: self.seen_light(vehicle) },
+            _ => self.approach_light(vehicle.side),
+        }
+    }
+    
+    /// State of the light on a vehicle's approach as its driver sees it
+    fn seen_light(&self, vehicle: &Vehicle) -> LightState {
+        self.compliance.perceived(vehicle, self.light(vehicle.side).state, self.simulation_time)
+    }
+    
+    /// Turns every light red, for the signals to start over from an all-red interval
+    fn reset_signals(&mut self) {
+        for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+            *self.light_mut(side) = TrafficLight::red();
+        }
+        self.green_side = None;
+        self.phase_timer = 0;
+    }
+    
+    /// Returns the traffic light controlling the given approach for modification
+    fn light_mut(&mut self, side: Side) -> &mut TrafficLight {
+        match side {
+            Side::FromNorth => &mut self.north_light,
+            Side::FromSouth => &mut self.south_light,
+            Side::FromEast => &mut self.east_light,
+            Side::FromWest => &mut self.west_light,
+        }
+    }
+    
+    /// Returns the lane queues approaching the intersection from the given side
+    pub fn waiting(&self, side: Side) -> &Vec<Vec<Vehicle>> {
+        match side {
+            Side::FromNorth => &self.vehicles_waiting_north,
+            Side::FromSouth => &self.vehicles_waiting_south,
+            Side::FromEast => &self.vehicles_waiting_east,
+            Side::FromWest => &self.vehicles_waiting_west,
+        }
+    }
+    
+    /// Returns the lane queues approaching from the given side for modification
+    fn waiting_mut(&mut self, side: Side) -> &mut Vec<Vec<Vehicle>> {
+        match side {
+            Side::FromNorth => &mut self.vehicles_waiting_north,
+            Side::FromSouth => &mut self.vehicles_waiting_south,
+            Side::FromEast => &mut self.vehicles_waiting_east,
+            Side::FromWest => &mut self.vehicles_waiting_west,
+        }
+    }
+    
+    /// Vehicles approaching the intersection from the given side, in all lanes
+    fn waiting_vehicles(&self, side: Side) -> impl Iterator<Item = &Vehicle> {
+        self.waiting(side).iter().flatten()
+    }
+    
+    /// Number of vehicles approaching from the given side, including buses in the queue jump lane
+    pub fn approach_count(&self, side: Side) -> usize {
+        self.waiting_vehicles(side).count() + 
+        self.vehicles_queue_jump.iter().filter(|vehicle| vehicle.side == side).count()
+    }
+    
+    /// Number of vehicles approaching the intersection on all but the given side
+    fn waiting_except(&self, side: Side) -> usize {
+        [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .into_iter()
+            .filter(|&other| other != side)
+            .map(|other| self.approach_count(other))
+            .sum()
+    }
+    
+    /// Whether a bus within detection range is asking for priority on the given approach
+    fn bus_detected(&self, side: Side) -> bool {
+        self.transit_priority.enabled && 
+        self.waiting_vehicles(side)
+            .chain(self.vehicles_queue_jump.iter().filter(|vehicle| vehicle.side == side))
+            .any(|vehicle| {
+                vehicle.class == VehicleClass::Bus && 
+                vehicle.distance_to_stop_line() <= TSP_DETECTION_DISTANCE as f32
+            })
+    }
+    
+    /// Finds an approach, other than the given one, with a bus asking for priority
+    fn priority_request(&self, except: Option<Side>) -> Option<Side> {
+        [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .into_iter()
+            .find(|&side| Some(side) != except && self.bus_detected(side))
+    }
+    
+    /// Adds the buses that are due to the approaches they serve
+    fn dispatch_scheduled_buses(&mut self) {
+        self.bus_schedule.tick(self.simulation_time);
+        
+        for (line, route) in self.bus_schedule.due() {
+            let bus = Vehicle::new(route.side, VehicleClass::Bus, &mut self.rng).with_direction(route.direction);
+            if self.admit_vehicle(bus) {
+                self.bus_schedule.dispatched(line);
             }
         }
     }
     
-    /// Processes vehicles currently in the intersection
-    fn process_intersection_vehicles(&mut self) {
-        if self.vehicles_in_intersection.is_empty() {
+    /// Draws new arrivals from the origin-destination matrix and lets in those that fit
+    fn dispatch_demand(&mut self) {
+        self.demand.tick(&mut self.rng);
+        
+        for (side, direction) in self.demand.due() {
+            let class = self.fleet_mix.sample(&mut self.rng);
+            let vehicle = Vehicle::new(side, class, &mut self.rng).with_direction(direction);
+            if self.admit_vehicle(vehicle) {
+                self.demand.dispatched(side, direction);
+            }
+        }
+    }
+    
+    /// Adds this frame's lost time to every vehicle that has not cleared the intersection
+    fn accumulate_delay(&mut self) {
+        self.vehicles_waiting_north.iter_mut().flatten()
+            .chain(self.vehicles_waiting_south.iter_mut().flatten())
+            .chain(self.vehicles_waiting_east.iter_mut().flatten())
+            .chain(self.vehicles_waiting_west.iter_mut().flatten())
+            .chain(self.vehicles_queue_jump.iter_mut())
+            .chain(self.vehicles_in_intersection.iter_mut())
+            .for_each(Vehicle::accumulate_delay);
+    }
+    
+    /// Every vehicle in the intersection's area, from the approaches to the exits
+    pub fn vehicles(&self) -> impl Iterator<Item = &Vehicle> {
+        self.vehicles_waiting_north.iter().flatten()
+            .chain(self.vehicles_waiting_south.iter().flatten())
+            .chain(self.vehicles_waiting_east.iter().flatten())
+            .chain(self.vehicles_waiting_west.iter().flatten())
+            .chain(&self.vehicles_queue_jump)
+            .chain(&self.vehicles_in_intersection)
+            .chain(&self.vehicles_passed_east)
+            .chain(&self.vehicles_passed_north)
+            .chain(&self.vehicles_passed_west)
+            .chain(&self.vehicles_passed_south)
+    }
+    
+    /// Runs the safety checker over every vehicle in the intersection's area
+    ///
+    /// Vehicles in the intersection on crossing paths are only a violation where the
+    /// control keeps them apart in space: at traffic lights and stop signs. At a
+    /// roundabout they merge, and reservations keep them apart in time.
+    fn check_safety(&mut self) {
+        if !self.safety.enabled {
             return;
         }
         
-        // Move the vehicle in the intersection
-        self.vehicles_in_intersection[0].update_position(&TrafficLight::green());
+        // The checker is set aside while the vehicles are borrowed
+        let mut safety = std::mem::replace(&mut self.safety, SafetyChecker::configured());
+        let vehicles: Vec<&Vehicle> = self.vehicles().collect();
         
-        // Check if the vehicle has left the intersection area
-        let vehicle = &self.vehicles_in_intersection[0];
-        let outside_x = vehicle.x > WINDOW_WIDTH / 2 + VEHICLE_WIDTH || 
-                        vehicle.x < WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH;
-        let outside_y = vehicle.y > WINDOW_HEIGHT / 2 + VEHICLE_HEIGHT || 
-                        vehicle.y < WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT;
-                        
-        if outside_x || outside_y {
-            let vehicle = self.vehicles_in_intersection.remove(0);
-            self.total_vehicles_processed += 1;
+        let separated = matches!(self.control, Control::Signals | Control::AllWayStop | Control::TwoWayStop(_));
+        let in_box: Vec<&Vehicle> = self.vehicles_in_intersection.iter()
+            .filter(|vehicle| separated && !vehicle.roundabout)
+            .collect();
+        let crossing: Vec<(&Vehicle, &Vehicle)> = in_box.iter()
+            .enumerate()
+            .flat_map(|(i, vehicle)| in_box[i + 1..].iter().map(move |other| (*vehicle, *other)))
+            .filter(|(vehicle, other)| Self::paths_cross(vehicle, other))
+            .collect();
+        
+        safety.check(self.simulation_time, &vehicles, &crossing);
+        self.safety = safety;
+    }
+    
+    /// Takes this frame's surrogate safety measures
+    ///
+    /// Followers are paired with the vehicle ahead in their lane, from the approach
+    /// into the intersection. Vehicles going round a roundabout are left out.
+    fn update_surrogates(&mut self) {
+        if !self.surrogates.enabled {
+            return;
+        }
+        
+        let mut following = vec![];
+        for side in self.layout.legs() {
+            for lane in 0..=LANES_PER_APPROACH {
+                let approach: Vec<&Vehicle> = if lane < LANES_PER_APPROACH {
+                    self.waiting(side)[lane].iter().collect()
+                } else {
+                    self.vehicles_queue_jump.iter().filter(|bus| bus.side == side).collect()
+                };
+                let lane_vehicles: Vec<&Vehicle> = self.vehicles_in_intersection.iter()
+                    .filter(|vehicle| vehicle.side == side && vehicle.lane == lane && !vehicle.roundabout)
+                    .chain(approach)
+                    .collect();
+                following.extend(lane_vehicles.windows(2).filter_map(|pair| {
+                    let (leader, follower) = (pair[0], pair[1]);
+                    time_to_collision(leader, follower).map(|ttc| (follower.id, leader.id, side, ttc))
+                }));
+            }
+        }
+        self.surrogates.record_following(&following);
+        
+        let time = self.simulation_time;
+        self.surrogates.record_crossings(time, &self.vehicles_in_intersection);
+        
+        let speeds: Vec<(u32, Side, f32, (f32, f32))> = self.vehicles()
+            .map(|vehicle| (vehicle.id, vehicle.side, vehicle.velocity, vehicle.center()))
+            .collect();
+        self.surrogates.record_speeds(time, &speeds);
+    }
+    
+    /// Takes a sample of the queues, throughput and delay for the charts, if one is due
+    fn update_trends(&mut self) {
+        let queues = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .map(|side| self.approach_count(side));
+        let delay = (
+            self.general_delay.total_delay + self.bus_delay.total_delay,
+            self.general_delay.vehicles + self.bus_delay.vehicles,
+        );
+        self.trends.record(self.simulation_time, queues, self.total_vehicles_processed, delay);
+    }
+    
+    /// Starts and ends emergency vehicle preemption and records its impact
+    fn update_preemption(&mut self) {
+        if let Some(preemption) = self.preemption {
+            // Preemption lasts until the emergency vehicle has entered the intersection
+            let still_approaching = self.waiting_vehicles(preemption.side)
+                .any(|vehicle| vehicle.class == VehicleClass::Emergency);
+            if still_approaching {
+                self.preemption_stats.preempted_ticks += 1;
+                return;
+            }
             
-            // Determine which exit vector to add the vehicle to
-            match (vehicle.side, vehicle.direction) {
-                // Vehicles going north
-                (Side::FromEast, Direction::Left) | 
-                (Side::FromSouth, Direction::Straight) | 
-                (Side::FromWest, Direction::Right) => {
-                    self.vehicles_passed_north.push(vehicle);
-                },
+            self.preemption = None;
+            self.preemption_stats.recovering = Some((preemption, self.simulation_time));
+        }
+        
+        // Normal operation has recovered once the held approaches are back to their earlier queues
+        if let Some((preemption, ended_at)) = self.preemption_stats.recovering {
+            if self.waiting_except(preemption.side) <= preemption.queue_at_start {
+                let recovery_ticks = self.simulation_time - ended_at;
+                self.preemption_stats.recoveries += 1;
+                self.preemption_stats.total_recovery_ticks += recovery_ticks;
+                self.preemption_stats.last_recovery_ticks = Some(recovery_ticks);
+                self.preemption_stats.recovering = None;
+            }
+        }
+        
+        // Look for an emergency vehicle within detection range of a stop line
+        let detected = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .into_iter()
+            .find(|&side| {
+                self.waiting_vehicles(side).any(|vehicle| {
+                    vehicle.class == VehicleClass::Emergency && 
+                    vehicle.distance_to_stop_line() <= PREEMPTION_DETECTION_DISTANCE as f32
+                })
+            });
+        
+        if let Some(side) = detected {
+            self.preemption = Some(Preemption {
+                side,
+                started_at: self.simulation_time,
+                queue_at_start: self.waiting_except(side),
+            });
+            self.preemption_stats.events += 1;
+            self.preemption_stats.recovering = None;
+        }
+    }
+    
+    /// Updates the traffic light states based on traffic conditions
+    ///
+    /// Each green is followed by a yellow and an all-red clearance interval before
+    /// the next approach is served. A preemption cuts short any conflicting green
+    /// and holds the emergency approach on green until the vehicle has gone through.
+    fn update_traffic_lights(&mut self) {
+        self.phase_timer += 1;
+        
+        let preempted_side = self.preemption.map(|preemption| preemption.side);
+        
+        match self.green_side {
+            Some(side) if self.light(side).state == LightState::Green => {
+                let end_green = match preempted_side {
+                    // Clear conflicting movements immediately
+                    Some(preempted) if preempted != side => true,
+                    // Hold the green for the emergency vehicle
+                    Some(_) => false,
+                    // Serve the queue between the minimum and maximum green times
+                    None => self.end_of_green(side),
+                };
                 
-                // Vehicles going east
-                (Side::FromSouth, Direction::Left) | 
-                (Side::FromWest, Direction::Straight) | 
-                (Side::FromNorth, Direction::Right) => {
-                    self.vehicles_passed_east.push(vehicle);
-                },
+                if end_green {
+                    *self.light_mut(side) = TrafficLight::yellow();
+                    self.phase_timer = 0;
+                }
+            },
+            Some(side) => {
+                // Yellow interval
+                if self.phase_timer >= YELLOW_TICKS {
+                    *self.light_mut(side) = TrafficLight::red();
+                    self.green_side = None;
+                    self.phase_timer = 0;
+                }
+            },
+            None => {
+                // All-red interval
+                if self.phase_timer < ALL_RED_TICKS {
+                    return;
+                }
                 
-                // Vehicles going south
-                (Side::FromWest, Direction::Left) | 
-                (Side::FromNorth, Direction::Straight) | 
-                (Side::FromEast, Direction::Right) => {
-                    self.vehicles_passed_south.push(vehicle);
-                },
+                let busiest_side = self.busiest_approach();
+                let priority_side = if self.transit_priority.early_green {
+                    self.priority_request(None)
+                } else {
+                    None
+                };
+                if priority_side.is_some() && priority_side != busiest_side && preempted_side.is_none() {
+                    // Bring the green forward for the bus
+                    self.transit_priority.early_greens_granted += 1;
+                }
                 
-                // Vehicles going west
-                (Side::FromNorth, Direction::Left) | 
-                (Side::FromEast, Direction::Straight) | 
-                (Side::FromSouth, Direction::Right) => {
-                    self.vehicles_passed_west.push(vehicle);
+                let next_side = preempted_side.or(priority_side).or(busiest_side);
+                if let Some(side) = next_side {
+                    *self.light_mut(side) = TrafficLight::green();
+                    self.green_side = Some(side);
+                    self.phase_timer = 0;
+                }
+            },
+        }
+    }
+    
+    /// Decides whether the green on the given approach has run its course
+    ///
+    /// With transit signal priority a bus on the approach can extend the green past
+    /// its maximum, and a bus waiting elsewhere cuts it short after the minimum green.
+    fn end_of_green(&mut self, side: Side) -> bool {
+        if self.phase_timer < MIN_GREEN_TICKS || self.pedestrians.holds_green(self.layout, side) {
+            return false;
+        }
+        
+        let bus_here = self.bus_detected(side);
+        if !bus_here && self.transit_priority.early_green && self.priority_request(Some(side)).is_some() {
+            self.transit_priority.early_greens_granted += 1;
+            return true;
+        }
+        
+        if self.approach_count(side) == 0 {
+            return true;
+        }
+        
+        let extend = bus_here && self.transit_priority.green_extension;
+        if extend && self.phase_timer == MAX_GREEN_TICKS {
+            self.transit_priority.extensions_granted += 1;
+        }
+        
+        let max_green = if extend {
+            MAX_GREEN_TICKS + TSP_GREEN_EXTENSION_TICKS
+        } else {
+            MAX_GREEN_TICKS
+        };
+        self.phase_timer >= max_green
+    }
+    
+    /// Number of vehicles waiting on the given approach and pedestrians waiting to walk with it
+    fn call_count(&self, side: Side) -> usize {
+        self.approach_count(side) + self.pedestrians.calls_for(self.layout, side)
+    }
+    
+    /// Finds the approach with the most waiting vehicles and pedestrians, if any are waiting
+    fn busiest_approach(&self) -> Option<Side> {
+        let east_count = self.call_count(Side::FromEast);
+        let west_count = self.call_count(Side::FromWest);
+        let north_count = self.call_count(Side::FromNorth);
+        let south_count = self.call_count(Side::FromSouth);
+        
+        if east_count >= west_count && east_count >= north_count && east_count >= south_count && east_count > 0 {
+            Some(Side::FromEast)
+        } else if west_count >= east_count && west_count >= north_count && west_count >= south_count && west_count > 0 {
+            Some(Side::FromWest)
+        } else if north_count >= east_count && north_count >= west_count && north_count >= south_count && north_count > 0 {
+            Some(Side::FromNorth)
+        } else if south_count > 0 {
+            Some(Side::FromSouth)
+        } else {
+            None
+        }
+    }
+    
+    /// Runs the pedestrian signals on, and starts walks for waiting pedestrians
+    ///
+    /// At traffic lights each crosswalk walks with the green of a neighbouring
+    /// approach. Without signals the crosswalks work like zebra crossings: a walk
+    /// starts as soon as no vehicle is on the crosswalk. Under reservation control
+    /// the crosswalk is first booked with the intersection manager for the walk and
+    /// its clearance, so that no vehicle is given a crossing over it meanwhile.
+    fn update_crosswalks(&mut self) {
+        self.pedestrians.tick(&mut self.rng);
+        
+        let time = self.simulation_time;
+        for i in 0..self.pedestrians.crosswalks.len() {
+            let leg = self.pedestrians.crosswalks[i].leg;
+            let waiting = self.pedestrians.is_waiting(leg);
+            match self.control {
+                Control::Signals => {
+                    let side = serving_approach(self.layout, leg);
+                    let green = side.is_some_and(|side| self.light(side).state == LightState::Green);
+                    let green_left = match (&self.signal_plan, side) {
+                        (Some(plan), Some(side)) => plan.green_remaining(side, time),
+                        _ => u32::MAX,
+                    };
+                    self.pedestrians.crosswalks[i].update_signal(green, green_left);
+                },
+                Control::Reservation => {
+                    let crosswalk = &mut self.pedestrians.crosswalks[i];
+                    crosswalk.advance(true);
+                    if crosswalk.signal == PedestrianSignal::DontWalk && crosswalk.booked.is_none() && waiting {
+                        crosswalk.booked = self.reservations.reserve_area(crosswalk_rect(leg), WALK_TICKS + clearance_ticks(), time);
+                    }
+                    if crosswalk.booked == Some(time) {
+                        crosswalk.booked = None;
+                        crosswalk.start_walk(WALK_TICKS);
+                    }
+                },
+                _ => {
+                    let (left, top, width, height) = crosswalk_rect(leg);
+                    let occupied = self.vehicles_in_intersection.iter().any(|vehicle| {
+                        vehicle.x < left + width && vehicle.x + vehicle.width as f32 > left &&
+                        vehicle.y < top + height && vehicle.y + vehicle.height as f32 > top
+                    });
+                    let crosswalk = &mut self.pedestrians.crosswalks[i];
+                    crosswalk.advance(true);
+                    if crosswalk.signal == PedestrianSignal::DontWalk && waiting && !occupied {
+                        crosswalk.start_walk(WALK_TICKS);
+                    }
                 },
             }
         }
     }
     
-    /// Processes vehicles that have passed through the intersection
-    fn process_passed_vehicles(&mut self) {
-        // Process vehicles that have exited east
-        if !self.vehicles_passed_east.is_empty() {
-            self.vehicles_passed_east[0].update_position(&TrafficLight::green());
-            
-            // Process following vehicles with safety distance
-            if self.vehicles_passed_east.len() > 1 {
-                let mut front_vehicle = self.vehicles_passed_east[0].clone();
-                
-                for i in 1..self.vehicles_passed_east.len() {
-                    if self.vehicles_passed_east[i].x - self.vehicles_passed_east[i].velocity > 
-                       front_vehicle.x + SAFETY_DISTANCE {
-                        self.vehicles_passed_east[i].update_position(&TrafficLight::green());
-                        front_vehicle = self.vehicles_passed_east[i].clone();
-                    }
+    /// Whether a vehicle has to give way to a pedestrian on a crosswalk
+    ///
+    /// Under reservation control the crosswalks are booked like the vehicles' crossings,
+    /// and a vehicle stopping short of its tiles would hold up the ones booked after it.
+    fn yields_to_pedestrian(&self, vehicle: &Vehicle) -> bool {
+        self.control != Control::Reservation && self.pedestrians.in_path(vehicle)
+    }
+    
+    /// Lets cyclists arrive, decides which of them may ride past the stop line, and moves them on
+    ///
+    /// At traffic lights cyclists go with the green of their approach. Under
+    /// reservation control a cyclist standing at the line books its way across
+    /// with the intersection manager. Under other controls it waits until no
+    /// vehicle in the intersection is near its way across.
+    fn update_cyclists(&mut self) {
+        let waiting = [
+            &self.vehicles_waiting_east,
+            &self.vehicles_waiting_north,
+            &self.vehicles_waiting_west,
+            &self.vehicles_waiting_south,
+        ];
+        let passed = [
+            &self.vehicles_passed_east,
+            &self.vehicles_passed_north,
+            &self.vehicles_passed_west,
+            &self.vehicles_passed_south,
+        ];
+        let vehicles: Vec<&Vehicle> = self.vehicles_in_intersection.iter()
+            .chain(&self.vehicles_queue_jump)
+            .chain(waiting.into_iter().flatten().flatten())
+            .chain(passed.into_iter().flatten())
+            .collect();
+        self.cyclists.tick(&mut self.rng, self.layout, &vehicles);
+        
+        let time = self.simulation_time;
+        if self.control == Control::Reservation {
+            for cyclist in &mut self.cyclists.cyclists {
+                if cyclist.is_at_line() && !cyclist.reserved {
+                    cyclist.reserved = self.reservations.reserve_footprints(&CyclistTraffic::footprints(cyclist, time));
                 }
             }
         }
         
-        // Process vehicles that have exited north
-        if !self.vehicles_passed_north.is_empty() {
-            self.vehicles_passed_north[0].update_position(&TrafficLight::green());
+        let may_enter: Vec<bool> = self.cyclists.cyclists.iter()
+            .map(|cyclist| match self.control {
+                Control::Signals => self.light(cyclist.side).state == LightState::Green && !self.is_yielding(cyclist.side),
+                Control::Reservation => cyclist.reserved,
+                _ => CyclistTraffic::is_clear_for(cyclist, &self.vehicles_in_intersection),
+            })
+            .collect();
+        
+        self.cyclists.update(&may_enter, &vehicles, &self.pedestrians);
+        self.cyclists.record_right_hooks(&self.vehicles_in_intersection);
+    }
+    
+    /// Whether a vehicle has to give way to a cyclist in its path
+    ///
+    /// Under reservation control cyclists book their way across like vehicles do.
+    fn yields_to_cyclist(&self, vehicle: &Vehicle) -> bool {
+        self.control != Control::Reservation && self.cyclists.in_path(vehicle)
+    }
+    
+    /// Whether a vehicle at the stop line has to let cyclists go first
+    ///
+    /// Drivers turning right wait for cyclists they see riding straight on alongside,
+    /// and no vehicle goes while a cyclist from another approach is still crossing.
+    /// At a roundabout, where the way out to every leg crosses a bike lane, vehicles
+    /// wait for cyclists from their own approach as well.
+    fn waits_for_cyclists(&self, vehicle: &Vehicle) -> bool {
+        if self.control == Control::Reservation {
+            return false;
+        }
+        let right_hook = vehicle.direction == Direction::Right && self.cyclists.approaching(vehicle.side);
+        let crossing = self.cyclists.cyclists.iter().any(|cyclist| {
+            (cyclist.side != vehicle.side || self.control == Control::Roundabout) && cyclist.is_crossing()
+        });
+        right_hook || crossing || self.cyclists.in_path(vehicle)
+    }
+    
+    /// Processes vehicles currently in the intersection
+    ///
+    /// Several vehicles from the same approach can be in the intersection at once.
+    /// Each keeps its distance to the vehicle ahead in its lane, or on the
+    /// circulating lane of a roundabout, and to the back of the queue on the road
+    /// it is leaving by, and gives way to pedestrians on the crosswalks and to cyclists.
+    fn process_intersection_vehicles(&mut self) {
+        for i in 0..self.vehicles_in_intersection.len() {
+            let vehicle = &self.vehicles_in_intersection[i];
             
-            // Process following vehicles with safety distance
-            if self.vehicles_passed_north.len() > 1 {
-                let mut front_vehicle = self.vehicles_passed_north[0].clone();
-                
-                for i in 1..self.vehicles_passed_north.len() {
-                    if self.vehicles_passed_north[i].y - self.vehicles_passed_north[i].velocity > 
-                       front_vehicle.y + SAFETY_DISTANCE {
-                        self.vehicles_passed_north[i].update_position(&TrafficLight::green());
-                        front_vehicle = self.vehicles_passed_north[i].clone();
-                    }
-                }
+            let blocked_in_lane = if vehicle.roundabout {
+                Roundabout::blocked(&self.vehicles_in_intersection, i)
+            } else {
+                let leader = self.vehicles_in_intersection[..i].iter().rev()
+                    .find(|other| other.side == vehicle.side && other.lane == vehicle.lane);
+                leader.is_some_and(|leader| {
+                    let gap = (leader.route_progress() - leader.length() as f32 / 2.0) - 
+                              (vehicle.route_progress() + vehicle.length() as f32 / 2.0);
+                    gap - vehicle.next_velocity() <= SAFETY_GAP as f32
+                })
+            };
+            
+            // Once lined up with the exit road, keep clear of the vehicles on it
+            let lined_up = if vehicle.roundabout {
+                RoundaboutPath::for_vehicle(vehicle).ring_remaining(vehicle.route_travelled) < 0.0
+            } else {
+                !vehicle.is_signalling()
+            };
+            let blocked_on_exit = lined_up && 
+                self.exit_queue(vehicle).iter().rev()
+                    .find(|other| other.exit_lane() == vehicle.exit_lane())
+                    .is_some_and(|leader| !vehicle.can_follow(leader));
+            
+            let yielding = self.yields_to_pedestrian(vehicle);
+            if yielding {
+                self.pedestrians.vehicle_yield_ticks += 1;
             }
+            let yielding_to_cyclist = self.yields_to_cyclist(vehicle);
+            if yielding_to_cyclist {
+                self.cyclists.vehicle_yield_ticks += 1;
+            }
+            
+            let vehicle = &mut self.vehicles_in_intersection[i];
+            if blocked_in_lane || blocked_on_exit || yielding || yielding_to_cyclist {
+                vehicle.stop();
+            } else {
+                vehicle.update_position(&TrafficLight::green());
+            }
+            
+            // A vehicle held up off its planned course books its way on again
+            self.reservations.keep_track(&mut self.vehicles_in_intersection[i], self.simulation_time);
         }
         
-        // Process vehicles that have exited west
-        if !self.vehicles_passed_west.is_empty() {
-            self.vehicles_passed_west[0].update_position(&TrafficLight::green());
+        // Move the vehicles that have left the intersection area onto their exit roads
+        let mut i = 0;
+        while i < self.vehicles_in_intersection.len() {
+            if !self.vehicles_in_intersection[i].has_cleared_intersection() {
+                i += 1;
+                continue;
+            }
             
-            // Process following vehicles with safety distance
-            if self.vehicles_passed_west.len() > 1 {
-                let mut front_vehicle = self.vehicles_passed_west[0].clone();
-                
-                for i in 1..self.vehicles_passed_west.len() {
-                    if self.vehicles_passed_west[i].x + self.vehicles_passed_west[i].velocity < 
-                       front_vehicle.x - SAFETY_DISTANCE {
-                        self.vehicles_passed_west[i].update_position(&TrafficLight::green());
-                        front_vehicle = self.vehicles_passed_west[i].clone();
-                    }
-                }
+            let mut vehicle = self.vehicles_in_intersection.remove(i);
+            if let Some(reservation) = vehicle.reservation.take() {
+                self.reservations.release(&reservation);
+            }
+            self.total_vehicles_processed += 1;
+            
+            if vehicle.class == VehicleClass::Bus {
+                self.bus_delay.record(vehicle.delay);
+            } else {
+                self.general_delay.record(vehicle.delay);
             }
+            
+            self.exit_queue_mut(&vehicle).push(vehicle);
         }
-        
-        // Process vehicles that have exited south
-        if !self.vehicles_passed_south.is_empty() {
-            self.vehicles_passed_south[0].update_position(&TrafficLight::green());
+    }
+    
+    /// Returns the vehicles on the road the given vehicle leaves the intersection by
+    fn exit_queue(&self, vehicle: &Vehicle) -> &Vec<Vehicle> {
+        match vehicle.exit_leg() {
+            Side::FromNorth => &self.vehicles_passed_north,
+            Side::FromEast => &self.vehicles_passed_east,
+            Side::FromSouth => &self.vehicles_passed_south,
+            Side::FromWest => &self.vehicles_passed_west,
+        }
+    }
+    
+    /// Returns the vehicles on the road the given vehicle leaves by for modification
+    fn exit_queue_mut(&mut self, vehicle: &Vehicle) -> &mut Vec<Vehicle> {
+        match vehicle.exit_leg() {
+            Side::FromNorth => &mut self.vehicles_passed_north,
+            Side::FromEast => &mut self.vehicles_passed_east,
+            Side::FromSouth => &mut self.vehicles_passed_south,
+            Side::FromWest => &mut self.vehicles_passed_west,
+        }
+    }
+    
+    /// Processes vehicles that have passed through the intersection
+    ///
+    /// Vehicles that drive off the edge of the area are handed over to
+    /// `vehicles_departed`, for the network to pass on to the next intersection.
+    fn process_passed_vehicles(&mut self) {
+        for vehicles in [
+            &mut self.vehicles_passed_east,
+            &mut self.vehicles_passed_north,
+            &mut self.vehicles_passed_west,
+            &mut self.vehicles_passed_south,
+        ] {
+            Self::process_exit_road(vehicles, &self.cyclists);
             
-            // Process following vehicles with safety distance
-            if self.vehicles_passed_south.len() > 1 {
-                let mut front_vehicle = self.vehicles_passed_south[0].clone();
-                
-                for i in 1..self.vehicles_passed_south.len() {
-                    if self.vehicles_passed_south[i].y + self.vehicles_passed_south[i].velocity < 
-                       front_vehicle.y - SAFETY_DISTANCE {
-                        self.vehicles_passed_south[i].update_position(&TrafficLight::green());
-                        front_vehicle = self.vehicles_passed_south[i].clone();
-                    }
-                }
+            let (departed, remaining): (Vec<Vehicle>, Vec<Vehicle>) = vehicles.drain(..).partition(Vehicle::has_departed);
+            *vehicles = remaining;
+            self.vehicles_departed.extend(departed);
+        }
+    }
+    
+    /// Moves the vehicles on an exit road, each keeping its distance to the one ahead in its lane
+    /// and giving way to cyclists in the bike lane
+    fn process_exit_road(vehicles: &mut [Vehicle], cyclists: &CyclistTraffic) {
+        for i in 0..vehicles.len() {
+            let (ahead, rest) = vehicles.split_at_mut(i);
+            let vehicle = &mut rest[0];
+            let leader = ahead.iter().rev().find(|other| other.exit_lane() == vehicle.exit_lane());
+            if leader.is_some_and(|leader| !vehicle.can_follow(leader)) || cyclists.in_path(vehicle) {
+                vehicle.stop();
+            } else {
+                vehicle.update_position(&TrafficLight::green());
             }
         }
     }
     
     /// Processes vehicles waiting at traffic lights
     fn process_waiting_vehicles(&mut self) {
-        // Process vehicles waiting at the north light
-        self.process_north_waiting_vehicles();
+        // Vehicles on other approaches pull over and wait while an emergency vehicle goes through
+        if let Some(preemption) = self.preemption {
+            for side in [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth] {
+                if side != preemption.side {
+                    self.waiting_mut(side).iter_mut().flatten().for_each(Vehicle::stop);
+                }
+            }
+        }
+        
+        // Note which vehicles have come to a stop at the line of a stop sign
+        self.record_stop_arrivals();
         
-        // Process vehicles waiting at the south light
-        self.process_south_waiting_vehicles();
+        // Buses in the queue jump lanes reach the stop line ahead of the queues
+        self.process_queue_jump_vehicles();
         
-        // Process vehicles waiting at the east light
-        self.process_east_waiting_vehicles();
+        // Let vehicles move over to their turn lanes or past slower vehicles
+        self.process_lane_changes();
         
-        // Process vehicles waiting at the west light
-        self.process_west_waiting_vehicles();
+        // Process the lanes of each approach
+        for side in [Side::FromNorth, Side::FromSouth, Side::FromEast, Side::FromWest] {
+            for lane in 0..LANES_PER_APPROACH {
+                self.process_lane(side, lane);
+            }
+        }
     }
     
-    /// Processes vehicles waiting at the north traffic light
-    fn process_north_waiting_vehicles(&mut self) {
-        if self.vehicles_waiting_north.is_empty() {
-            return;
+    /// Whether vehicles on the given approach must yield to an emergency vehicle
+    fn is_yielding(&self, side: Side) -> bool {
+        self.preemption.is_some_and(|preemption| preemption.side != side)
+    }
+    
+    /// Whether a vehicle at the stop line can enter the intersection without conflict
+    ///
+    /// Only one approach is served at a time.
+    fn can_enter(&self, vehicle: &Vehicle) -> bool {
+        self.vehicles_in_intersection.iter().all(|other| {
+            other.side == vehicle.side && !other.roundabout && Self::can_go_alongside(vehicle, other)
+        })
+    }
+    
+    /// Whether a vehicle can enter with a vehicle from the same approach already in the intersection
+    ///
+    /// Vehicles from neighbouring lanes may go through together as long as their
+    /// paths do not cross, and a vehicle in the same lane must have pulled far
+    /// enough ahead.
+    fn can_go_alongside(vehicle: &Vehicle, other: &Vehicle) -> bool {
+        if other.lane == vehicle.lane {
+            let gap = (other.route_progress() - other.length() as f32 / 2.0) - 
+                      (vehicle.route_progress() + vehicle.length() as f32 / 2.0);
+            return gap > SAFETY_GAP as f32;
         }
         
-        // Check if first vehicle should enter the intersection
-        let first_at_light = self.vehicles_waiting_north[0].y >= (WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT);
-        if first_at_light && self.north_light.state == LightState::Green && self.vehicles_in_intersection.is_empty() {
-            let vehicle = self.vehicles_waiting_north.remove(0);
-            self.vehicles_in_intersection.push(vehicle);
-            return;
+        // Vehicles merging into the same exit lane have to go one after the other
+        if other.direction == vehicle.direction && other.exit_lane() == vehicle.exit_lane() {
+            return false;
         }
         
-        // Move vehicles before the light
-        if !self.vehicles_waiting_north.is_empty() && 
-           self.vehicles_waiting_north[0].y < (WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT) {
-            self.vehicles_waiting_north[0].update_position(&self.north_light);
-            
-            // Move following vehicles with safety distance
-            if self.vehicles_waiting_north.len() > 1 {
-                let mut front_y = self.vehicles_waiting_north[0].y;
-                
-                for i in 1..self.vehicles_waiting_north.len() {
-                    if self.vehicles_waiting_north[i].y + self.vehicles_waiting_north[i].velocity < 
-                       front_y - SAFETY_DISTANCE {
-                        self.vehicles_waiting_north[i].update_position(&self.north_light);
-                        front_y = self.vehicles_waiting_north[i].y;
-                    }
-                }
-            }
+        // Paths cross unless turns further left are made from lanes further left
+        let rank = |direction: Direction| match direction {
+            Direction::Left => 0,
+            Direction::Straight => 1,
+            Direction::Right => 2,
+        };
+        let (inner, outer) = if other.lane < vehicle.lane { (other, vehicle) } else { (vehicle, other) };
+        rank(inner.direction) <= rank(outer.direction)
+    }
+    
+    /// Whether the paths of two vehicles through the intersection cross
+    fn paths_cross(vehicle: &Vehicle, other: &Vehicle) -> bool {
+        if other.side == vehicle.side {
+            !Self::can_go_alongside(vehicle, other)
+        } else {
+            movements_conflict(vehicle.side, vehicle.direction, other.side, other.direction)
+        }
+    }
+    
+    /// Whether a vehicle at the stop line can enter without crossing the path of any vehicle in the intersection
+    ///
+    /// Without signals, vehicles from several approaches may be in the intersection at once.
+    fn is_clear_for(&self, vehicle: &Vehicle) -> bool {
+        self.vehicles_in_intersection.iter()
+            .all(|other| !other.roundabout && !Self::paths_cross(vehicle, other))
+    }
+    
+    /// The first vehicle in a lane of an approach, the shoulder lane being the queue jump lane
+    fn first_in_lane(&self, side: Side, lane: usize) -> Option<&Vehicle> {
+        if lane < LANES_PER_APPROACH {
+            self.waiting(side)[lane].first()
+        } else {
+            self.vehicles_queue_jump.iter().find(|bus| bus.side == side)
         }
     }
     
-    /// Processes vehicles waiting at the south traffic light
-    fn process_south_waiting_vehicles(&mut self) {
-        if self.vehicles_waiting_south.is_empty() {
+    /// The first vehicle in a lane of an approach, for modification
+    fn first_in_lane_mut(&mut self, side: Side, lane: usize) -> Option<&mut Vehicle> {
+        if lane < LANES_PER_APPROACH {
+            self.waiting_mut(side)[lane].first_mut()
+        } else {
+            self.vehicles_queue_jump.iter_mut().find(|bus| bus.side == side)
+        }
+    }
+    
+    /// Asks the intersection manager for a crossing for the first vehicle in a lane once it
+    /// gets near, after giving up one it can no longer keep
+    fn update_reservation(&mut self, side: Side, lane: usize) {
+        let time = self.simulation_time;
+        let Some(vehicle) = self.first_in_lane_mut(side, lane) else {
             return;
+        };
+        
+        // A vehicle that is late, or has changed lane or direction, needs a new crossing
+        let stale = vehicle.reservation.as_ref().is_some_and(|reservation| {
+            time > reservation.arrival || reservation.lane != vehicle.lane || reservation.direction != vehicle.direction
+        });
+        if stale {
+            if let Some(reservation) = vehicle.reservation.take() {
+                self.reservations.release(&reservation);
+            }
         }
         
-        // Check if first vehicle should enter the intersection
-        let first_at_light = self.vehicles_waiting_south[0].y <= (WINDOW_HEIGHT / 2 + 2 * VEHICLE_HEIGHT);
-        if first_at_light && self.south_light.state == LightState::Green && self.vehicles_in_intersection.is_empty() {
-            let vehicle = self.vehicles_waiting_south.remove(0);
-            self.vehicles_in_intersection.push(vehicle);
+        let Some(vehicle) = self.first_in_lane(side, lane) else {
             return;
+        };
+        if vehicle.reservation.is_none() && vehicle.distance_to_stop_line() <= AIM_REQUEST_DISTANCE {
+            let vehicle = vehicle.clone();
+            let reservation = self.reservations.request(&vehicle, time);
+            if let Some(vehicle) = self.first_in_lane_mut(side, lane) {
+                vehicle.reservation = reservation;
+            }
         }
-        
-        // Move vehicles before the light
-        if !self.vehicles_waiting_south.is_empty() && 
-           self.vehicles_waiting_south[0].y > (WINDOW_HEIGHT / 2 + 2 * VEHICLE_HEIGHT) {
-            self.vehicles_waiting_south[0].update_position(&self.south_light);
-            
-            // Move following vehicles with safety distance
-            if self.vehicles_waiting_south.len() > 1 {
-                let mut front_y = self.vehicles_waiting_south[0].y;
-                
-                for i in 1..self.vehicles_waiting_south.len() {
-                    if self.vehicles_waiting_south[i].y - self.vehicles_waiting_south[i].velocity > 
-                       front_y + SAFETY_DISTANCE {
-                        self.vehicles_waiting_south[i].update_position(&self.south_light);
-                        front_y = self.vehicles_waiting_south[i].y;
-                    }
+    }
+    
+    /// Keeps the order in which vehicles have come to a stop at the lines of stop signs
+    fn record_stop_arrivals(&mut self) {
+        let mut standing = vec![];
+        for side in self.layout.legs() {
+            if !self.control.stops(side) {
+                continue;
+            }
+            for lane in 0..=LANES_PER_APPROACH {
+                let stopped = self.first_in_lane(side, lane)
+                    .is_some_and(|vehicle| vehicle.distance_to_stop_line() <= 0.0 && vehicle.velocity == 0.0);
+                if stopped {
+                    standing.push((side, lane));
                 }
             }
         }
+        
+        self.stop_control.retain(|side, lane| standing.contains(&(side, lane)));
+        for (side, lane) in standing {
+            self.stop_control.arrived(side, lane, self.simulation_time);
+        }
     }
     
-    /// Processes vehicles waiting at the east traffic light
-    fn process_east_waiting_vehicles(&mut self) {
-        if self.vehicles_waiting_east.is_empty() {
-            return;
+    /// Whether traffic with priority over a vehicle, and crossing its path, is about to reach the intersection
+    ///
+    /// At a two-way stop, the minor road gives way to the major road, and left
+    /// turns from the major road give way to oncoming traffic.
+    fn must_give_way(&self, vehicle: &Vehicle) -> bool {
+        let rank = |other: &Vehicle| match (self.control.stops(other.side), other.direction) {
+            (true, _) => 0,
+            (false, Direction::Left) => 1,
+            (false, _) => 2,
+        };
+        self.layout.legs().into_iter()
+            .flat_map(|side| self.waiting(side).iter().flatten())
+            .chain(self.vehicles_queue_jump.iter())
+            .filter(|other| other.side != vehicle.side && rank(other) > rank(vehicle))
+            .any(|other| {
+                let arrival = other.distance_to_stop_line().max(0.0) / other.max_velocity;
+                arrival < self.stop_control.critical_gap &&
+                movements_conflict(vehicle.side, vehicle.direction, other.side, other.direction)
+            })
+    }
+    
+    /// Whether a vehicle at the stop line may go into the intersection now
+    fn may_enter(&self, vehicle: &Vehicle) -> bool {
+        if self.yields_to_pedestrian(vehicle) || self.waits_for_cyclists(vehicle) {
+            return false;
+        }
+        match self.control {
+            Control::Signals => match vehicle.light_decision {
+                // Drivers running the light only mind the vehicles from their own approach
+                Some(LightDecision::Go) => self.vehicles_in_intersection.iter()
+                    .filter(|other| other.side == vehicle.side)
+                    .all(|other| !other.roundabout && Self::can_go_alongside(vehicle, other)),
+                _ => self.seen_light(vehicle) == LightState::Green && self.can_enter(vehicle),
+            },
+            Control::Roundabout => self.roundabout.accepts(vehicle, &self.vehicles_in_intersection, self.simulation_time),
+            Control::AllWayStop => {
+                // Vehicles that stopped earlier go first, unless their paths do not cross
+                let earlier_cross = self.stop_control.earlier(vehicle.side, vehicle.lane).into_iter()
+                    .filter_map(|(side, lane)| self.first_in_lane(side, lane))
+                    .any(|other| Self::paths_cross(vehicle, other));
+                self.stop_control.has_stopped(vehicle.side, vehicle.lane, self.simulation_time) &&
+                !earlier_cross && self.is_clear_for(vehicle)
+            },
+            Control::TwoWayStop(_) => {
+                let stopped = !self.control.stops(vehicle.side) ||
+                    self.stop_control.has_stopped(vehicle.side, vehicle.lane, self.simulation_time);
+                stopped && self.is_clear_for(vehicle) && !self.must_give_way(vehicle)
+            },
+            Control::Reservation => vehicle.reservation.as_ref().is_some_and(|reservation| {
+                reservation.arrival == self.simulation_time && 
+                reservation.lane == vehicle.lane && 
+                reservation.direction == vehicle.direction
+            }),
+        }
+    }
+    
+    /// Moves a vehicle from the stop line into the intersection
+    fn enter(&mut self, mut vehicle: Vehicle) {
+        if self.control == Control::Roundabout {
+            vehicle.roundabout = true;
+            self.roundabout.entered(vehicle.side, self.simulation_time);
+        }
+        if self.control.stops(vehicle.side) {
+            self.stop_control.entered(vehicle.side, vehicle.lane);
         }
         
-        // Check if first vehicle should enter the intersection
-        let first_at_light = self.vehicles_waiting_east[0].x >= (WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH);
-        if first_at_light && self.east_light.state == LightState::Green && self.vehicles_in_intersection.is_empty() {
-            let vehicle = self.vehicles_waiting_east.remove(0);
-            self.vehicles_in_intersection.push(vehicle);
-            return;
+        if self.control == Control::Signals {
+            self.compliance.entered(self.light(vehicle.side).state);
+            self.discharge.entered(vehicle.side, vehicle.lane, self.simulation_time);
         }
         
-        // Move vehicles before the light
-        if !self.vehicles_waiting_east.is_empty() && 
-           self.vehicles_waiting_east[0].x < (WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH) {
-            self.vehicles_waiting_east[0].update_position(&self.east_light);
+        // The reservation is kept until the crossing is over
+        vehicle.light_decision = None;
+        self.vehicles_in_intersection.push(vehicle);
+    }
+    
+    /// Moves vehicles that decide to change lanes over to the queue of their new lane
+    fn process_lane_changes(&mut self) {
+        for side in [Side::FromNorth, Side::FromSouth, Side::FromEast, Side::FromWest] {
+            if self.is_yielding(side) {
+                continue;
+            }
+            
+            let lane_uses = self.lane_uses(side);
+            for (lane, lane_use) in lane_uses.iter().enumerate() {
+                let mut i = 0;
+                while i < self.waiting(side)[lane].len() {
+                    let Some(target) = self.lane_change.choose_lane(self.waiting(side), &lane_uses, lane, i) else {
+                        i += 1;
+                        continue;
+                    };
+                    
+                    let mut vehicle = self.waiting_mut(side)[lane].remove(i);
+                    self.lane_change.record(!lane_use.allows(vehicle.direction));
+                    vehicle.change_lane(target);
+                    
+                    // Slot in between the vehicles ahead and behind in the new lane
+                    let queue = &mut self.waiting_mut(side)[target];
+                    let position = queue.iter()
+                        .position(|other| other.route_progress() < vehicle.route_progress())
+                        .unwrap_or(queue.len());
+                    queue.insert(position, vehicle);
+                }
+            }
+        }
+    }
+    
+    /// Processes buses travelling in the queue jump lanes
+    fn process_queue_jump_vehicles(&mut self) {
+        for side in [Side::FromNorth, Side::FromSouth, Side::FromEast, Side::FromWest] {
+            let light = self.approach_light(side);
+            let yielding = self.is_yielding(side);
+            let mut leader: Option<usize> = None;
+            let mut i = 0;
             
-            // Move following vehicles with safety distance
-            if self.vehicles_waiting_east.len() > 1 {
-                let mut front_x = self.vehicles_waiting_east[0].x;
+            while i < self.vehicles_queue_jump.len() {
+                if self.vehicles_queue_jump[i].side != side {
+                    i += 1;
+                    continue;
+                }
                 
-                for i in 1..self.vehicles_waiting_east.len() {
-                    if self.vehicles_waiting_east[i].x + self.vehicles_waiting_east[i].velocity < 
-                       front_x - SAFETY_DISTANCE {
-                        self.vehicles_waiting_east[i].update_position(&self.east_light);
-                        front_x = self.vehicles_waiting_east[i].x;
-                    }
+                match leader {
+                    None => {
+                        // The first bus enters the intersection ahead of the general queue
+                        if self.control == Control::Reservation {
+                            self.update_reservation(side, LANES_PER_APPROACH);
+                        }
+                        let bus = &self.vehicles_queue_jump[i];
+                        let at_light = bus.distance_to_stop_line() <= 0.0;
+                        let may_enter = match self.control {
+                            Control::Signals => {
+                                light.state == LightState::Green && self.vehicles_in_intersection.is_empty() &&
+                                !self.yields_to_pedestrian(bus) && !self.waits_for_cyclists(bus)
+                            },
+                            _ => self.may_enter(bus),
+                        };
+                        if at_light && !yielding && may_enter {
+                            let bus = self.vehicles_queue_jump.remove(i);
+                            self.enter(bus);
+                            continue;
+                        }
+                        
+                        let bus = &self.vehicles_queue_jump[i];
+                        let light = self.approach_light_for(bus);
+                        let held = self.is_held_at_bike_box(bus, &light) || self.cyclists.in_path(bus);
+                        if at_light || yielding || held {
+                            self.vehicles_queue_jump[i].stop();
+                        } else {
+                            self.vehicles_queue_jump[i].update_position(&light);
+                        }
+                    },
+                    Some(j) => {
+                        // Following buses keep their distance
+                        let bus = &self.vehicles_queue_jump[i];
+                        let can_follow = bus.can_follow(&self.vehicles_queue_jump[j]) && !self.cyclists.in_path(bus);
+                        let light = self.approach_light_for(bus);
+                        let held = self.is_held_at_bike_box(bus, &light);
+                        let bus = &mut self.vehicles_queue_jump[i];
+                        if !yielding && can_follow && !held {
+                            bus.update_position(&light);
+                        } else {
+                            bus.stop();
+                        }
+                    },
                 }
+                
+                leader = Some(i);
+                i += 1;
             }
         }
     }
     
-    /// Processes vehicles waiting at the west traffic light
-    fn process_west_waiting_vehicles(&mut self) {
-        if self.vehicles_waiting_west.is_empty() {
+    /// Whether a vehicle waiting for a green has come up to the back of the bike box
+    ///
+    /// Vehicles already in the box when the light changes go on up to the line.
+    fn is_held_at_bike_box(&self, vehicle: &Vehicle, light: &TrafficLight) -> bool {
+        if self.control != Control::Signals || !self.cyclists.bike_boxes || light.state == LightState::Green {
+            return false;
+        }
+        let distance = vehicle.distance_to_stop_line();
+        let depth = BIKE_BOX_DEPTH as f32;
+        distance >= depth && distance - vehicle.next_velocity() < depth
+    }
+    
+    /// Processes vehicles waiting in one lane of an approach
+    fn process_lane(&mut self, side: Side, lane: usize) {
+        if self.waiting(side)[lane].is_empty() || self.is_yielding(side) {
             return;
         }
+        let light = self.approach_light(side);
+        self.waiting_mut(side)[lane].iter_mut().for_each(Vehicle::steer_to_lane);
         
-        // Check if first vehicle should enter the intersection
-        let first_at_light = self.vehicles_waiting_west[0].x <= (WINDOW_WIDTH / 2 + VEHICLE_WIDTH);
-        if first_at_light && self.west_light.state == LightState::Green && self.vehicles_in_intersection.is_empty() {
-            let vehicle = self.vehicles_waiting_west.remove(0);
-            self.vehicles_in_intersection.push(vehicle);
+        // A vehicle still in the wrong lane when it gets a green, or reaches the line of
+        // an intersection without signals, gives up on its turn rather than hold up everyone behind it
+        let first = &self.waiting(side)[lane][0];
+        let first_at_light = first.distance_to_stop_line() <= 0.0;
+        let lane_use = self.layout.lane_use(side, lane);
+        let in_lane = !first.is_changing_lane() && lane_use.allows(first.direction);
+        let has_right_of_way = self.control != Control::Signals || light.state == LightState::Green;
+        if first_at_light && !first.is_changing_lane() && !in_lane && has_right_of_way {
+            let queue = &mut self.waiting_mut(side)[lane];
+            let vehicle = queue.remove(0).with_direction(lane_use.main_direction());
+            queue.insert(0, vehicle);
+            self.lane_change.missed_turns += 1;
             return;
         }
         
-        // Move vehicles before the light
-        if !self.vehicles_waiting_west.is_empty() && 
-           self.vehicles_waiting_west[0].x > (WINDOW_WIDTH / 2 + VEHICLE_WIDTH) {
-            self.vehicles_waiting_west[0].update_position(&self.west_light);
-            
-            // Move following vehicles with safety distance
-            if self.vehicles_waiting_west.len() > 1 {
-                let mut front_x = self.vehicles_waiting_west[0].x;
-                
-                for i in 1..self.vehicles_waiting_west.len() {
-                    if self.vehicles_waiting_west[i].x - self.vehicles_waiting_west[i].velocity > 
-                       front_x + SAFETY_DISTANCE {
-                        self.vehicles_waiting_west[i].update_position(&self.west_light);
-                        front_x = self.vehicles_waiting_west[i].x;
-                    }
-                }
+        // Under reservation control the first vehicle asks for its way across as it gets near
+        if self.control == Control::Reservation && in_lane {
+            self.update_reservation(side, lane);
+        }
+        
+        // At traffic lights the first driver makes up their mind about a light turning against them
+        if self.control == Control::Signals {
+            self.decide_at_light(side, lane);
+        }
+        
+        // Standing drivers take their reaction time to move off, except in the
+        // automated vehicles under reservation control
+        let time = self.simulation_time;
+        let reacts = self.discharge.reaction && self.control != Control::Reservation;
+        
+        // Check if first vehicle should enter the intersection
+        let first = &self.waiting(side)[lane][0];
+        if first_at_light && in_lane && self.may_enter(first) {
+            let first = &mut self.waiting_mut(side)[lane][0];
+            if !reacts || first.ready_to_move(time) {
+                let vehicle = self.waiting_mut(side)[lane].remove(0);
+                self.enter(vehicle);
+                return;
+            }
+        } else if first_at_light {
+            self.waiting_mut(side)[lane][0].ready_since = None;
+        }
+        
+        // The first vehicle also keeps its distance to one that has just entered from the lane
+        let box_leader = self.vehicles_in_intersection.iter().rev()
+            .find(|other| other.side == side && other.lane == lane);
+        
+        // Vehicles keep their distance to the one ahead in the lane, and to any vehicle
+        // still partly in the lane while changing lanes
+        let queue = &self.waiting(side)[lane];
+        let blocked: Vec<bool> = queue.iter().enumerate()
+            .map(|(i, vehicle)| {
+                let leader = if i == 0 { box_leader } else { Some(&queue[i - 1]) };
+                let blocked_in_lane = leader.is_some_and(|leader| !vehicle.can_follow(leader));
+                let blocked_across = self.waiting(side).iter()
+                    .enumerate()
+                    .filter(|&(other_lane, _)| other_lane.abs_diff(lane) == 1)
+                    .flat_map(|(_, other_queue)| other_queue)
+                    .filter(|other| {
+                        other.route_progress() > vehicle.route_progress() && 
+                        other.overlaps_laterally(vehicle)
+                    })
+                    .any(|other| !vehicle.can_follow(other));
+                blocked_in_lane || blocked_across
+            })
+            .collect();
+        let lights: Vec<TrafficLight> = queue.iter().map(|vehicle| self.approach_light_for(vehicle)).collect();
+        let held: Vec<bool> = queue.iter().zip(&lights).map(|(vehicle, light)| self.is_held_at_bike_box(vehicle, light)).collect();
+        
+        // Move vehicles before the light, or hold the first one there
+        let queue = &mut self.waiting_mut(side)[lane];
+        for (i, vehicle) in queue.iter_mut().enumerate() {
+            if blocked[i] || held[i] {
+                vehicle.ready_since = None;
+                vehicle.stop();
+            } else if (i == 0 && first_at_light) || (reacts && !vehicle.ready_to_move(time)) {
+                vehicle.stop();
+            } else {
+                vehicle.update_position(&lights[i]);
             }
         }
     }
     
+    /// Lets the driver of the first vehicle in a lane decide whether to stop for a yellow or red
+    ///
+    /// The decision holds until the vehicle enters or the driver sees a green again.
+    fn decide_at_light(&mut self, side: Side, lane: usize) {
+        let vehicle = self.waiting(side)[lane][0].clone();
+        let seen = self.seen_light(&vehicle);
+        let decision = if seen == LightState::Green {
+            None
+        } else {
+            let time = self.simulation_time;
+            self.compliance.decide(&vehicle, seen, time, &mut self.rng).or(vehicle.light_decision)
+        };
+        self.waiting_mut(side)[lane][0].light_decision = decision;
+    }
+    
     /// Creates a new vehicle from the east if there's sufficient space
     pub fn spawn_vehicle_from_east(&mut self) -> bool {
-        if self.vehicles_waiting_east.is_empty() || 
-           self.vehicles_waiting_east.last().unwrap().x > SAFETY_DISTANCE {
-            self.vehicles_waiting_east.push(Vehicle::new(Side::FromEast));
-            return true;
-        }
-        return false;
+        let class = self.fleet_mix.sample(&mut self.rng);
+        self.spawn_vehicle(Side::FromEast, class)
     }
     
     /// Creates a new vehicle from the west if there's sufficient space
     pub fn spawn_vehicle_from_west(&mut self) -> bool {
-        if self.vehicles_waiting_west.is_empty() || 
-           self.vehicles_waiting_west.last().unwrap().x < WINDOW_WIDTH - SAFETY_DISTANCE {
-            self.vehicles_waiting_west.push(Vehicle::new(Side::FromWest));
-            return true;
-        }
-        return false;
+        let class = self.fleet_mix.sample(&mut self.rng);
+        self.spawn_vehicle(Side::FromWest, class)
     }
     
     /// Creates a new vehicle from the north if there's sufficient space
     pub fn spawn_vehicle_from_north(&mut self) -> bool {
-        if self.vehicles_waiting_north.is_empty() || 
-           self.vehicles_waiting_north.last().unwrap().y > SAFETY_DISTANCE {
-            self.vehicles_waiting_north.push(Vehicle::new(Side::FromNorth));
-            return true;
-        }
-        return false;
+        let class = self.fleet_mix.sample(&mut self.rng);
+        self.spawn_vehicle(Side::FromNorth, class)
     }
     
     /// Creates a new vehicle from the south if there's sufficient space
     pub fn spawn_vehicle_from_south(&mut self) -> bool {
-        if self.vehicles_waiting_south.is_empty() || 
-           self.vehicles_waiting_south.last().unwrap().y < WINDOW_HEIGHT - SAFETY_DISTANCE {
-            self.vehicles_waiting_south.push(Vehicle::new(Side::FromSouth));
+        let class = self.fleet_mix.sample(&mut self.rng);
+        self.spawn_vehicle(Side::FromSouth, class)
+    }
+    
+    /// Creates an emergency vehicle from a random direction if there's sufficient space
+    pub fn spawn_emergency_vehicle(&mut self) -> bool {
+        let legs = self.layout.legs();
+        let side = legs[self.rng.gen_range(0..legs.len())];
+        self.spawn_vehicle(side, VehicleClass::Emergency)
+    }
+    
+    /// Creates a new vehicle of the given class if there's room at the start of its lane
+    pub fn spawn_vehicle(&mut self, side: Side, class: VehicleClass) -> bool {
+        let vehicle = Vehicle::new(side, class, &mut self.rng);
+        self.admit_vehicle(vehicle)
+    }
+    
+    /// Adds a vehicle at the start of a lane if there's room for it
+    ///
+    /// Vehicles join the shortest queue, whatever their direction, and change lanes
+    /// on the way to the stop line. Buses go to the queue jump lane when transit
+    /// signal priority uses one. There is no room on a missing leg, and a vehicle
+    /// heading for one picks another way to go. Vehicles let in are numbered in turn.
+    pub fn admit_vehicle(&mut self, mut vehicle: Vehicle) -> bool {
+        let side = vehicle.side;
+        vehicle.id = self.next_vehicle_id;
+        if !self.layout.has_leg(side) {
+            return false;
+        }
+        if !self.layout.allows(side, vehicle.direction) {
+            let directions = self.layout.directions(side);
+            vehicle = vehicle.with_direction(directions[self.rng.gen_range(0..directions.len())]);
+        }
+        let has_room = |vehicle: &Vehicle, last: Option<&Vehicle>| {
+            last.is_none_or(|last| vehicle.gap_behind(last) > SAFETY_GAP as f32)
+        };
+        
+        if vehicle.class == VehicleClass::Bus && self.transit_priority.uses_queue_jump() && self.control == Control::Signals {
+            vehicle.place_in_lane(LANES_PER_APPROACH);
+            let last = self.vehicles_queue_jump.iter().rev().find(|other| other.side == side);
+            if !has_room(&vehicle, last) || self.cyclists.near(&vehicle, SAFETY_GAP as f32) {
+                return false;
+            }
+            self.vehicles_queue_jump.push(vehicle);
+            self.next_vehicle_id += 1;
             return true;
         }
-        return false;
+        
+        let queues = self.waiting(side);
+        let lanes: Vec<usize> = (0..LANES_PER_APPROACH)
+            .filter(|&lane| {
+                let mut candidate = vehicle.clone();
+                candidate.place_in_lane(lane);
+                has_room(&candidate, queues[lane].last())
+            })
+            .collect();
+        
+        // Break ties between equally short queues at random
+        let shortest = lanes.iter().map(|&lane| queues[lane].len()).min();
+        let lanes: Vec<usize> = lanes.into_iter()
+            .filter(|&lane| Some(queues[lane].len()) == shortest)
+            .collect();
+        let lane = (!lanes.is_empty()).then(|| lanes[self.rng.gen_range(0..lanes.len())]);
+        
+        match lane {
+            Some(lane) => {
+                vehicle.place_in_lane(lane);
+                self.waiting_mut(side)[lane].push(vehicle);
+                self.next_vehicle_id += 1;
+                true
+            },
+            None => false,
+        }
     }
     
     /// Creates a new vehicle from a random direction if there's sufficient space
     pub fn spawn_vehicle_random(&mut self) -> bool {
-        let direction = rand::thread_rng().gen_range(0..4);
-        match direction {
-            0 => self.spawn_vehicle_from_east(),
-            1 => self.spawn_vehicle_from_west(),
-            2 => self.spawn_vehicle_from_north(),
-            _ => self.spawn_vehicle_from_south(),
+        let legs = self.layout.legs();
+        match legs[self.rng.gen_range(0..legs.len())] {
+            Side::FromEast => self.spawn_vehicle_from_east(),
+            Side::FromWest => self.spawn_vehicle_from_west(),
+            Side::FromNorth => self.spawn_vehicle_from_north(),
+            Side::FromSouth => self.spawn_vehicle_from_south(),
         }
     }
     
     /// Returns traffic statistics
     pub fn stats(&self) -> (usize, usize, usize, usize, usize) {
-        let waiting = self.vehicles_waiting_east.len() + 
-                      self.vehicles_waiting_west.len() + 
-                      self.vehicles_waiting_north.len() + 
-                      self.vehicles_waiting_south.len();
+        let waiting = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .into_iter()
+            .map(|side| self.waiting_vehicles(side).count())
+            .sum();
         
         let passing = self.vehicles_in_intersection.len();
         
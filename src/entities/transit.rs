@@ -0,0 +1,124 @@
+use crate::config::*;
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+
+/// Timetable of scheduled bus arrivals at the edge of the simulation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusSchedule {
+    /// Lines served by the schedule
+    pub routes: Vec<BusRoute>,
+    /// Frames between consecutive buses on the same line
+    pub headway: u32,
+    /// Buses that are due but could not enter yet, per line
+    pending: Vec<usize>,
+}
+
+impl BusSchedule {
+    /// Create the schedule set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            routes: BUS_ROUTES.to_vec(),
+            headway: BUS_HEADWAY_TICKS,
+            pending: vec![0; BUS_ROUTES.len()],
+        }
+    }
+    
+    /// Registers the buses due at the given time, staggering the lines over the headway
+    pub fn tick(&mut self, time: u32) {
+        if self.headway == 0 {
+            return;
+        }
+        
+        let lines = self.routes.len() as u32;
+        for (line, pending) in self.pending.iter_mut().enumerate() {
+            let offset = self.headway * line as u32 / lines;
+            if time % self.headway == offset {
+                *pending += 1;
+            }
+        }
+    }
+    
+    /// Lines that have a bus waiting to enter
+    pub fn due(&self) -> Vec<(usize, BusRoute)> {
+        self.routes.iter()
+            .enumerate()
+            .filter(|(line, _)| self.pending[*line] > 0)
+            .map(|(line, route)| (line, *route))
+            .collect()
+    }
+    
+    /// Marks a bus on the given line as having entered
+    pub fn dispatched(&mut self, line: usize) {
+        self.pending[line] = self.pending[line].saturating_sub(1);
+    }
+    
+    /// Stops serving the lines whose route fails the given check
+    pub fn retain_routes(&mut self, keep: impl Fn(&BusRoute) -> bool) {
+        (self.routes, self.pending) = self.routes.iter()
+            .zip(&self.pending)
+            .filter(|(route, _)| keep(route))
+            .map(|(route, pending)| (*route, *pending))
+            .unzip();
+    }
+}
+
+/// Transit signal priority settings and counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransitPriority {
+    /// Whether buses get any priority at all
+    pub enabled: bool,
+    /// Keep the green on a bus approach beyond the maximum green
+    pub green_extension: bool,
+    /// Cut short conflicting greens when a bus is waiting at a red
+    pub early_green: bool,
+    /// Let buses bypass the general queue in a lane of their own
+    pub queue_jump: bool,
+    /// Number of greens held beyond their maximum for a bus
+    pub extensions_granted: usize,
+    /// Number of greens cut short or brought forward for a bus
+    pub early_greens_granted: usize,
+}
+
+impl TransitPriority {
+    /// Create the priority settings from the configuration
+    pub fn configured() -> Self {
+        Self {
+            enabled: TSP_ENABLED,
+            green_extension: TSP_GREEN_EXTENSION,
+            early_green: TSP_EARLY_GREEN,
+            queue_jump: TSP_QUEUE_JUMP,
+            extensions_granted: 0,
+            early_greens_granted: 0,
+        }
+    }
+    
+    /// Whether buses currently use the queue jump lane
+    pub fn uses_queue_jump(&self) -> bool {
+        self.enabled && self.queue_jump
+    }
+}
+
+/// Accumulated delay for a group of vehicles
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct DelayStats {
+    /// Number of vehicles that have cleared the intersection
+    pub vehicles: usize,
+    /// Sum of their delays, in frames
+    pub total_delay: f32,
+}
+
+impl DelayStats {
+    /// Adds the delay of a vehicle that has cleared the intersection
+    pub fn record(&mut self, delay: f32) {
+        self.vehicles += 1;
+        self.total_delay += delay;
+    }
+    
+    /// Average delay per vehicle, in frames
+    pub fn average(&self) -> Option<f32> {
+        if self.vehicles == 0 {
+            return None;
+        }
+        Some(self.total_delay / self.vehicles as f32)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::*;
+use serde::{Deserialize, Serialize};
+
+/// Frames in a simulated minute
+const MINUTE_TICKS: u32 = 60 * FPS as u32;
+
+/// The measures of an intersection plotted on screen, at one moment
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrendSample {
+    /// Frame the sample was taken at
+    pub time: u32,
+    /// Vehicles approaching on each side, in the order east, west, north, south
+    pub queues: [usize; 4],
+    /// Vehicles processed since the previous sample, in vehicles per minute
+    pub throughput: f32,
+    /// Average delay of the vehicles that cleared the intersection since the previous sample, in frames
+    pub delay: Option<f32>,
+}
+
+/// Recent history of queue lengths, throughput and delay, for the charts on screen
+///
+/// A sample is taken every `sample_ticks` frames, and samples older than
+/// `window_ticks` are dropped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trends {
+    /// Frames between samples
+    pub sample_ticks: u32,
+    /// Length of the history kept, in frames
+    pub window_ticks: u32,
+    /// Samples in the window, oldest first
+    pub samples: Vec<TrendSample>,
+    /// Vehicles processed as of the previous sample
+    processed: usize,
+    /// Total delay and number of vehicles that had cleared the intersection as of the previous sample
+    delay: (f32, usize),
+}
+
+impl Trends {
+    /// Create the history set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            sample_ticks: TREND_SAMPLE_TICKS,
+            window_ticks: TREND_WINDOW_MINUTES * MINUTE_TICKS,
+            samples: vec![],
+            processed: 0,
+            delay: (0.0, 0),
+        }
+    }
+
+    /// Takes a sample at the given time if one is due
+    ///
+    /// `processed` is the number of vehicles processed so far, and `delay` the
+    /// total delay and number of vehicles that have cleared the intersection so far.
+    pub fn record(&mut self, time: u32, queues: [usize; 4], processed: usize, delay: (f32, usize)) {
+        if !time.is_multiple_of(self.sample_ticks) {
+            return;
+        }
+
+        let (total_delay, vehicles) = delay;
+        let (previous_delay, previous_vehicles) = self.delay;
+        let cleared = vehicles - previous_vehicles;
+        self.samples.push(TrendSample {
+            time,
+            queues,
+            throughput: (processed - self.processed) as f32 * MINUTE_TICKS as f32 / self.sample_ticks as f32,
+            delay: (cleared > 0).then(|| (total_delay - previous_delay) / cleared as f32),
+        });
+        self.processed = processed;
+        self.delay = delay;
+
+        let window = self.window_ticks;
+        self.samples.retain(|sample| time - sample.time <= window);
+    }
+}
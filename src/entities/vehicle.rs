@@ -1,55 +1,344 @@
 use crate::config::*;
 use crate::types::*;
+use super::reservation::Reservation;
+use super::roundabout::RoundaboutPath;
 
 use rand::Rng;
 use sdl2::pixels::Color;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
 
 /// Represents a vehicle in the simulation
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Vehicle {
+    /// Number the intersection gave the vehicle when letting it in, 0 until then
+    pub id: u32,
     /// X position of the vehicle
-    pub x: i32,
+    pub x: f32,
     /// Y position of the vehicle
-    pub y: i32,
+    pub y: f32,
+    /// Horizontal extent of the vehicle on screen
+    pub width: i32,
+    /// Vertical extent of the vehicle on screen
+    pub height: i32,
     /// Color based on the intended direction
+    #[serde(with = "ColorDef")]
     pub color: Color,
     /// Direction the vehicle will take at the intersection
     pub direction: Direction,
     /// Side from which the vehicle enters the intersection
     pub side: Side,
-    /// Movement speed of the vehicle
-    pub velocity: i32,
+    /// Lane of the approach the vehicle drives in, counted from the centre line
+    pub lane: usize,
+    /// Class of the vehicle
+    pub class: VehicleClass,
+    /// Temperament of the driver
+    pub driver: DriverStyle,
+    /// Time the driver takes to notice a light changing, in frames
+    pub reaction_ticks: u32,
+    /// What the driver has decided to do about the yellow or red light ahead
+    pub light_decision: Option<LightDecision>,
+    /// Frame since which the driver, standing, has had the way clear to move off
+    pub ready_since: Option<u32>,
+    /// Current movement speed of the vehicle
+    pub velocity: f32,
+    /// Top speed of the vehicle
+    pub max_velocity: f32,
+    /// Speed gained per frame when accelerating
+    pub acceleration: f32,
+    /// Direction the vehicle is facing, in radians clockwise from the +x axis
+    pub heading: f32,
+    /// Whether the vehicle has passed the stop line and follows its route
+    pub routed: bool,
+    /// Whether the vehicle goes round a roundabout rather than straight across
+    pub roundabout: bool,
+    /// Crossing granted by the intersection manager, held until the vehicle enters
+    pub reservation: Option<Reservation>,
+    /// Centre of the vehicle where it crossed the stop line
+    pub turn_origin: (f32, f32),
+    /// Distance travelled along the route since crossing the stop line
+    pub route_travelled: f32,
+    /// Time lost compared to travelling at top speed, in frames
+    pub delay: f32,
+    /// Intersection and leg the vehicle's trip through the network ends at
+    pub destination: Option<(usize, Side)>,
+    /// Simulation time at which the vehicle set off on a trip through the network
+    pub trip_start: Option<u32>,
 }
 
 impl Vehicle {
-    /// Creates a new vehicle entering from the specified side
-    pub fn new(side: Side) -> Self {
-        let mut rng = rand::thread_rng();
+    /// Creates a new vehicle of the given class entering from the specified side
+    ///
+    /// Its direction, driver and reaction time are drawn from `rng`.
+    pub fn new<R: Rng>(side: Side, class: VehicleClass, rng: &mut R) -> Self {
         let random_direction = rng.gen_range(0..3);
-        let velocity = rng.gen_range(MIN_VELOCITY..MAX_VELOCITY);
-        
+        let spec = class.spec();
+        let driver = DriverMix::configured().sample(rng);
+        let (fastest, slowest) = driver.spec().reaction_ticks;
+
         // Determine direction and color
         let (direction, color) = match random_direction {
             0 => (Direction::Left, VEHICLE_COLOR_LEFT),
             1 => (Direction::Straight, VEHICLE_COLOR_STRAIGHT),
             _ => (Direction::Right, VEHICLE_COLOR_RIGHT),
         };
-        
-        // Set initial position based on entry side
+
+        // Vehicles are laid out lengthwise along their lane
+        let (width, height) = match side {
+            Side::FromEast | Side::FromWest => (spec.length, spec.width),
+            Side::FromNorth | Side::FromSouth => (spec.width, spec.length),
+        };
+
+        // Set initial position at the edge of the window on the entry side
         let (x, y) = match side {
-            Side::FromEast => (0, WINDOW_HEIGHT / 2),
-            Side::FromNorth => (WINDOW_WIDTH / 2 - VEHICLE_WIDTH, 0),
-            Side::FromSouth => (WINDOW_WIDTH / 2, WINDOW_HEIGHT - VEHICLE_HEIGHT),
-            Side::FromWest => (WINDOW_WIDTH - VEHICLE_WIDTH, WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT),
+            Side::FromEast => (0, 0),
+            Side::FromNorth => (0, 0),
+            Side::FromSouth => (0, WINDOW_HEIGHT - height),
+            Side::FromWest => (WINDOW_WIDTH - width, 0),
         };
-        
-        Self {
-            x, y, color, direction, side, velocity
+
+        let mut vehicle = Self {
+            id: 0,
+            x: x as f32,
+            y: y as f32,
+            width,
+            height,
+            color,
+            direction,
+            side,
+            lane: 0,
+            class,
+            driver,
+            reaction_ticks: rng.gen_range(fastest..=slowest),
+            light_decision: None,
+            ready_since: None,
+            velocity: spec.max_velocity,
+            max_velocity: spec.max_velocity,
+            acceleration: spec.acceleration,
+            heading: side.heading(),
+            routed: false,
+            roundabout: false,
+            reservation: None,
+            turn_origin: (0.0, 0.0),
+            route_travelled: 0.0,
+            delay: 0.0,
+            destination: None,
+            trip_start: None,
+        };
+        vehicle.place_in_lane(0);
+        vehicle
+    }
+
+    /// Sets the direction the vehicle will take, instead of a random one
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self.color = match direction {
+            Direction::Left => VEHICLE_COLOR_LEFT,
+            Direction::Straight => VEHICLE_COLOR_STRAIGHT,
+            Direction::Right => VEHICLE_COLOR_RIGHT,
+        };
+        self
+    }
+
+    /// Moves the vehicle sideways into the middle of the given lane of its approach
+    ///
+    /// Lanes are counted from the centre line outwards; the lane just beyond the
+    /// last one is the shoulder.
+    pub fn place_in_lane(&mut self, lane: usize) {
+        self.lane = lane;
+        self.set_lateral_position(self.lane_center(lane));
+    }
+    
+    /// Switches to a neighbouring lane, steering across gradually as the vehicle moves
+    pub fn change_lane(&mut self, lane: usize) {
+        self.lane = lane;
+    }
+    
+    /// Whether the vehicle is still steering across into its lane
+    pub fn is_changing_lane(&self) -> bool {
+        (self.lane_center(self.lane) - self.lateral_position()).abs() > f32::EPSILON
+    }
+    
+    /// Steers the vehicle sideways towards the middle of its lane
+    pub fn steer_to_lane(&mut self) {
+        let offset = self.lane_center(self.lane) - self.lateral_position();
+        let step = offset.clamp(-LANE_CHANGE_SPEED, LANE_CHANGE_SPEED);
+        self.set_lateral_position(self.lateral_position() + step);
+    }
+    
+    /// Whether the vehicle takes up some of the same strip of road as another one
+    /// on the same approach, as happens while either of them changes lanes
+    pub fn overlaps_laterally(&self, other: &Vehicle) -> bool {
+        let distance = (self.lateral_position() - other.lateral_position()).abs();
+        distance < (self.breadth() + other.breadth()) as f32 / 2.0
+    }
+    
+    /// Screen coordinate across the road of the middle of the given lane
+    fn lane_center(&self, lane: usize) -> f32 {
+        let offset = (lane as f32 + 0.5) * LANE_WIDTH as f32;
+        match self.side {
+            Side::FromEast => (WINDOW_HEIGHT / 2) as f32 + offset,
+            Side::FromNorth => (WINDOW_WIDTH / 2) as f32 - offset,
+            Side::FromWest => (WINDOW_HEIGHT / 2) as f32 - offset,
+            Side::FromSouth => (WINDOW_WIDTH / 2) as f32 + offset,
         }
     }
     
+    /// Screen coordinate across the road of the centre of the vehicle
+    fn lateral_position(&self) -> f32 {
+        let (center_x, center_y) = self.center();
+        match self.side {
+            Side::FromEast | Side::FromWest => center_y,
+            Side::FromNorth | Side::FromSouth => center_x,
+        }
+    }
+    
+    /// Moves the centre of the vehicle across the road to the given coordinate
+    fn set_lateral_position(&mut self, position: f32) {
+        match self.side {
+            Side::FromEast | Side::FromWest => self.y = position - self.height as f32 / 2.0,
+            Side::FromNorth | Side::FromSouth => self.x = position - self.width as f32 / 2.0,
+        }
+    }
+
+    /// Lane of the exit road the vehicle ends up in
+    pub fn exit_lane(&self) -> usize {
+        match self.direction {
+            Direction::Straight => self.lane,
+            Direction::Left | Direction::Right => self.lane.min(LANES_PER_APPROACH - 1),
+        }
+    }
+
+    /// Leg of the intersection the vehicle leaves by
+    pub fn exit_leg(&self) -> Side {
+        self.side.exit_leg(self.direction)
+    }
+    
+    /// Whether the vehicle has driven off the edge of the intersection's area
+    pub fn has_departed(&self) -> bool {
+        self.x > WINDOW_WIDTH as f32 || self.x + (self.width as f32) < 0.0 || 
+        self.y > WINDOW_HEIGHT as f32 || self.y + (self.height as f32) < 0.0
+    }
+    
+    /// Accumulates the time lost this frame by not travelling at top speed
+    pub fn accumulate_delay(&mut self) {
+        self.delay += 1.0 - self.velocity / self.max_velocity;
+    }
+
+    /// Centre point of the vehicle
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width as f32 / 2.0, self.y + self.height as f32 / 2.0)
+    }
+
+    /// Moves the vehicle so that its centre is at the given point
+    fn set_center(&mut self, x: f32, y: f32) {
+        self.x = x - self.width as f32 / 2.0;
+        self.y = y - self.height as f32 / 2.0;
+    }
+
+    /// Width of the vehicle across its direction of travel
+    pub fn breadth(&self) -> i32 {
+        self.width.min(self.height)
+    }
+
+    /// Length of the vehicle along its direction of travel
+    pub fn length(&self) -> i32 {
+        self.width.max(self.height)
+    }
+
+    /// Speed the vehicle will have on its next move
+    ///
+    /// A vehicle with a reservation keeps to the granted speed until it reaches the stop line.
+    pub fn next_velocity(&self) -> f32 {
+        let top = match &self.reservation {
+            Some(reservation) if !self.routed && self.distance_to_stop_line() > 0.0 => reservation.velocity,
+            _ => self.max_velocity,
+        };
+        (self.velocity + self.acceleration).min(top)
+    }
+
+    /// Whether the vehicle is entirely outside the intersection
+    pub fn has_cleared_intersection(&self) -> bool {
+        let outside_x = self.x > (WINDOW_WIDTH / 2 + INTERSECTION_HALF_WIDTH) as f32 || 
+                        self.x + (self.width as f32) < (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH) as f32;
+        let outside_y = self.y > (WINDOW_HEIGHT / 2 + INTERSECTION_HALF_WIDTH) as f32 || 
+                        self.y + (self.height as f32) < (WINDOW_HEIGHT / 2 - INTERSECTION_HALF_WIDTH) as f32;
+        outside_x || outside_y
+    }
+
+    /// Distance from the front of the vehicle to its stop line, negative once past it
+    pub fn distance_to_stop_line(&self) -> f32 {
+        match self.side {
+            Side::FromEast => (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH) as f32 - (self.x + self.width as f32),
+            Side::FromNorth => (WINDOW_HEIGHT / 2 - INTERSECTION_HALF_WIDTH) as f32 - (self.y + self.height as f32),
+            Side::FromWest => self.x - (WINDOW_WIDTH / 2 + INTERSECTION_HALF_WIDTH) as f32,
+            Side::FromSouth => self.y - (WINDOW_HEIGHT / 2 + INTERSECTION_HALF_WIDTH) as f32,
+        }
+    }
+
+    /// Distance of the centre of the vehicle along its route, measured from the stop line
+    pub fn route_progress(&self) -> f32 {
+        if self.routed {
+            self.route_travelled
+        } else {
+            -(self.distance_to_stop_line() + self.length() as f32 / 2.0)
+        }
+    }
+
+    /// Bumper-to-bumper distance to a vehicle ahead in the same lane
+    ///
+    /// Both vehicles are assumed to travel along the same straight line.
+    pub fn gap_behind(&self, leader: &Vehicle) -> f32 {
+        let (leader_x, leader_y) = leader.center();
+        let (own_x, own_y) = self.center();
+        let along = (leader_x - own_x) * self.heading.cos() + (leader_y - own_y) * self.heading.sin();
+        along - (leader.length() + self.length()) as f32 / 2.0
+    }
+
+    /// Whether the vehicle can take its next step without closing in on the leader
+    pub fn can_follow(&self, leader: &Vehicle) -> bool {
+        self.gap_behind(leader) - self.next_velocity() > SAFETY_GAP as f32
+    }
+
+    /// Acceleration the vehicle would like to have behind the given leader
+    ///
+    /// Follows the Intelligent Driver Model. It is used to weigh up lane changes,
+    /// not to move the vehicle.
+    pub fn desired_acceleration(&self, leader: Option<&Vehicle>) -> f32 {
+        let free_road = 1.0 - (self.velocity / self.max_velocity).powi(4);
+        let interaction = leader.map_or(0.0, |leader| {
+            let gap = self.gap_behind(leader).max(0.1);
+            let closing_speed = self.velocity - leader.velocity;
+            let desired_gap = SAFETY_GAP as f32 + 
+                self.velocity * IDM_TIME_HEADWAY + 
+                self.velocity * closing_speed / (2.0 * (self.acceleration * IDM_COMFORTABLE_DECELERATION).sqrt());
+            (desired_gap.max(0.0) / gap).powi(2)
+        });
+        self.acceleration * (free_road - interaction)
+    }
+
+    /// Brings the vehicle to a halt, so it has to accelerate again to move off
+    pub fn stop(&mut self) {
+        self.velocity = 0.0;
+    }
+
+    /// Whether the driver moves off now, the way ahead being clear at the given time
+    ///
+    /// A standing driver only moves off once the way has been clear for their
+    /// reaction time; a moving one carries on.
+    pub fn ready_to_move(&mut self, time: u32) -> bool {
+        if self.velocity > 0.0 {
+            self.ready_since = None;
+            return true;
+        }
+        let since = *self.ready_since.get_or_insert(time);
+        time - since >= self.reaction_ticks
+    }
+
     /// Updates the vehicle position based on its state and traffic light
     pub fn update_position(&mut self, traffic_light: &TrafficLight) {
+        self.velocity = self.next_velocity();
+
         match self.side {
             Side::FromEast => self.update_from_east(traffic_light),
             Side::FromNorth => self.update_from_north(traffic_light),
@@ -57,140 +346,219 @@ impl Vehicle {
             Side::FromWest => self.update_from_west(traffic_light),
         }
     }
-    
+
+    /// Lays the on-screen extent of the vehicle along the axis closest to its heading
+    fn orient(&mut self) {
+        let (center_x, center_y) = self.center();
+        let (length, breadth) = (self.length(), self.breadth());
+        if self.heading.cos().abs() >= self.heading.sin().abs() {
+            self.width = length;
+            self.height = breadth;
+        } else {
+            self.width = breadth;
+            self.height = length;
+        }
+        self.set_center(center_x, center_y);
+    }
+
+    /// Whether the vehicle should be showing a turn signal
+    pub fn is_signalling(&self) -> bool {
+        if self.roundabout {
+            // Signal until off the circulating lane
+            let path = RoundaboutPath::for_vehicle(self);
+            return self.direction != Direction::Straight && path.ring_remaining(self.route_travelled) > 0.0;
+        }
+        self.direction != Direction::Straight && self.route_travelled < self.turn_radius() * FRAC_PI_2
+    }
+
+    /// Radius of the curve the vehicle's centre follows through its turn
+    pub fn turn_radius(&self) -> f32 {
+        // Turns start at the stop line and end where the exit lane leaves the box
+        let exit_offset = (self.exit_lane() as f32 + 0.5) * LANE_WIDTH as f32;
+        match self.direction {
+            Direction::Left => INTERSECTION_HALF_WIDTH as f32 + exit_offset,
+            Direction::Right => INTERSECTION_HALF_WIDTH as f32 - exit_offset,
+            Direction::Straight => f32::INFINITY,
+        }
+    }
+
+    /// Moves the vehicle forward along its heading
+    fn advance(&mut self, distance: f32) {
+        self.x += distance * self.heading.cos();
+        self.y += distance * self.heading.sin();
+    }
+
+    /// Follows the vehicle's route once it has passed the stop line
+    ///
+    /// Turning vehicles follow a quarter circle, slowing down so the lateral
+    /// acceleration stays within `MAX_LATERAL_ACCELERATION`, then carry on
+    /// straight along their new heading.
+    fn follow_route(&mut self) {
+        if !self.routed {
+            // Turns start with the centre of the vehicle on the stop line
+            let (center_x, center_y) = self.center();
+            let ahead = (self.distance_to_stop_line() + self.length() as f32 / 2.0).max(0.0);
+            self.routed = true;
+            self.turn_origin = (center_x + ahead * self.heading.cos(), center_y + ahead * self.heading.sin());
+        }
+        
+        if self.roundabout {
+            self.follow_roundabout();
+            return;
+        }
+
+        let turn = match self.direction {
+            Direction::Left => -1.0,
+            Direction::Right => 1.0,
+            Direction::Straight => 0.0,
+        };
+        let radius = self.turn_radius();
+        let arc_length = radius * FRAC_PI_2;
+
+        if turn == 0.0 || self.route_travelled >= arc_length {
+            self.route_travelled += self.velocity;
+            self.advance(self.velocity);
+            return;
+        }
+
+        // Slow down to a comfortable speed for the curve
+        self.velocity = self.velocity.min((MAX_LATERAL_ACCELERATION * radius).sqrt());
+        self.route_travelled = (self.route_travelled + self.velocity).min(arc_length);
+
+        // The centre of the curve lies on the side the vehicle turns towards
+        let entry_heading = self.side.heading();
+        let (origin_x, origin_y) = self.turn_origin;
+        let curve_x = origin_x - turn * radius * entry_heading.sin();
+        let curve_y = origin_y + turn * radius * entry_heading.cos();
+
+        self.heading = entry_heading + turn * self.route_travelled / radius;
+        self.orient();
+        self.set_center(
+            curve_x + turn * radius * self.heading.sin(),
+            curve_y - turn * radius * self.heading.cos()
+        );
+    }
+
+    /// Follows the way round a roundabout, then carries on straight along the exit road
+    fn follow_roundabout(&mut self) {
+        let path = RoundaboutPath::for_vehicle(self);
+        if self.route_travelled >= path.length() {
+            self.route_travelled += self.velocity;
+            self.advance(self.velocity);
+            return;
+        }
+        
+        // Keep to a comfortable speed for going round
+        self.velocity = self.velocity.min((MAX_LATERAL_ACCELERATION * ROUNDABOUT_RADIUS).sqrt());
+        self.route_travelled = (self.route_travelled + self.velocity).min(path.length());
+        
+        let ((center_x, center_y), heading) = path.pose(self.route_travelled);
+        self.heading = heading;
+        self.orient();
+        self.set_center(center_x, center_y);
+    }
+
     /// Handles movement for vehicles coming from the east
     fn update_from_east(&mut self, traffic_light: &TrafficLight) {
-        if self.x + self.velocity < WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH {
+        let stop_line = (WINDOW_WIDTH / 2 - INTERSECTION_HALF_WIDTH) as f32;
+        let front = self.x + self.width as f32;
+
+        if self.routed {
+            // Through the stop line, follow the route
+            self.follow_route();
+        } else if front + self.velocity < stop_line {
             // Regular movement before intersection
             self.x += self.velocity;
-        } else if self.x + self.velocity < WINDOW_WIDTH / 2 - VEHICLE_WIDTH {
+        } else if front + self.velocity < stop_line + (self.width / 2) as f32 {
             // Approaching intersection, check traffic light
             if traffic_light.state == LightState::Green {
                 self.x += self.velocity;
             } else {
                 // Stop at the light
-                self.x = WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH;
+                self.x = stop_line - self.width as f32;
+                self.stop();
             }
         } else {
-            // In intersection, route based on direction
-            match self.direction {
-                Direction::Left => {
-                    // Turn left (north)
-                    self.x = WINDOW_WIDTH / 2;
-                    self.y -= self.velocity;
-                },
-                Direction::Right => {
-                    // Turn right (south)
-                    self.x = WINDOW_WIDTH / 2 - VEHICLE_WIDTH;
-                    self.y += self.velocity;
-                },
-                Direction::Straight => {
-                    // Continue west
-                    self.x += self.velocity;
-                },
-            }
+            // Entering the intersection
+            self.follow_route();
         }
     }
-    
+
     /// Handles movement for vehicles coming from the north
     fn update_from_north(&mut self, traffic_light: &TrafficLight) {
-        if self.y + self.velocity < WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT {
+        let stop_line = (WINDOW_HEIGHT / 2 - INTERSECTION_HALF_WIDTH) as f32;
+        let front = self.y + self.height as f32;
+
+        if self.routed {
+            // Through the stop line, follow the route
+            self.follow_route();
+        } else if front + self.velocity < stop_line {
             // Regular movement before intersection
             self.y += self.velocity;
-        } else if self.y + self.velocity < WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT {
+        } else if front + self.velocity < stop_line + (self.height / 2) as f32 {
             // Approaching intersection, check traffic light
             if traffic_light.state == LightState::Green {
                 self.y += self.velocity;
             } else {
                 // Stop at the light
-                self.y = WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT;
+                self.y = stop_line - self.height as f32;
+                self.stop();
             }
         } else {
-            // In intersection, route based on direction
-            match self.direction {
-                Direction::Left => {
-                    // Turn left (east)
-                    self.y = WINDOW_HEIGHT / 2;
-                    self.x += self.velocity;
-                },
-                Direction::Right => {
-                    // Turn right (west)
-                    self.y = WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT;
-                    self.x -= self.velocity;
-                },
-                Direction::Straight => {
-                    // Continue south
-                    self.y += self.velocity;
-                },
-            }
+            // Entering the intersection
+            self.follow_route();
         }
     }
-    
+
     /// Handles movement for vehicles coming from the west
     fn update_from_west(&mut self, traffic_light: &TrafficLight) {
-        if self.x - self.velocity > WINDOW_WIDTH / 2 + 2 * VEHICLE_WIDTH {
+        let stop_line = (WINDOW_WIDTH / 2 + INTERSECTION_HALF_WIDTH) as f32;
+        let front = self.x;
+
+        if self.routed {
+            // Through the stop line, follow the route
+            self.follow_route();
+        } else if front - self.velocity > stop_line {
             // Regular movement before intersection
             self.x -= self.velocity;
-        } else if self.x - self.velocity > WINDOW_WIDTH / 2 {
+        } else if front - self.velocity > stop_line - (self.width / 2) as f32 {
             // Approaching intersection, check traffic light
             if traffic_light.state == LightState::Green {
                 self.x -= self.velocity;
             } else {
                 // Stop at the light
-                self.x = WINDOW_WIDTH / 2 + VEHICLE_WIDTH;
+                self.x = stop_line;
+                self.stop();
             }
         } else {
-            // In intersection, route based on direction
-            match self.direction {
-                Direction::Left => {
-                    // Turn left (south)
-                    self.x = WINDOW_WIDTH / 2 - VEHICLE_WIDTH;
-                    self.y += self.velocity;
-                },
-                Direction::Right => {
-                    // Turn right (north)
-                    self.x = WINDOW_WIDTH / 2;
-                    self.y -= self.velocity;
-                },
-                Direction::Straight => {
-                    // Continue east
-                    self.x -= self.velocity;
-                },
-            }
+            // Entering the intersection
+            self.follow_route();
         }
     }
-    
+
     /// Handles movement for vehicles coming from the south
     fn update_from_south(&mut self, traffic_light: &TrafficLight) {
-        if self.y - self.velocity > WINDOW_HEIGHT / 2 + 2 * VEHICLE_HEIGHT {
+        let stop_line = (WINDOW_HEIGHT / 2 + INTERSECTION_HALF_WIDTH) as f32;
+        let front = self.y;
+
+        if self.routed {
+            // Through the stop line, follow the route
+            self.follow_route();
+        } else if front - self.velocity > stop_line {
             // Regular movement before intersection
             self.y -= self.velocity;
-        } else if self.y - self.velocity > WINDOW_HEIGHT / 2 {
+        } else if front - self.velocity > stop_line - (self.height / 2) as f32 {
             // Approaching intersection, check traffic light
             if traffic_light.state == LightState::Green {
                 self.y -= self.velocity;
             } else {
                 // Stop at the light
-                self.y = WINDOW_HEIGHT / 2 + VEHICLE_HEIGHT;
+                self.y = stop_line;
+                self.stop();
             }
         } else {
-            // In intersection, route based on direction
-            match self.direction {
-                Direction::Left => {
-                    // Turn left (west)
-                    self.y = WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT;
-                    self.x -= self.velocity;
-                },
-                Direction::Right => {
-                    // Turn right (east)
-                    self.y = WINDOW_HEIGHT / 2;
-                    self.x += self.velocity;
-                },
-                Direction::Straight => {
-                    // Continue north
-                    self.y -= self.velocity;
-                },
-            }
+            // Entering the intersection
+            self.follow_route();
         }
     }
-}
\ No newline at end of file
+}
@@ -4,18 +4,67 @@ extern crate sdl2;
 mod config;
 mod types;
 mod entities;
+mod font;
 
-use entities::{Intersection, Vehicle};
+use entities::{Intersection, Network, Vehicle};
+use entities::pedestrian::{crosswalk_rect, PEDESTRIAN_RADIUS};
+use entities::recording::{Playback, Recorder, Recording};
+use entities::safety::ViolationKind;
+use entities::surrogate::Distribution;
+use entities::trends::{TrendSample, Trends};
 use config::*;
-use types::{Side, LightState};
+use types::{Control, Direction, LaneUse, Layout, LightState, PedestrianSignal, Side, VehicleClass};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{Canvas, RenderTarget};
+use sdl2::surface::{Surface, SurfaceRef};
 use std::time::Duration;
 
+/// Key bindings listed on screen while simulating
+const SIMULATION_KEYS: [&str; 8] = [
+    "ARROWS: SPAWN A VEHICLE",
+    "R RANDOM  E EMERGENCY",
+    "I CONTROL  T BUS PRIORITY",
+    "D DEMAND  C COORDINATION",
+    "P PEDESTRIANS  B BIKE BOXES",
+    "O TRIP  TAB NEXT INTERSECTION",
+    "S SAVE  L RESTORE",
+    "G CHARTS  ESC QUIT",
+];
+
+/// Key bindings listed on screen while replaying a recording
+const REPLAY_KEYS: [&str; 4] = [
+    "SPACE PAUSE",
+    "LEFT/RIGHT SEEK",
+    "UP/DOWN SPEED",
+    "TAB NEXT INTERSECTION  ESC QUIT",
+];
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    
+    // Render frames to image files without a window, if asked to
+    if args.iter().any(|arg| arg == "--headless") {
+        let Some(directory) = argument(&args, "--headless") else {
+            eprintln!("--headless needs the directory to write the frames to");
+            return;
+        };
+        let Some(network) = start_network(&args) else {
+            return;
+        };
+        let frames = argument(&args, "--frames").and_then(|frames| frames.parse().ok()).unwrap_or(HEADLESS_FRAMES);
+        let interval = argument(&args, "--every").and_then(|interval| interval.parse().ok()).unwrap_or(HEADLESS_FRAME_INTERVAL);
+        let selected = argument(&args, "--intersection").and_then(|index| index.parse().ok()).unwrap_or(0);
+        match render_headless(network, &directory, frames, interval.max(1), selected) {
+            Ok(written) => println!("{} frames written to {}", written, directory),
+            Err(error) => eprintln!("Could not render the frames to {}: {}", directory, error),
+        }
+        return;
+    }
+    
     // Initialize SDL
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -31,8 +80,30 @@ fn main() {
     let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    // Create intersection
-    let mut intersection = Intersection::new();
+    // Replay a recorded run instead of simulating, if asked to
+    if let Some(path) = argument(&args, "--replay") {
+        match Recording::load(&path) {
+            Ok(recording) => replay(&mut canvas, &mut event_pump, Playback::new(recording)),
+            Err(error) => eprintln!("Could not read the recording {}: {}", path, error),
+        }
+        return;
+    }
+
+    // Create the road network, showing one intersection at a time
+    let Some(mut network) = start_network(&args) else {
+        return;
+    };
+    let mut selected = 0;
+    let mut show_charts = CHARTS_VISIBLE;
+    
+    // Record the run, if asked to
+    let mut recorder = argument(&args, "--record").and_then(|path| match Recorder::create(&path, &network) {
+        Ok(recorder) => Some(recorder),
+        Err(error) => {
+            eprintln!("Could not record to {}: {}", path, error);
+            None
+        },
+    });
     
     // Track last key press time to prevent spamming
     let mut last_key_press = std::time::Instant::now();
@@ -56,12 +127,86 @@ fn main() {
                         continue; // Prevent key spamming
                     }
                     
+                    let intersection = &mut network.intersections[selected];
                     let spawn_successful = match keycode {
                         Keycode::Left => intersection.spawn_vehicle_from_east(),
                         Keycode::Right => intersection.spawn_vehicle_from_west(),
                         Keycode::Up => intersection.spawn_vehicle_from_south(),
                         Keycode::Down => intersection.spawn_vehicle_from_north(),
                         Keycode::R => intersection.spawn_vehicle_random(),
+                        Keycode::E => intersection.spawn_emergency_vehicle(),
+                        Keycode::T => {
+                            let priority = &mut intersection.transit_priority;
+                            priority.enabled = !priority.enabled;
+                            true
+                        },
+                        Keycode::D => {
+                            let demand = &mut intersection.demand;
+                            demand.enabled = !demand.enabled;
+                            true
+                        },
+                        Keycode::C => {
+                            let coordinated = network.is_coordinated();
+                            network.set_coordinated(!coordinated);
+                            true
+                        },
+                        Keycode::I => {
+                            let control = match intersection.control {
+                                Control::Signals => Control::Roundabout,
+                                Control::Roundabout => Control::AllWayStop,
+                                Control::AllWayStop => Control::TwoWayStop(intersection.layout.through_road()),
+                                Control::TwoWayStop(_) => Control::Reservation,
+                                Control::Reservation => Control::Signals,
+                            };
+                            intersection.set_control(control);
+                            true
+                        },
+                        Keycode::P => {
+                            let pedestrians = &mut intersection.pedestrians;
+                            pedestrians.enabled = !pedestrians.enabled;
+                            true
+                        },
+                        Keycode::B => {
+                            let cyclists = &mut intersection.cyclists;
+                            cyclists.bike_boxes = !cyclists.bike_boxes;
+                            true
+                        },
+                        Keycode::O => network.spawn_trip(),
+                        Keycode::G => {
+                            show_charts = !show_charts;
+                            true
+                        },
+                        Keycode::S => match network.save(SNAPSHOT_PATH) {
+                            Ok(()) => {
+                                println!("Snapshot saved to {} at frame {}", SNAPSHOT_PATH, network.simulation_time);
+                                true
+                            },
+                            Err(error) => {
+                                eprintln!("Could not save the snapshot: {}", error);
+                                false
+                            },
+                        },
+                        // Going back in time would leave the recording out of step with the run
+                        Keycode::L if recorder.is_some() => {
+                            eprintln!("Cannot restore a snapshot while recording");
+                            false
+                        },
+                        Keycode::L => match Network::load(SNAPSHOT_PATH) {
+                            Ok(snapshot) => {
+                                network = snapshot;
+                                selected = selected.min(network.intersections.len() - 1);
+                                println!("Snapshot restored from {} at frame {}", SNAPSHOT_PATH, network.simulation_time);
+                                true
+                            },
+                            Err(error) => {
+                                eprintln!("Could not restore the snapshot: {}", error);
+                                false
+                            },
+                        },
+                        Keycode::Tab => {
+                            selected = (selected + 1) % network.intersections.len();
+                            true
+                        },
                         _ => false,
                     };
                     
@@ -77,20 +222,23 @@ fn main() {
         canvas.set_draw_color(BACKGROUND_COLOR);
         canvas.clear();
 
-        // Draw roads
-        draw_roads(&mut canvas);
-        
-        // Draw traffic lights
-        draw_traffic_lights(&mut canvas, &intersection);
-        
-        // Draw vehicles
-        draw_vehicles(&mut canvas, &intersection);
-        
-        // Draw UI info
-        draw_ui_info(&mut canvas, &intersection);
+        // Draw the intersection on screen
+        draw_scene(&mut canvas, &network.intersections[selected], &SIMULATION_KEYS);
+        draw_network_info(&mut canvas, network.intersections.len(), selected);
+        if show_charts {
+            draw_charts(&mut canvas, &network.intersections[selected].trends);
+        }
 
         // Update simulation
-        intersection.update();
+        network.update();
+        
+        // Write the frame to the recording, giving up on it if the file cannot be written
+        if let Some(writer) = &mut recorder {
+            if let Err(error) = writer.record(&network) {
+                eprintln!("Could not record to {}: {}", writer.path, error);
+                recorder = None;
+            }
+        }
 
         // Present canvas
         canvas.present();
@@ -98,29 +246,446 @@ fn main() {
         // Control frame rate
         std::thread::sleep(Duration::from_millis(1000 / FPS));
     }
+    
+    if let Some(recorder) = recorder {
+        let path = recorder.path.clone();
+        match recorder.finish() {
+            Ok(()) => println!("Run recorded to {}", path),
+            Err(error) => eprintln!("Could not record to {}: {}", path, error),
+        }
+    }
+    
+    for (index, intersection) in network.intersections.iter().enumerate() {
+        println!("Intersection {}:", index);
+        print_summary(intersection);
+    }
+    
+    let trips = &network.trips;
+    if trips.started > 0 {
+        println!(
+            "Network trips: {} started, {} completed in {} on average, {} left the network off their route",
+            trips.started,
+            trips.completed,
+            trips.average_travel_time().map_or("-".to_string(), |average| format!("{:.0} frames", average)),
+            trips.strayed
+        );
+    }
+    
+    if network.corridor.is_some() {
+        match network.time_space.export(TIME_SPACE_SIGNALS_PATH, TIME_SPACE_TRAJECTORIES_PATH) {
+            Ok(()) => println!(
+                "Time-space diagram written to {} and {}",
+                TIME_SPACE_SIGNALS_PATH,
+                TIME_SPACE_TRAJECTORIES_PATH
+            ),
+            Err(error) => eprintln!("Could not write the time-space diagram: {}", error),
+        }
+    }
+}
+
+/// Value given after a command-line flag, as in `--record run.jsonl`
+///
+/// Returns `None` if the flag is missing or is followed by another flag instead of a value.
+fn argument(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .filter(|value| !value.starts_with("--"))
+        .cloned()
+}
+
+/// The network set up in the configuration, or restored from the snapshot given with `--restore`
+///
+/// Returns `None` if the snapshot cannot be read.
+fn start_network(args: &[String]) -> Option<Network> {
+    match argument(args, "--restore") {
+        Some(path) => match Network::load(&path) {
+            Ok(network) => Some(network),
+            Err(error) => {
+                eprintln!("Could not restore the snapshot {}: {}", path, error);
+                None
+            },
+        },
+        None => Some(Network::configured()),
+    }
+}
+
+/// Runs the simulation for the given number of frames without a window, writing
+/// every `interval`th frame of the selected intersection to a numbered PNG file
+///
+/// Frames are drawn by the same code as on screen, onto a software surface, and
+/// numbered from 0 so the sequence can be turned into a GIF or video. Returns the
+/// number of frames written.
+fn render_headless(
+    mut network: Network,
+    directory: &str,
+    frames: u32,
+    interval: u32,
+    selected: usize
+) -> Result<usize, String> {
+    let intersections = network.intersections.len();
+    if selected >= intersections {
+        return Err(format!("the network has {} intersections", intersections));
+    }
+    std::fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+    
+    let surface = Surface::new(WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32, PixelFormatEnum::RGB24)?;
+    let mut canvas = surface.into_canvas()?;
+    let mut written = 0;
+    for frame in 0..frames {
+        if frame % interval == 0 {
+            canvas.set_draw_color(BACKGROUND_COLOR);
+            canvas.clear();
+            draw_scene(&mut canvas, &network.intersections[selected], &[]);
+            draw_network_info(&mut canvas, intersections, selected);
+            if CHARTS_VISIBLE {
+                draw_charts(&mut canvas, &network.intersections[selected].trends);
+            }
+            
+            // Presenting carries out the drawing queued up by the renderer
+            canvas.present();
+            save_png(canvas.surface(), &format!("{}/frame_{:06}.png", directory, written))?;
+            written += 1;
+        }
+        network.update();
+    }
+    Ok(written)
+}
+
+/// Writes a surface with 24-bit RGB pixels to a PNG file
+fn save_png(surface: &SurfaceRef, path: &str) -> Result<(), String> {
+    let (width, height) = surface.size();
+    let pitch = surface.pitch() as usize;
+    let row = width as usize * 3;
+    let mut data = Vec::with_capacity(row * height as usize);
+    surface.with_lock(|pixels| {
+        for y in 0..height as usize {
+            data.extend_from_slice(&pixels[y * pitch..y * pitch + row]);
+        }
+    });
+    
+    let file = std::io::BufWriter::new(std::fs::File::create(path).map_err(|error| error.to_string())?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|error| error.to_string())?;
+    writer.write_image_data(&data).map_err(|error| error.to_string())
+}
+
+/// Plays back a recorded run, with pause, seek and speed control
+fn replay(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    event_pump: &mut sdl2::EventPump,
+    mut playback: Playback
+) {
+    let mut selected = 0;
+    let intersections = playback.recording.layouts.len().max(1);
+    
+    'replaying: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | 
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    break 'replaying;
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } => match keycode {
+                    Keycode::Space => playback.paused = !playback.paused,
+                    Keycode::Left => playback.seek(-(REPLAY_SEEK_TICKS as i64)),
+                    Keycode::Right => playback.seek(REPLAY_SEEK_TICKS as i64),
+                    Keycode::Up => playback.faster(),
+                    Keycode::Down => playback.slower(),
+                    Keycode::Tab => selected = (selected + 1) % intersections,
+                    _ => {},
+                },
+                _ => {},
+            }
+        }
+        
+        canvas.set_draw_color(BACKGROUND_COLOR);
+        canvas.clear();
+        
+        // Draw the intersection as it was in the frame being played
+        draw_scene(canvas, playback.intersection(selected), &REPLAY_KEYS);
+        draw_network_info(canvas, intersections, selected);
+        draw_replay_progress(canvas, &playback);
+        
+        playback.advance();
+        
+        canvas.present();
+        std::thread::sleep(Duration::from_millis(1000 / FPS));
+    }
+}
+
+/// Draws a bar along the top of the screen showing how far the replay has got
+///
+/// The bar is drawn yellow while the replay is paused.
+fn draw_replay_progress<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    playback: &Playback
+) {
+    let height = 6;
+    canvas.set_draw_color(ROAD_COLOR);
+    canvas.fill_rect(Rect::new(0, 0, WINDOW_WIDTH as u32, height)).unwrap();
+    
+    let played = (playback.fraction() * WINDOW_WIDTH as f32) as u32;
+    canvas.set_draw_color(if playback.paused { LIGHT_YELLOW } else { ROAD_MARKING_COLOR });
+    if played > 0 {
+        canvas.fill_rect(Rect::new(0, 0, played, height)).unwrap();
+    }
+}
+
+/// Prints the metrics gathered during the run
+fn print_summary(intersection: &Intersection) {
+    let (waiting, passing, _passed, total, time) = intersection.stats();
+    println!("Simulation time: {} frames", time);
+    println!("Vehicles processed: {} ({} waiting, {} in intersection)", total, waiting, passing);
+    
+    for (label, delay) in [("Bus", &intersection.bus_delay), ("General traffic", &intersection.general_delay)] {
+        if let Some(average) = delay.average() {
+            println!("{} delay: {:.1} frames per vehicle over {} vehicles", label, average, delay.vehicles);
+        }
+    }
+    
+    let demand = &intersection.demand;
+    println!(
+        "Origin-destination demand {}: {} vehicles generated, {} waiting to enter",
+        if demand.enabled { "on" } else { "off" },
+        demand.generated,
+        demand.backlog()
+    );
+    
+    match intersection.control {
+        Control::Signals => println!("Control: signals"),
+        Control::Roundabout => println!("Control: roundabout, {} vehicles entered", intersection.roundabout.entries),
+        Control::AllWayStop => println!("Control: all-way stop, {} vehicles entered after stopping", intersection.stop_control.entries),
+        Control::TwoWayStop(major) => println!(
+            "Control: two-way stop with the major road through {:?}, {} vehicles entered after stopping",
+            major,
+            intersection.stop_control.entries
+        ),
+        Control::Reservation => println!(
+            "Control: reservations, {} granted, {} requests turned down, {} tiles in conflict",
+            intersection.reservations.granted,
+            intersection.reservations.rejected,
+            intersection.reservations.conflicts
+        ),
+    }
+    
+    let compliance = &intersection.compliance;
+    if intersection.control == Control::Signals {
+        println!(
+            "Driver compliance {}: {} entered on yellow, {} on red, {} stopped for a yellow, {} caught in the dilemma zone",
+            if compliance.enabled { "modelled" } else { "perfect" },
+            compliance.yellow_entries,
+            compliance.red_entries,
+            compliance.yellow_stops,
+            compliance.dilemma_zone_entries
+        );
+    }
+    
+    let discharge = &intersection.discharge;
+    if let Some(saturation) = discharge.saturation_headway() {
+        let headways: Vec<String> = discharge.mean_headways()
+            .into_iter()
+            .map(|headway| headway.map_or("-".to_string(), |headway| format!("{:.0}", headway)))
+            .collect();
+        println!(
+            "Queue discharge: headways by queue position {} frames; saturation headway {:.1} frames, start-up lost time {}",
+            headways.join(" "),
+            saturation,
+            discharge.start_up_lost_time().map_or("-".to_string(), |lost| format!("{:.1} frames", lost))
+        );
+    }
+    
+    let pedestrians = &intersection.pedestrians;
+    println!(
+        "Pedestrians {}: {} crossed, {} waiting or crossing",
+        if pedestrians.enabled { "on" } else { "off" },
+        pedestrians.crossed,
+        pedestrians.pedestrians.len()
+    );
+    if let Some(average) = pedestrians.average_delay() {
+        println!(
+            "Pedestrian delay: {:.0} frames on average; vehicles gave way for {} frames, pedestrians were held up for {} frames",
+            average,
+            pedestrians.vehicle_yield_ticks,
+            pedestrians.blocked_ticks
+        );
+    }
+    
+    let cyclists = &intersection.cyclists;
+    println!(
+        "Cyclists {}: {} rode through, {} on the road; bike boxes {}",
+        if cyclists.enabled { "on" } else { "off" },
+        cyclists.crossed,
+        cyclists.cyclists.len(),
+        if cyclists.bike_boxes { "on" } else { "off" }
+    );
+    if let Some(average) = cyclists.average_delay() {
+        println!(
+            "Cyclist delay: {:.0} frames on average; {} right-hook conflicts, vehicles gave way for {} frames",
+            average,
+            cyclists.right_hooks,
+            cyclists.vehicle_yield_ticks
+        );
+    }
+    
+    let safety = &intersection.safety;
+    if safety.enabled {
+        println!(
+            "Safety violations: {} overlaps, {} conflicting movements",
+            safety.count(ViolationKind::Overlap),
+            safety.count(ViolationKind::ConflictingMovements)
+        );
+        for violation in safety.violations.iter().take(SAFETY_REPORT_LIMIT) {
+            let ((x, y), (other_x, other_y)) = violation.positions;
+            println!(
+                "  frame {}: {:?} between vehicles {} at ({:.0}, {:.0}) and {} at ({:.0}, {:.0})",
+                violation.time,
+                violation.kind,
+                violation.vehicles.0,
+                x,
+                y,
+                violation.vehicles.1,
+                other_x,
+                other_y
+            );
+        }
+    }
+    
+    let surrogates = &intersection.surrogates;
+    if surrogates.enabled {
+        println!(
+            "Surrogate safety measures (conflicts below {:.0} frames TTC and {} frames PET):",
+            surrogates.ttc_threshold,
+            surrogates.pet_threshold
+        );
+        for side in intersection.layout.legs() {
+            let describe = |distribution: Option<Distribution>| match distribution {
+                Some(distribution) => format!(
+                    "{} conflicts (min {:.0}, 15th percentile {:.0}, median {:.0}, mean {:.1} frames)",
+                    distribution.count,
+                    distribution.min,
+                    distribution.p15,
+                    distribution.median,
+                    distribution.mean
+                ),
+                None => "no conflicts".to_string(),
+            };
+            println!(
+                "  {:?}: TTC {}; PET {}; {} hard braking events",
+                side,
+                describe(surrogates.ttc(side)),
+                describe(surrogates.pet(side)),
+                surrogates.hard_braking(side)
+            );
+        }
+    }
+    
+    if let Some(plan) = &intersection.signal_plan {
+        println!("Fixed-time plan: {} frame cycle, offset {} frames", plan.cycle(), plan.offset);
+    }
+    
+    let lane_change = &intersection.lane_change;
+    println!(
+        "Lane changes: {} to reach a turn lane, {} to pass slower vehicles, {} missed turns",
+        lane_change.mandatory_changes,
+        lane_change.discretionary_changes,
+        lane_change.missed_turns
+    );
+    
+    let priority = &intersection.transit_priority;
+    println!(
+        "Transit signal priority {}: {} green extensions, {} early greens",
+        if priority.enabled { "on" } else { "off" },
+        priority.extensions_granted,
+        priority.early_greens_granted
+    );
+    
+    let preemption = &intersection.preemption_stats;
+    if preemption.events > 0 {
+        println!(
+            "Preemptions: {} ({} frames preempted)",
+            preemption.events,
+            preemption.preempted_ticks
+        );
+        match preemption.average_recovery_ticks() {
+            Some(average) => println!(
+                "Recovery after preemption: {:.0} frames on average, {} frames last time",
+                average,
+                preemption.last_recovery_ticks.unwrap_or(0)
+            ),
+            None => println!("Recovery after preemption: not yet recovered"),
+        }
+    }
+}
+
+/// Draws an intersection with its control, crosswalks, road users and HUD
+fn draw_scene<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection,
+    bindings: &[&str]
+) {
+    // Draw roads
+    draw_roads(canvas, intersection.layout);
+    
+    // Draw the traffic lights, the central island of a roundabout or the stop signs
+    match intersection.control {
+        Control::Signals => draw_traffic_lights(canvas, intersection),
+        Control::Roundabout => draw_roundabout(canvas),
+        Control::AllWayStop | Control::TwoWayStop(_) => draw_stop_signs(canvas, intersection),
+        Control::Reservation => draw_reservations(canvas, intersection),
+    }
+    
+    // Draw the crosswalks and their pedestrian signals, and the bike boxes
+    draw_crosswalks(canvas, intersection);
+    draw_bike_boxes(canvas, intersection);
+    
+    // Draw vehicles, pedestrians and cyclists
+    draw_vehicles(canvas, intersection);
+    draw_pedestrians(canvas, intersection);
+    draw_cyclists(canvas, intersection);
+    
+    // Draw UI info
+    draw_ui_info(canvas, intersection, bindings);
 }
 
 /// Draws the road layout
-fn draw_roads(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
+fn draw_roads<T: RenderTarget>(canvas: &mut Canvas<T>, layout: Layout) {
+    let half_width = INTERSECTION_HALF_WIDTH;
+    
     // Draw background road area
     canvas.set_draw_color(ROAD_COLOR);
     
     // Horizontal road
     canvas.fill_rect(Rect::new(
         0, 
-        WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT * 2, 
+        WINDOW_HEIGHT / 2 - half_width, 
         WINDOW_WIDTH as u32, 
-        VEHICLE_HEIGHT as u32 * 4
+        half_width as u32 * 2
     )).unwrap();
     
     // Vertical road
     canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH / 2 - VEHICLE_WIDTH * 2, 
+        WINDOW_WIDTH / 2 - half_width, 
         0, 
-        VEHICLE_WIDTH as u32 * 4, 
+        half_width as u32 * 2, 
         WINDOW_HEIGHT as u32
     )).unwrap();
     
+    // Bike lanes along the shoulders, up to the intersection
+    canvas.set_draw_color(BIKE_LANE_COLOR);
+    let shoulder = LANE_WIDTH as u32;
+    let (road_length_x, road_length_y) = ((WINDOW_WIDTH / 2 - half_width) as u32, (WINDOW_HEIGHT / 2 - half_width) as u32);
+    for y in [WINDOW_HEIGHT / 2 - half_width, WINDOW_HEIGHT / 2 + APPROACH_WIDTH] {
+        canvas.fill_rect(Rect::new(0, y, road_length_x, shoulder)).unwrap();
+        canvas.fill_rect(Rect::new(WINDOW_WIDTH / 2 + half_width, y, road_length_x, shoulder)).unwrap();
+    }
+    for x in [WINDOW_WIDTH / 2 - half_width, WINDOW_WIDTH / 2 + APPROACH_WIDTH] {
+        canvas.fill_rect(Rect::new(x, 0, shoulder, road_length_y)).unwrap();
+        canvas.fill_rect(Rect::new(x, WINDOW_HEIGHT / 2 + half_width, shoulder, road_length_y)).unwrap();
+    }
+    
     // Draw road markings
     canvas.set_draw_color(ROAD_MARKING_COLOR);
     
@@ -154,240 +719,722 @@ fn draw_roads(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
         x_pos += dash_length + gap_length;
     }
     
+    // Lines between the lanes of each approach, stopping at the intersection
+    for lane in 1..=LANES_PER_APPROACH as i32 {
+        for offset in [-lane * LANE_WIDTH, lane * LANE_WIDTH] {
+            let mut position = 0;
+            while position < WINDOW_WIDTH.max(WINDOW_HEIGHT) {
+                let outside_x = position + dash_length < WINDOW_WIDTH / 2 - half_width || 
+                                position > WINDOW_WIDTH / 2 + half_width;
+                let outside_y = position + dash_length < WINDOW_HEIGHT / 2 - half_width || 
+                                position > WINDOW_HEIGHT / 2 + half_width;
+                
+                // The outermost line marks off the shoulder and is solid
+                let length = if lane == LANES_PER_APPROACH as i32 {
+                    dash_length + gap_length
+                } else {
+                    dash_length / 2
+                };
+                if outside_x {
+                    canvas.fill_rect(Rect::new(position, WINDOW_HEIGHT / 2 + offset, length as u32, 1)).unwrap();
+                }
+                if outside_y {
+                    canvas.fill_rect(Rect::new(WINDOW_WIDTH / 2 + offset, position, 1, length as u32)).unwrap();
+                }
+                
+                position += dash_length + gap_length;
+            }
+        }
+    }
+    
+    // Clear away the road on the side a three-leg intersection has no leg
+    if let Layout::ThreeLeg(missing) = layout {
+        let length = (WINDOW_WIDTH / 2 - half_width) as u32;
+        let across = half_width as u32 * 2;
+        let arm = match missing {
+            Side::FromEast => Rect::new(0, WINDOW_HEIGHT / 2 - half_width, length, across),
+            Side::FromWest => Rect::new(WINDOW_WIDTH / 2 + half_width, WINDOW_HEIGHT / 2 - half_width, length, across),
+            Side::FromNorth => Rect::new(WINDOW_WIDTH / 2 - half_width, 0, across, length),
+            Side::FromSouth => Rect::new(WINDOW_WIDTH / 2 - half_width, WINDOW_HEIGHT / 2 + half_width, across, length),
+        };
+        canvas.set_draw_color(BACKGROUND_COLOR);
+        canvas.fill_rect(arm).unwrap();
+        canvas.set_draw_color(ROAD_MARKING_COLOR);
+    }
+    
+    // Arrows showing the movements allowed from each lane
+    for side in layout.legs() {
+        for lane in 0..LANES_PER_APPROACH {
+            draw_lane_arrow(canvas, side, lane, layout.lane_use(side, lane));
+        }
+    }
+    
     // Draw intersection boundary
     canvas.draw_rect(Rect::new(
-        WINDOW_WIDTH / 2 - VEHICLE_WIDTH * 2,
-        WINDOW_HEIGHT / 2 - VEHICLE_HEIGHT * 2,
-        VEHICLE_WIDTH as u32 * 4,
-        VEHICLE_HEIGHT as u32 * 4
+        WINDOW_WIDTH / 2 - half_width,
+        WINDOW_HEIGHT / 2 - half_width,
+        half_width as u32 * 2,
+        half_width as u32 * 2
     )).unwrap();
 }
 
+/// Draws the central island of a roundabout and the edges of its circulating lane
+fn draw_roundabout<T: RenderTarget>(canvas: &mut Canvas<T>) {
+    let center_x = WINDOW_WIDTH / 2;
+    let center_y = WINDOW_HEIGHT / 2;
+    
+    // Fill the island row by row
+    let radius = ROUNDABOUT_ISLAND_RADIUS;
+    canvas.set_draw_color(BACKGROUND_COLOR);
+    for dy in -radius..=radius {
+        let dx = ((radius * radius - dy * dy) as f32).sqrt().round() as i32;
+        canvas.draw_line(
+            Point::new(center_x - dx, center_y + dy),
+            Point::new(center_x + dx, center_y + dy)
+        ).unwrap();
+    }
+    
+    // Dashed edges on either side of the circulating lane
+    canvas.set_draw_color(ROAD_MARKING_COLOR);
+    let dashes = 24;
+    for edge in [ROUNDABOUT_RADIUS - LANE_WIDTH as f32 / 2.0, ROUNDABOUT_RADIUS + LANE_WIDTH as f32 / 2.0] {
+        for dash in 0..dashes {
+            let start = dash as f32 / dashes as f32 * std::f32::consts::TAU;
+            let end = start + std::f32::consts::PI / dashes as f32;
+            let point = |angle: f32| Point::new(
+                center_x + (edge * angle.cos()).round() as i32,
+                center_y + (edge * angle.sin()).round() as i32
+            );
+            canvas.draw_line(point(start), point(end)).unwrap();
+        }
+    }
+}
+
+/// Draws the arrow painted on a lane shortly before its stop line
+fn draw_lane_arrow<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    side: Side,
+    lane: usize,
+    lane_use: LaneUse
+) {
+    let heading = side.heading();
+    let (forward_x, forward_y) = (heading.cos(), heading.sin());
+    let (right_x, right_y) = (-forward_y, forward_x);
+    
+    // Tip of the arrow, in the middle of the lane a short way before the stop line
+    let across = (lane as f32 + 0.5) * LANE_WIDTH as f32;
+    let before = INTERSECTION_HALF_WIDTH as f32 + LANE_WIDTH as f32;
+    let point = |along: f32, sideways: f32| {
+        let along = -before + along;
+        let sideways = across + sideways;
+        Point::new(
+            WINDOW_WIDTH / 2 + (along * forward_x + sideways * right_x).round() as i32,
+            WINDOW_HEIGHT / 2 + (along * forward_y + sideways * right_y).round() as i32
+        )
+    };
+    
+    canvas.draw_line(point(-20.0, 0.0), point(0.0, 0.0)).unwrap();
+    for direction in [Direction::Left, Direction::Straight, Direction::Right] {
+        if !lane_use.allows(direction) {
+            continue;
+        }
+        let (tip, wing) = match direction {
+            Direction::Straight => (point(0.0, 0.0), [point(-4.0, -3.0), point(-4.0, 3.0)]),
+            Direction::Left => (point(-6.0, -6.0), [point(-9.0, -3.0), point(-3.0, -3.0)]),
+            Direction::Right => (point(-6.0, 6.0), [point(-9.0, 3.0), point(-3.0, 3.0)]),
+        };
+        if direction != Direction::Straight {
+            canvas.draw_line(point(-6.0, 0.0), tip).unwrap();
+        }
+        canvas.draw_line(tip, wing[0]).unwrap();
+        canvas.draw_line(tip, wing[1]).unwrap();
+    }
+}
+
 /// Draws the traffic lights
-fn draw_traffic_lights(
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+fn draw_traffic_lights<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
     intersection: &Intersection
 ) {
+    // Lights sit in the corners of the intersection
+    let half_width = INTERSECTION_HALF_WIDTH;
+    
     // North light
-    if intersection.north_light.state == LightState::Green {
-        canvas.set_draw_color(LIGHT_GREEN);
-    } else {
-        canvas.set_draw_color(LIGHT_RED);
+    if intersection.layout.has_leg(Side::FromNorth) {
+        canvas.set_draw_color(light_color(intersection.north_light.state));
+        canvas.fill_rect(Rect::new(
+            WINDOW_WIDTH / 2 - half_width,
+            WINDOW_HEIGHT / 2 - half_width,
+            LANE_WIDTH as u32,
+            LANE_WIDTH as u32
+        )).unwrap();
     }
-    canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH,
-        WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT,
-        VEHICLE_WIDTH as u32,
-        VEHICLE_HEIGHT as u32
-    )).unwrap();
     
     // East light
-    if intersection.east_light.state == LightState::Green {
-        canvas.set_draw_color(LIGHT_GREEN);
-    } else {
-        canvas.set_draw_color(LIGHT_RED);
+    if intersection.layout.has_leg(Side::FromEast) {
+        canvas.set_draw_color(light_color(intersection.east_light.state));
+        canvas.fill_rect(Rect::new(
+            WINDOW_WIDTH / 2 - half_width,
+            WINDOW_HEIGHT / 2 + half_width - LANE_WIDTH,
+            LANE_WIDTH as u32,
+            LANE_WIDTH as u32
+        )).unwrap();
     }
-    canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH / 2 - 2 * VEHICLE_WIDTH,
-        WINDOW_HEIGHT / 2 + VEHICLE_HEIGHT,
-        VEHICLE_WIDTH as u32,
-        VEHICLE_HEIGHT as u32
-    )).unwrap();
     
     // South light
-    if intersection.south_light.state == LightState::Green {
-        canvas.set_draw_color(LIGHT_GREEN);
-    } else {
-        canvas.set_draw_color(LIGHT_RED);
+    if intersection.layout.has_leg(Side::FromSouth) {
+        canvas.set_draw_color(light_color(intersection.south_light.state));
+        canvas.fill_rect(Rect::new(
+            WINDOW_WIDTH / 2 + half_width - LANE_WIDTH,
+            WINDOW_HEIGHT / 2 + half_width - LANE_WIDTH,
+            LANE_WIDTH as u32,
+            LANE_WIDTH as u32
+        )).unwrap();
     }
-    canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH / 2 + VEHICLE_WIDTH,
-        WINDOW_HEIGHT / 2 + VEHICLE_HEIGHT,
-        VEHICLE_WIDTH as u32,
-        VEHICLE_HEIGHT as u32
-    )).unwrap();
     
     // West light
-    if intersection.west_light.state == LightState::Green {
-        canvas.set_draw_color(LIGHT_GREEN);
-    } else {
-        canvas.set_draw_color(LIGHT_RED);
+    if intersection.layout.has_leg(Side::FromWest) {
+        canvas.set_draw_color(light_color(intersection.west_light.state));
+        canvas.fill_rect(Rect::new(
+            WINDOW_WIDTH / 2 + half_width - LANE_WIDTH,
+            WINDOW_HEIGHT / 2 - half_width,
+            LANE_WIDTH as u32,
+            LANE_WIDTH as u32
+        )).unwrap();
+    }
+}
+
+/// Draws a stop sign in the corner where the light of each stop-controlled approach would be
+fn draw_stop_signs<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    let half_width = INTERSECTION_HALF_WIDTH;
+    let near = -half_width + LANE_WIDTH / 2;
+    let far = half_width - LANE_WIDTH / 2;
+    let radius = LANE_WIDTH as f32 / 2.0;
+    
+    for side in intersection.layout.legs() {
+        if !intersection.control.stops(side) {
+            continue;
+        }
+        let (offset_x, offset_y) = match side {
+            Side::FromNorth => (near, near),
+            Side::FromEast => (near, far),
+            Side::FromSouth => (far, far),
+            Side::FromWest => (far, near),
+        };
+        let (center_x, center_y) = ((WINDOW_WIDTH / 2 + offset_x) as f32, (WINDOW_HEIGHT / 2 + offset_y) as f32);
+        
+        // A red octagon with a white bar across
+        let octagon: Vec<(f32, f32)> = (0..8)
+            .map(|corner| {
+                let angle = (corner as f32 + 0.5) * std::f32::consts::FRAC_PI_4;
+                (center_x + radius * angle.cos(), center_y + radius * angle.sin())
+            })
+            .collect();
+        canvas.set_draw_color(Color::RGB(200, 0, 0));
+        fill_polygon(canvas, &octagon);
+        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        canvas.fill_rect(Rect::new(center_x as i32 - 6, center_y as i32 - 1, 12, 3)).unwrap();
+    }
+}
+
+/// Shades the tiles of the intersection that are reserved for the current frame
+fn draw_reservations<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    canvas.set_draw_color(Color::RGB(70, 70, 110));
+    for (left, top, size) in intersection.reservations.held_tiles(intersection.simulation_time) {
+        canvas.fill_rect(Rect::new(left, top, size as u32, size as u32)).unwrap();
+    }
+}
+
+/// Draws zebra stripes across each crosswalk, with a pedestrian signal at either end
+///
+/// The signals show white for WALK, flash orange for the clearance interval and
+/// show orange for DON'T WALK.
+fn draw_crosswalks<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    let blink_on = (intersection.simulation_time / BLINK_PERIOD_TICKS).is_multiple_of(2);
+    let stripe = 4;
+    let signal_size = 6;
+    
+    for crosswalk in &intersection.pedestrians.crosswalks {
+        let (left, top, width, height) = crosswalk_rect(crosswalk.leg);
+        let (left, top, width, height) = (left as i32, top as i32, width as i32, height as i32);
+        let across = matches!(crosswalk.leg, Side::FromEast | Side::FromWest);
+        
+        // Stripes run along the road, spaced out across it
+        canvas.set_draw_color(ROAD_MARKING_COLOR);
+        let length = if across { height } else { width };
+        for start in (0..length).step_by(2 * stripe as usize) {
+            let rect = if across {
+                Rect::new(left, top + start, width as u32, stripe as u32)
+            } else {
+                Rect::new(left + start, top, stripe as u32, height as u32)
+            };
+            canvas.fill_rect(rect).unwrap();
+        }
+        
+        let color = match crosswalk.signal {
+            PedestrianSignal::Walk => Color::RGB(255, 255, 255),
+            PedestrianSignal::FlashingDontWalk if !blink_on => continue,
+            PedestrianSignal::FlashingDontWalk | PedestrianSignal::DontWalk => Color::RGB(255, 140, 0),
+        };
+        canvas.set_draw_color(color);
+        let ends = if across {
+            [(left + (width - signal_size) / 2, top - signal_size - 2), (left + (width - signal_size) / 2, top + height + 2)]
+        } else {
+            [(left - signal_size - 2, top + (height - signal_size) / 2), (left + width + 2, top + (height - signal_size) / 2)]
+        };
+        for (x, y) in ends {
+            canvas.fill_rect(Rect::new(x, y, signal_size as u32, signal_size as u32)).unwrap();
+        }
+    }
+}
+
+/// Draws the pedestrians waiting at the kerbs and walking across
+fn draw_pedestrians<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    let size = (2.0 * PEDESTRIAN_RADIUS) as u32;
+    canvas.set_draw_color(Color::RGB(240, 200, 150));
+    for pedestrian in &intersection.pedestrians.pedestrians {
+        let (x, y) = pedestrian.position();
+        canvas.fill_rect(Rect::new((x - PEDESTRIAN_RADIUS) as i32, (y - PEDESTRIAN_RADIUS) as i32, size, size)).unwrap();
+    }
+}
+
+/// Draws the bike boxes in front of the waiting vehicles, when the lights use them
+fn draw_bike_boxes<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    if intersection.control != Control::Signals || !intersection.cyclists.bike_boxes {
+        return;
+    }
+    
+    // Each box spans the approach lanes and the bike lane, back from the stop line
+    let (center_x, center_y) = (WINDOW_WIDTH / 2, WINDOW_HEIGHT / 2);
+    let (half_width, depth) = (INTERSECTION_HALF_WIDTH, BIKE_BOX_DEPTH);
+    canvas.set_draw_color(BIKE_LANE_COLOR);
+    for side in intersection.layout.legs() {
+        let rect = match side {
+            Side::FromEast => Rect::new(center_x - half_width - depth, center_y, depth as u32, half_width as u32),
+            Side::FromWest => Rect::new(center_x + half_width, center_y - half_width, depth as u32, half_width as u32),
+            Side::FromNorth => Rect::new(center_x - half_width, center_y - half_width - depth, half_width as u32, depth as u32),
+            Side::FromSouth => Rect::new(center_x, center_y + half_width, half_width as u32, depth as u32),
+        };
+        canvas.fill_rect(rect).unwrap();
+    }
+}
+
+/// Draws the cyclists as small bars along their heading
+fn draw_cyclists<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection
+) {
+    let half_length = CYCLIST_LENGTH as f32 / 2.0;
+    let half_width = CYCLIST_WIDTH as f32 / 2.0;
+    canvas.set_draw_color(CYCLIST_COLOR);
+    for cyclist in &intersection.cyclists.cyclists {
+        let ((center_x, center_y), heading) = cyclist.pose();
+        let (forward_x, forward_y) = (heading.cos(), heading.sin());
+        let (right_x, right_y) = (-forward_y, forward_x);
+        let corner = |along: f32, across: f32| {
+            (center_x + along * forward_x + across * right_x, center_y + along * forward_y + across * right_y)
+        };
+        fill_polygon(canvas, &[
+            corner(-half_length, -half_width),
+            corner(half_length, -half_width),
+            corner(half_length, half_width),
+            corner(-half_length, half_width),
+        ]);
+    }
+}
+
+/// Returns the color a traffic light shows in the given state
+fn light_color(state: LightState) -> Color {
+    match state {
+        LightState::Green => LIGHT_GREEN,
+        LightState::Yellow => LIGHT_YELLOW,
+        LightState::Red => LIGHT_RED,
     }
-    canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH / 2 + VEHICLE_WIDTH,
-        WINDOW_HEIGHT / 2 - 2 * VEHICLE_HEIGHT,
-        VEHICLE_WIDTH as u32,
-        VEHICLE_HEIGHT as u32
-    )).unwrap();
 }
 
 /// Draws all vehicles
-fn draw_vehicles(
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+fn draw_vehicles<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
     intersection: &Intersection
 ) {
+    let blink_on = (intersection.simulation_time / BLINK_PERIOD_TICKS).is_multiple_of(2);
+    
     // Draw vehicles that have passed through intersection
     for vehicle in &intersection.vehicles_passed_east {
-        draw_vehicle(canvas, vehicle);
+        draw_vehicle(canvas, vehicle, blink_on);
     }
     for vehicle in &intersection.vehicles_passed_west {
-        draw_vehicle(canvas, vehicle);
+        draw_vehicle(canvas, vehicle, blink_on);
     }
     for vehicle in &intersection.vehicles_passed_north {
-        draw_vehicle(canvas, vehicle);
+        draw_vehicle(canvas, vehicle, blink_on);
     }
     for vehicle in &intersection.vehicles_passed_south {
-        draw_vehicle(canvas, vehicle);
+        draw_vehicle(canvas, vehicle, blink_on);
+    }
+    
+    // Draw buses in the queue jump lanes
+    for vehicle in &intersection.vehicles_queue_jump {
+        draw_vehicle(canvas, vehicle, blink_on);
     }
     
     // Draw vehicles in the intersection
     for vehicle in &intersection.vehicles_in_intersection {
-        draw_vehicle(canvas, vehicle);
+        draw_vehicle(canvas, vehicle, blink_on);
     }
     
-    // Draw vehicles waiting at lights
-    for vehicle in &intersection.vehicles_waiting_east {
-        draw_vehicle(canvas, vehicle);
+    // Draw vehicles waiting at lights, lane by lane
+    for vehicle in intersection.vehicles_waiting_east.iter().flatten() {
+        draw_vehicle(canvas, vehicle, blink_on);
     }
-    for vehicle in &intersection.vehicles_waiting_west {
-        draw_vehicle(canvas, vehicle);
+    for vehicle in intersection.vehicles_waiting_west.iter().flatten() {
+        draw_vehicle(canvas, vehicle, blink_on);
     }
-    for vehicle in &intersection.vehicles_waiting_north {
-        draw_vehicle(canvas, vehicle);
+    for vehicle in intersection.vehicles_waiting_north.iter().flatten() {
+        draw_vehicle(canvas, vehicle, blink_on);
     }
-    for vehicle in &intersection.vehicles_waiting_south {
-        draw_vehicle(canvas, vehicle);
+    for vehicle in intersection.vehicles_waiting_south.iter().flatten() {
+        draw_vehicle(canvas, vehicle, blink_on);
     }
 }
 
-/// Draws a single vehicle
-fn draw_vehicle(
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-    vehicle: &Vehicle
+/// Draws a single vehicle, rotated to its heading
+///
+/// `blink_on` selects the phase of the turn signals, which flash while a vehicle
+/// approaches and makes its turn.
+fn draw_vehicle<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    vehicle: &Vehicle,
+    blink_on: bool
 ) {
-    // Draw the vehicle body
-    canvas.set_draw_color(vehicle.color);
-    canvas.fill_rect(Rect::new(
-        vehicle.x as i32,
-        vehicle.y as i32,
-        VEHICLE_WIDTH as u32,
-        VEHICLE_HEIGHT as u32
-    )).unwrap();
-    
-    // Add simple vehicle details (windows/lights)
+    let length = vehicle.length();
+    let breadth = vehicle.breadth();
     let dark_color = Color::RGB(
         vehicle.color.r / 2,
         vehicle.color.g / 2,
         vehicle.color.b / 2
     );
     
-    canvas.set_draw_color(dark_color);
-    
-    // Draw "windows" - different patterns based on direction to help visualize
-    match vehicle.side {
-        Side::FromEast | Side::FromWest => {
-            canvas.fill_rect(Rect::new(
-                vehicle.x as i32 + 3,
-                vehicle.y as i32 + 3,
-                (VEHICLE_WIDTH - 6) as u32,
-                5
-            )).unwrap();
+    // Draw the vehicle body
+    canvas.set_draw_color(vehicle.color);
+    fill_vehicle_rect(canvas, vehicle, 0, 0, length, breadth);
+    
+    // Add class-specific details so vehicle types can be told apart
+    canvas.set_draw_color(vehicle.class.spec().accent);
+    match vehicle.class {
+        VehicleClass::Car => {
+            // Windscreen towards the front and a smaller rear window
+            canvas.set_draw_color(dark_color);
+            fill_vehicle_rect(canvas, vehicle, length - 9, 2, 4, breadth - 4);
+            fill_vehicle_rect(canvas, vehicle, 3, 3, 2, breadth - 6);
+        },
+        VehicleClass::Truck => {
+            // Cargo box at the back, cab windscreen at the front
+            fill_vehicle_rect(canvas, vehicle, 2, 2, length * 2 / 3, breadth - 4);
+            canvas.set_draw_color(dark_color);
+            fill_vehicle_rect(canvas, vehicle, length - 6, 2, 3, breadth - 4);
+        },
+        VehicleClass::Bus => {
+            // Row of windows along both sides and a wide windscreen
+            let mut offset = 3;
+            while offset + 4 < length - 6 {
+                fill_vehicle_rect(canvas, vehicle, offset, 2, 4, 3);
+                fill_vehicle_rect(canvas, vehicle, offset, breadth - 5, 4, 3);
+                offset += 6;
+            }
+            fill_vehicle_rect(canvas, vehicle, length - 4, 2, 2, breadth - 4);
+        },
+        VehicleClass::Motorcycle => {
+            // Rider in the middle of the bike
+            fill_vehicle_rect(canvas, vehicle, length / 2 - 2, breadth / 2 - 2, 4, 4);
+        },
+        VehicleClass::Emergency => {
+            // Light bar across the roof, half red and half blue
+            fill_vehicle_rect(canvas, vehicle, length / 2 - 2, 2, 4, breadth - 4);
+            canvas.set_draw_color(LIGHT_RED);
+            fill_vehicle_rect(canvas, vehicle, length / 2 - 2, 2, 4, (breadth - 4) / 2);
         },
-        Side::FromNorth | Side::FromSouth => {
-            canvas.fill_rect(Rect::new(
-                vehicle.x as i32 + 3,
-                vehicle.y as i32 + 3,
-                5,
-                (VEHICLE_HEIGHT - 6) as u32
-            )).unwrap();
+    }
+    
+    // Headlights at the front corners, tail lights at the rear
+    let lamp = 3.min(breadth / 3);
+    canvas.set_draw_color(HEADLIGHT_COLOR);
+    fill_vehicle_rect(canvas, vehicle, length - 2, 0, 2, lamp);
+    fill_vehicle_rect(canvas, vehicle, length - 2, breadth - lamp, 2, lamp);
+    canvas.set_draw_color(TAIL_LIGHT_COLOR);
+    fill_vehicle_rect(canvas, vehicle, 0, 0, 2, lamp);
+    fill_vehicle_rect(canvas, vehicle, 0, breadth - lamp, 2, lamp);
+    
+    // Turn signals on the side the vehicle is turning towards
+    if blink_on && vehicle.is_signalling() {
+        let across = match vehicle.direction {
+            Direction::Left => 0,
+            _ => breadth - lamp,
+        };
+        canvas.set_draw_color(BLINKER_COLOR);
+        fill_vehicle_rect(canvas, vehicle, length - 2, across, 2, lamp);
+        fill_vehicle_rect(canvas, vehicle, 0, across, 2, lamp);
+    }
+}
+
+/// Fills a rectangle given in the vehicle's own frame, rotated to the vehicle's heading
+///
+/// `along` is measured from the rear of the vehicle and `across` from its left side.
+fn fill_vehicle_rect<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    vehicle: &Vehicle,
+    along: i32,
+    across: i32,
+    length: i32,
+    breadth: i32
+) {
+    let (center_x, center_y) = vehicle.center();
+    let (forward_x, forward_y) = (vehicle.heading.cos(), vehicle.heading.sin());
+    let (right_x, right_y) = (-forward_y, forward_x);
+    let rear = -(vehicle.length() as f32) / 2.0;
+    let left = -(vehicle.breadth() as f32) / 2.0;
+    
+    let corner = |a: i32, b: i32| {
+        let a = rear + a as f32;
+        let b = left + b as f32;
+        (center_x + a * forward_x + b * right_x, center_y + a * forward_y + b * right_y)
+    };
+    
+    fill_polygon(canvas, &[
+        corner(along, across),
+        corner(along + length, across),
+        corner(along + length, across + breadth),
+        corner(along, across + breadth),
+    ]);
+}
+
+/// Fills a convex polygon by drawing a horizontal span for each pixel row it covers
+fn fill_polygon<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    points: &[(f32, f32)]
+) {
+    let top = points.iter().map(|point| point.1).fold(f32::MAX, f32::min);
+    let bottom = points.iter().map(|point| point.1).fold(f32::MIN, f32::max);
+    
+    for row in (top.round() as i32)..(bottom.round() as i32) {
+        // Sample each row through the middle of its pixels
+        let y = row as f32 + 0.5;
+        let mut span_start = f32::MAX;
+        let mut span_end = f32::MIN;
+        
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            if (y0 <= y && y < y1) || (y1 <= y && y < y0) {
+                let x = x0 + (y - y0) * (x1 - x0) / (y1 - y0);
+                span_start = span_start.min(x);
+                span_end = span_end.max(x);
+            }
+        }
+        
+        let first = span_start.round() as i32;
+        let last = span_end.round() as i32 - 1;
+        if first <= last {
+            canvas.draw_line(Point::new(first, row), Point::new(last, row)).unwrap();
         }
     }
 }
 
-/// Draws UI information
-fn draw_ui_info(
-    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-    intersection: &Intersection
+/// Draws the panel of live charts of the recent queues, throughput and delay
+fn draw_charts<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    trends: &Trends
 ) {
-    // Get statistics
-    let (waiting, passing, _passed, total, _) = intersection.stats();
+    let width = 300;
+    let chart_height = 70;
+    let title_height = 12;
+    let padding = 8;
+    let height = 3 * (title_height + chart_height + padding) + padding;
+    let x_position = WINDOW_WIDTH - 10 - width;
+    let y_position = WINDOW_HEIGHT - 30 - height;
     
-    // Draw color key at the bottom of the screen
-    let margin = 10;
-    let key_width = 15;
-    let key_height = 15;
-    let spacing = 85;
-    let y_position = WINDOW_HEIGHT - margin - key_height;
+    canvas.set_draw_color(CHART_PANEL_COLOR);
+    canvas.fill_rect(Rect::new(x_position, y_position, width as u32, height as u32)).unwrap();
     
-    // Left turn key (red)
-    canvas.set_draw_color(VEHICLE_COLOR_LEFT);
-    canvas.fill_rect(Rect::new(
-        margin,
-        y_position,
-        key_width as u32,
-        key_height as u32
-    )).unwrap();
+    let series = |value: &dyn Fn(&TrendSample) -> Option<f32>| -> Vec<(u32, f32)> {
+        trends.samples.iter()
+            .filter_map(|sample| value(sample).map(|value| (sample.time, value)))
+            .collect()
+    };
+    let queues: Vec<(Color, Vec<(u32, f32)>)> = CHART_QUEUE_COLORS.into_iter()
+        .enumerate()
+        .map(|(index, color)| (color, series(&|sample| Some(sample.queues[index] as f32))))
+        .collect();
+    let charts = [
+        ("QUEUES E W N S", queues),
+        ("THROUGHPUT VEH/MIN", vec![(CHART_THROUGHPUT_COLOR, series(&|sample| Some(sample.throughput)))]),
+        ("DELAY FRAMES", vec![(CHART_DELAY_COLOR, series(&|sample| sample.delay))]),
+    ];
     
-    // Straight key (blue)
-    canvas.set_draw_color(VEHICLE_COLOR_STRAIGHT);
-    canvas.fill_rect(Rect::new(
-        margin + spacing,
-        y_position,
-        key_width as u32,
-        key_height as u32
-    )).unwrap();
+    for (index, (title, lines)) in charts.iter().enumerate() {
+        let top = y_position + padding + index as i32 * (title_height + chart_height + padding);
+        let area = Rect::new(x_position + padding, top + title_height, (width - 2 * padding) as u32, chart_height as u32);
+        draw_chart(canvas, title, area, trends.window_ticks, lines);
+    }
+}
+
+/// Draws a line chart of the given series in an area, with its title above it
+///
+/// Each series is a color and its values by frame. The last `window_ticks`
+/// frames up to the latest value span the width, and the largest value the height.
+fn draw_chart<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    title: &str,
+    area: Rect,
+    window_ticks: u32,
+    lines: &[(Color, Vec<(u32, f32)>)]
+) {
+    let values = lines.iter().flat_map(|(_, points)| points.iter());
+    let latest = values.clone().map(|(time, _)| *time).max().unwrap_or(0);
+    let max = values.map(|(_, value)| *value).fold(1.0, f32::max);
     
-    // Right turn key (green)
-    canvas.set_draw_color(VEHICLE_COLOR_RIGHT);
-    canvas.fill_rect(Rect::new(
-        margin + spacing * 2,
-        y_position,
-        key_width as u32,
-        key_height as u32
-    )).unwrap();
+    let label = format!("{} (MAX {:.0})", title, max);
+    draw_text(canvas, &label, area.x(), area.y() - 10, 1, HUD_TEXT_COLOR);
+    canvas.set_draw_color(CHART_AXIS_COLOR);
+    canvas.draw_rect(area).unwrap();
     
-    // Draw labels
-    // Note: In a real implementation, you would render text using SDL2_ttf
-    // For this simplified version, we'll just use colored blocks for the key
+    let point = |(time, value): (u32, f32)| Point::new(
+        area.right() - ((latest - time) as f32 / window_ticks as f32 * area.width() as f32) as i32,
+        area.bottom() - (value / max * area.height() as f32) as i32
+    );
+    for (color, points) in lines {
+        let points: Vec<Point> = points.iter().map(|&sample| point(sample)).collect();
+        if points.len() > 1 {
+            canvas.set_draw_color(*color);
+            canvas.draw_lines(points.as_slice()).unwrap();
+        }
+    }
+}
+
+/// Draws one marker per intersection of the network, highlighting the one on screen
+fn draw_network_info<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersections: usize,
+    selected: usize
+) {
+    let size = 10;
+    let spacing = 15;
+    let y_position = WINDOW_HEIGHT - size - 10;
     
-    // Vehicle counts
-    let stats_y = margin;
-    let stats_height = 10;
-    let stats_spacing = 15;
+    for index in 0..intersections {
+        let color = if index == selected { ROAD_MARKING_COLOR } else { ROAD_COLOR };
+        canvas.set_draw_color(color);
+        canvas.fill_rect(Rect::new(
+            WINDOW_WIDTH - 10 - (intersections - index) as i32 * spacing,
+            y_position,
+            size as u32,
+            size as u32
+        )).unwrap();
+    }
+}
+
+/// Draws the HUD: the state of the intersection, the given key bindings and the color key
+fn draw_ui_info<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    intersection: &Intersection,
+    bindings: &[&str]
+) {
+    // Get statistics
+    let (waiting, passing, _passed, total, time) = intersection.stats();
     
-    // Waiting vehicles indicator
-    canvas.set_draw_color(Color::RGB(255, 255, 0));
-    canvas.fill_rect(Rect::new(
-        margin,
-        stats_y,
-        waiting as u32 * 5,
-        stats_height as u32
-    )).unwrap();
+    // Draw color key at the bottom of the screen, each swatch labelled
+    let margin = 10;
+    let key_width = 15;
+    let key_height = 15;
+    let spacing = 85;
+    let y_position = WINDOW_HEIGHT - margin - key_height;
     
-    // Passing vehicles indicator
-    canvas.set_draw_color(Color::RGB(0, 255, 255));
-    canvas.fill_rect(Rect::new(
-        margin,
-        stats_y + stats_spacing,
-        passing as u32 * 20,
-        stats_height as u32
-    )).unwrap();
+    let keys = [(VEHICLE_COLOR_LEFT, "LEFT"), (VEHICLE_COLOR_STRAIGHT, "STRAIGHT"), (VEHICLE_COLOR_RIGHT, "RIGHT")];
+    for (index, (color, label)) in keys.into_iter().enumerate() {
+        let x_position = margin + spacing * index as i32;
+        canvas.set_draw_color(color);
+        canvas.fill_rect(Rect::new(
+            x_position,
+            y_position,
+            key_width as u32,
+            key_height as u32
+        )).unwrap();
+        draw_text(canvas, label, x_position + key_width + 5, y_position + (key_height - font::GLYPH_HEIGHT) / 2, 1, HUD_TEXT_COLOR);
+    }
     
-    // Passed vehicles indicator
-    canvas.set_draw_color(Color::RGB(255, 0, 255));
-    canvas.fill_rect(Rect::new(
-        margin,
-        stats_y + stats_spacing * 2,
-        (total.min(50)) as u32 * 2,
-        stats_height as u32
-    )).unwrap();
+    // State of the intersection in the top left corner
+    let delay = |average: Option<f32>| average.map_or("-".to_string(), |average| format!("{:.0}", average));
+    let lines = [
+        format!("FRAME {}", time),
+        format!("CONTROL: {}", control_name(intersection.control)),
+        format!("WAITING {}", waiting),
+        format!("CROSSING {}", passing),
+        format!("PROCESSED {}", total),
+        format!("DELAY {} BUS {}", delay(intersection.general_delay.average()), delay(intersection.bus_delay.average())),
+    ];
+    let line_height = (font::GLYPH_HEIGHT + 3) * HUD_TEXT_SCALE;
+    for (index, line) in lines.iter().enumerate() {
+        draw_text(canvas, line, margin, margin + index as i32 * line_height, HUD_TEXT_SCALE, HUD_TEXT_COLOR);
+    }
     
-    // Controls reminder
-    canvas.set_draw_color(Color::RGB(200, 200, 200));
-    canvas.fill_rect(Rect::new(
-        WINDOW_WIDTH - margin - 150,
-        stats_y,
-        150 as u32,
-        stats_height as u32
-    )).unwrap();
+    // Key bindings in the top right corner
+    let line_height = font::GLYPH_HEIGHT + 4;
+    for (index, binding) in bindings.iter().enumerate() {
+        let x_position = WINDOW_WIDTH - margin - font::text_width(binding, 1);
+        draw_text(canvas, binding, x_position, margin + index as i32 * line_height, 1, HUD_TEXT_COLOR);
+    }
+}
+
+/// Name of a kind of control, as shown on screen
+fn control_name(control: Control) -> &'static str {
+    match control {
+        Control::Signals => "SIGNALS",
+        Control::Roundabout => "ROUNDABOUT",
+        Control::AllWayStop => "ALL-WAY STOP",
+        Control::TwoWayStop(_) => "TWO-WAY STOP",
+        Control::Reservation => "RESERVATIONS",
+    }
+}
+
+/// Draws a line of text in the built-in font with its top left corner at the given point
+///
+/// Each font pixel is drawn as a square of `scale` screen pixels.
+fn draw_text<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    text: &str,
+    x: i32,
+    y: i32,
+    scale: i32,
+    color: Color
+) {
+    canvas.set_draw_color(color);
+    for (index, character) in text.chars().enumerate() {
+        let left = x + index as i32 * font::GLYPH_ADVANCE * scale;
+        for (row, bits) in font::glyph(character).into_iter().enumerate() {
+            for column in 0..font::GLYPH_WIDTH {
+                if bits & (1 << (font::GLYPH_WIDTH - 1 - column)) != 0 {
+                    canvas.fill_rect(Rect::new(
+                        left + column * scale,
+                        y + row as i32 * scale,
+                        scale as u32,
+                        scale as u32
+                    )).unwrap();
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -1,7 +1,14 @@
 //! Common types used throughout the simulation
 
+use crate::config::*;
+
+use rand::Rng;
+use sdl2::pixels::Color;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_PI_2, PI};
+
 /// Direction a vehicle can take at the intersection
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Direction {
     /// Turn left at the intersection
     Left,
@@ -12,7 +19,7 @@ pub enum Direction {
 }
 
 /// Side from which a vehicle enters the intersection
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Side {
     /// Vehicle coming from the south side
     FromSouth,
@@ -24,24 +31,412 @@ pub enum Side {
     FromEast,
 }
 
+impl Side {
+    /// Heading of vehicles entering from this side, in radians clockwise from the +x axis
+    pub fn heading(self) -> f32 {
+        match self {
+            Side::FromEast => 0.0,
+            Side::FromNorth => FRAC_PI_2,
+            Side::FromWest => PI,
+            Side::FromSouth => -FRAC_PI_2,
+        }
+    }
+    
+    /// The side across the intersection
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::FromEast => Side::FromWest,
+            Side::FromWest => Side::FromEast,
+            Side::FromNorth => Side::FromSouth,
+            Side::FromSouth => Side::FromNorth,
+        }
+    }
+    
+    /// Leg of the intersection left by a vehicle entering from this side and taking the given direction
+    ///
+    /// Legs are named after the side they lie on, the same way as approaches.
+    pub fn exit_leg(self, direction: Direction) -> Side {
+        match (self, direction) {
+            (Side::FromEast, Direction::Left) | 
+            (Side::FromSouth, Direction::Straight) | 
+            (Side::FromWest, Direction::Right) => Side::FromNorth,
+            
+            (Side::FromSouth, Direction::Left) | 
+            (Side::FromWest, Direction::Straight) | 
+            (Side::FromNorth, Direction::Right) => Side::FromEast,
+            
+            (Side::FromWest, Direction::Left) | 
+            (Side::FromNorth, Direction::Straight) | 
+            (Side::FromEast, Direction::Right) => Side::FromSouth,
+            
+            (Side::FromNorth, Direction::Left) | 
+            (Side::FromEast, Direction::Straight) | 
+            (Side::FromSouth, Direction::Right) => Side::FromWest,
+        }
+    }
+    
+    /// Turn that leads from this approach to the given leg, if any
+    ///
+    /// There is none back to the leg the approach comes from, as vehicles don't make U-turns.
+    pub fn direction_to(self, leg: Side) -> Option<Direction> {
+        [Direction::Left, Direction::Straight, Direction::Right]
+            .into_iter()
+            .find(|&direction| self.exit_leg(direction) == leg)
+    }
+}
+
+/// Movements a lane may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LaneUse {
+    /// Left turns only
+    LeftOnly,
+    /// Straight through only
+    Through,
+    /// Straight through or right turns
+    ThroughRight,
+    /// Right turns only
+    RightOnly,
+}
+
+impl LaneUse {
+    /// Whether a vehicle taking the given direction may use this lane
+    pub fn allows(self, direction: Direction) -> bool {
+        match self {
+            LaneUse::LeftOnly => direction == Direction::Left,
+            LaneUse::Through => direction == Direction::Straight,
+            LaneUse::ThroughRight => direction != Direction::Left,
+            LaneUse::RightOnly => direction == Direction::Right,
+        }
+    }
+    
+    /// Movement taken from this lane by vehicles that could not get to the lane they wanted
+    pub fn main_direction(self) -> Direction {
+        match self {
+            LaneUse::LeftOnly => Direction::Left,
+            LaneUse::Through | LaneUse::ThroughRight => Direction::Straight,
+            LaneUse::RightOnly => Direction::Right,
+        }
+    }
+}
+
+/// Arrangement of the legs meeting at an intersection
+///
+/// A Y junction has the same legs and movements as a T junction, so it is laid
+/// out as one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layout {
+    /// Four legs crossing at right angles
+    Cross,
+    /// Three legs, with no road on the given side
+    ThreeLeg(Side),
+}
+
+impl Layout {
+    /// Whether there is a road on the given side
+    pub fn has_leg(self, side: Side) -> bool {
+        match self {
+            Layout::Cross => true,
+            Layout::ThreeLeg(missing) => side != missing,
+        }
+    }
+    
+    /// Sides with a road, in the order approaches are listed elsewhere
+    pub fn legs(self) -> Vec<Side> {
+        [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth]
+            .into_iter()
+            .filter(|&side| self.has_leg(side))
+            .collect()
+    }
+    
+    /// Whether a vehicle on the given approach may take the given direction
+    pub fn allows(self, side: Side, direction: Direction) -> bool {
+        self.has_leg(side) && self.has_leg(side.exit_leg(direction))
+    }
+    
+    /// Movements available from the given approach
+    pub fn directions(self, side: Side) -> Vec<Direction> {
+        [Direction::Left, Direction::Straight, Direction::Right]
+            .into_iter()
+            .filter(|&direction| self.allows(side, direction))
+            .collect()
+    }
+    
+    /// A side on the road that runs straight through
+    ///
+    /// For a three-leg intersection this is the road across the stem; a four-leg
+    /// intersection is taken to have its through road east to west.
+    pub fn through_road(self) -> Side {
+        match self {
+            Layout::ThreeLeg(Side::FromEast | Side::FromWest) => Side::FromNorth,
+            _ => Side::FromEast,
+        }
+    }
+    
+    /// Movements a lane of the given approach may be used for
+    ///
+    /// Lanes keep the use set in `LANE_USE` where the layout allows it. A lane
+    /// whose movement leads nowhere is given over to a neighbouring movement, so
+    /// the stem of a T junction gets left and right turn lanes.
+    pub fn lane_use(self, side: Side, lane: usize) -> LaneUse {
+        let allows = |direction| self.allows(side, direction);
+        match LANE_USE[lane] {
+            LaneUse::LeftOnly if !allows(Direction::Left) => {
+                if allows(Direction::Straight) { LaneUse::Through } else { LaneUse::RightOnly }
+            },
+            LaneUse::Through if !allows(Direction::Straight) => {
+                if allows(Direction::Left) { LaneUse::LeftOnly } else { LaneUse::RightOnly }
+            },
+            LaneUse::ThroughRight if !allows(Direction::Straight) => LaneUse::RightOnly,
+            LaneUse::ThroughRight if !allows(Direction::Right) => LaneUse::Through,
+            LaneUse::RightOnly if !allows(Direction::Right) => LaneUse::Through,
+            lane_use => lane_use,
+        }
+    }
+}
+
+/// How vehicles are given the right of way at an intersection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Control {
+    /// Traffic lights serving one approach at a time
+    Signals,
+    /// A one-lane roundabout, entered by yielding to circulating traffic
+    Roundabout,
+    /// Stop signs on every approach, with vehicles going in the order they stopped
+    AllWayStop,
+    /// Stop signs on the minor road only; the major road runs through the given side and its opposite
+    TwoWayStop(Side),
+    /// No signals or signs; connected vehicles reserve their way across from an intersection manager
+    Reservation,
+}
+
+impl Control {
+    /// Whether vehicles on the given approach have to stop at the line
+    pub fn stops(self, side: Side) -> bool {
+        match self {
+            Control::Signals | Control::Roundabout | Control::Reservation => false,
+            Control::AllWayStop => true,
+            Control::TwoWayStop(major) => side != major && side != major.opposite(),
+        }
+    }
+}
+
+/// Class of a vehicle, determining its size and performance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VehicleClass {
+    /// Passenger car
+    Car,
+    /// Heavy goods vehicle
+    Truck,
+    /// Public transport bus
+    Bus,
+    /// Motorcycle
+    Motorcycle,
+    /// Emergency vehicle (ambulance, fire engine, police) that preempts the signals
+    Emergency,
+}
+
+impl VehicleClass {
+    /// Returns the physical characteristics of this class
+    pub fn spec(self) -> VehicleSpec {
+        match self {
+            VehicleClass::Car => CAR_SPEC,
+            VehicleClass::Truck => TRUCK_SPEC,
+            VehicleClass::Bus => BUS_SPEC,
+            VehicleClass::Motorcycle => MOTORCYCLE_SPEC,
+            VehicleClass::Emergency => EMERGENCY_SPEC,
+        }
+    }
+}
+
+/// Physical and performance characteristics of a vehicle class
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VehicleSpec {
+    /// Length along the direction of travel, in pixels
+    pub length: i32,
+    /// Width across the direction of travel, in pixels
+    pub width: i32,
+    /// Top speed in pixels per frame
+    pub max_velocity: f32,
+    /// Speed gained per frame when pulling away, in pixels per frame
+    pub acceleration: f32,
+    /// Colour used for class-specific details such as cargo boxes and windows
+    pub accent: Color,
+}
+
+/// Relative weights of each vehicle class among spawned vehicles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FleetMix {
+    pub car: u32,
+    pub truck: u32,
+    pub bus: u32,
+    pub motorcycle: u32,
+}
+
+impl FleetMix {
+    /// Create the fleet mix set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            car: FLEET_MIX_CAR,
+            truck: FLEET_MIX_TRUCK,
+            bus: FLEET_MIX_BUS,
+            motorcycle: FLEET_MIX_MOTORCYCLE,
+        }
+    }
+
+    /// Picks a vehicle class at random according to the weights
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> VehicleClass {
+        let total = self.car + self.truck + self.bus + self.motorcycle;
+        if total == 0 {
+            return VehicleClass::Car;
+        }
+
+        let mut pick = rng.gen_range(0..total);
+        for (class, weight) in [
+            (VehicleClass::Car, self.car),
+            (VehicleClass::Truck, self.truck),
+            (VehicleClass::Bus, self.bus),
+            (VehicleClass::Motorcycle, self.motorcycle),
+        ] {
+            if pick < weight {
+                return class;
+            }
+            pick -= weight;
+        }
+        VehicleClass::Car
+    }
+}
+
+/// Temperament of a driver, setting how they treat yellow and red lights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DriverStyle {
+    /// Stops whenever it can, and reacts slowly
+    Cautious,
+    /// Average driver
+    Normal,
+    /// Pushes on through yellows and now and then reds, and reacts quickly
+    Aggressive,
+}
+
+impl DriverStyle {
+    /// Returns the behaviour of drivers of this style
+    pub fn spec(self) -> DriverSpec {
+        match self {
+            DriverStyle::Cautious => CAUTIOUS_DRIVER_SPEC,
+            DriverStyle::Normal => NORMAL_DRIVER_SPEC,
+            DriverStyle::Aggressive => AGGRESSIVE_DRIVER_SPEC,
+        }
+    }
+}
+
+/// How drivers of a style treat the lights
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DriverSpec {
+    /// Probability of driving on through a yellow the driver could stop for
+    pub yellow_go_probability: f64,
+    /// Probability of running a red light when coming up to it
+    pub red_run_probability: f64,
+    /// Shortest and longest time to notice a light changing, in frames
+    pub reaction_ticks: (u32, u32),
+}
+
+/// Relative weights of each driver style among drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriverMix {
+    pub cautious: u32,
+    pub normal: u32,
+    pub aggressive: u32,
+}
+
+impl DriverMix {
+    /// Create the driver mix set up in the configuration
+    pub fn configured() -> Self {
+        Self {
+            cautious: DRIVER_MIX_CAUTIOUS,
+            normal: DRIVER_MIX_NORMAL,
+            aggressive: DRIVER_MIX_AGGRESSIVE,
+        }
+    }
+
+    /// Picks a driver style at random according to the weights
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> DriverStyle {
+        let total = self.cautious + self.normal + self.aggressive;
+        if total == 0 {
+            return DriverStyle::Normal;
+        }
+
+        let pick = rng.gen_range(0..total);
+        if pick < self.cautious {
+            DriverStyle::Cautious
+        } else if pick < self.cautious + self.normal {
+            DriverStyle::Normal
+        } else {
+            DriverStyle::Aggressive
+        }
+    }
+}
+
+/// What a driver has made up their mind to do about a light that is not green
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LightDecision {
+    /// Stop at the line
+    Stop,
+    /// Drive on into the intersection
+    Go,
+}
+
+/// A scheduled bus line crossing the intersection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusRoute {
+    /// Side the buses enter from
+    pub side: Side,
+    /// Movement the buses make at the intersection
+    pub direction: Direction,
+}
+
+/// A two-way road joining a leg of one intersection of the network to the opposite leg of another
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RoadSpec {
+    /// Intersection at one end of the road
+    pub from: usize,
+    /// Leg of that intersection the road leaves by
+    pub leg: Side,
+    /// Intersection at the other end of the road
+    pub to: usize,
+    /// Length of the road between the edges of the two intersections' areas, in pixels
+    pub length: f32,
+}
+
 /// Traffic light states
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LightState {
     /// Green light - vehicles can proceed
     Green,
+    /// Yellow light - the approach is being cleared, vehicles must not enter
+    Yellow,
     /// Red light - vehicles must stop
     Red,
 }
 
+/// Pedestrian signal states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PedestrianSignal {
+    /// Pedestrians may start crossing
+    Walk,
+    /// Pedestrians must not start crossing, and those on the crosswalk finish
+    FlashingDontWalk,
+    /// Pedestrians must not cross
+    DontWalk,
+}
+
 /// Traffic light representation
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TrafficLight {
     /// Current state of the traffic light
     pub state: LightState,
 }
 
 impl TrafficLight {
-    /// Create a new traffic light with the given state
+    // Create a new traffic light with the given state
     // pub fn new(state: LightState) -> Self {
     //     Self { state }
     //}
@@ -55,4 +450,71 @@ impl TrafficLight {
     pub fn green() -> Self {
         Self { state: LightState::Green }
     }
-}
\ No newline at end of file
+    
+    /// Create a new traffic light with a yellow state
+    pub fn yellow() -> Self {
+        Self { state: LightState::Yellow }
+    }
+}
+
+/// Stand-in for an SDL color when saving a snapshot, as SDL colors cannot be serialized themselves
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Color")]
+pub struct ColorDef {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRECTIONS: [Direction; 3] = [Direction::Left, Direction::Straight, Direction::Right];
+    const SIDES: [Side; 4] = [Side::FromEast, Side::FromWest, Side::FromNorth, Side::FromSouth];
+
+    #[test]
+    fn shared_lanes_allow_both_of_their_movements() {
+        assert!(LaneUse::ThroughRight.allows(Direction::Straight));
+        assert!(LaneUse::ThroughRight.allows(Direction::Right));
+        assert!(!LaneUse::ThroughRight.allows(Direction::Left));
+        assert!(!LaneUse::LeftOnly.allows(Direction::Straight));
+    }
+
+    #[test]
+    fn every_movement_has_a_lane() {
+        for direction in DIRECTIONS {
+            assert!(LANE_USE.iter().any(|lane_use| lane_use.allows(direction)), "no lane for {:?}", direction);
+        }
+    }
+
+    #[test]
+    fn four_leg_intersections_keep_the_configured_lane_use() {
+        for side in SIDES {
+            for (lane, &lane_use) in LANE_USE.iter().enumerate() {
+                assert_eq!(Layout::Cross.lane_use(side, lane), lane_use);
+            }
+        }
+    }
+
+    #[test]
+    fn every_lane_of_a_three_leg_intersection_leads_somewhere() {
+        for missing in SIDES {
+            let layout = Layout::ThreeLeg(missing);
+            for side in layout.legs() {
+                for lane in 0..LANES_PER_APPROACH {
+                    let lane_use = layout.lane_use(side, lane);
+                    assert!(layout.allows(side, lane_use.main_direction()), "{:?} lane {} from {:?}", layout, lane, side);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_stem_of_a_t_junction_gets_turn_lanes() {
+        let layout = Layout::ThreeLeg(Side::FromNorth);
+        let lane_uses: Vec<LaneUse> = (0..LANES_PER_APPROACH).map(|lane| layout.lane_use(Side::FromSouth, lane)).collect();
+        assert_eq!(lane_uses, [LaneUse::LeftOnly, LaneUse::LeftOnly, LaneUse::RightOnly]);
+    }
+}